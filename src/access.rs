@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::config::ServerConfig;
+
+/// A block of IPs like `10.0.0.0/8`, a single IP is a block of one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => same_prefix(
+                u32::from(net) as u128,
+                u32::from(ip) as u128,
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                same_prefix(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Cidr, String> {
+        let invalid = || format!("Invalid IP or CIDR: {}", text);
+        let (addr, prefix) = text.trim().split_once('/').unwrap_or((text.trim(), ""));
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            "" => bits,
+            prefix => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(invalid)?,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+/// Check if the first `prefix` bits of `bits`-bit numbers `a` and `b` are the same.
+fn same_prefix(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    let shift = bits - prefix;
+    shift >= bits || a >> shift == b >> shift
+}
+
+/// IPs allowed to connect, by the `allow` and `deny` lists of server.
+///
+/// Denied IPs are never allowed. If `allow` is not empty, only IPs in it are allowed.
+#[derive(Default)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+impl AccessList {
+    pub fn new(config: &ServerConfig) -> Result<AccessList, String> {
+        let parse = |list: &[String]| -> Result<Vec<Cidr>, String> {
+            list.iter().map(|cidr| cidr.parse()).collect()
+        };
+        Ok(AccessList {
+            allow: parse(&config.allow)?,
+            deny: parse(&config.deny)?,
+        })
+    }
+
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+}
+
+/// Counts open connections, in total and by IP.
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    ips: HashMap<IpAddr, usize>,
+}
+
+/// Open connections of server, a new one is only let in while there is room for it.
+pub struct Connections {
+    max_total: usize,
+    max_per_ip: usize,
+    counts: Mutex<Counts>,
+}
+impl Connections {
+    pub fn new(max_total: usize, max_per_ip: usize) -> Connections {
+        Connections {
+            max_total,
+            max_per_ip,
+            counts: Mutex::new(Counts::default()),
+        }
+    }
+
+    /// Take a place for a new connection, `Err` with the reason if it is full.
+    ///
+    /// The place is given back when the slot is dropped.
+    pub fn open(self: &Arc<Self>, ip: Option<IpAddr>) -> Result<Slot, &'static str> {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.total >= self.max_total {
+            return Err("Server is full, try again later.");
+        }
+        if let Some(ip) = ip {
+            if counts.ips.get(&ip).copied().unwrap_or_default() >= self.max_per_ip {
+                return Err("Too many connections from your IP.");
+            }
+            *counts.ips.entry(ip).or_default() += 1;
+        }
+        counts.total += 1;
+        Ok(Slot {
+            connections: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// Place of an open connection.
+pub struct Slot {
+    connections: Arc<Connections>,
+    ip: Option<IpAddr>,
+}
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut counts = self.connections.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(ip) = self.ip {
+            if let Some(count) = counts.ips.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.ips.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn match_cidrs() {
+        let lan: Cidr = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains(&ip("192.168.1.77")));
+        assert!(!lan.contains(&ip("192.168.2.1")));
+        assert!(lan.contains(&ip("::ffff:192.168.1.3")));
+        assert!(!lan.contains(&ip("fe80::1")));
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&ip("8.8.8.8")));
+        let one: Cidr = "10.0.0.7".parse().unwrap();
+        assert!(one.contains(&ip("10.0.0.7")));
+        assert!(!one.contains(&ip("10.0.0.8")));
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(&ip("2001:db8:1::1")));
+        assert!(!v6.contains(&ip("2001:db9::1")));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+
+        let config = ServerConfig {
+            allow: vec!["10.0.0.0/8".to_string()],
+            deny: vec!["10.0.0.7".to_string()],
+            ..Default::default()
+        };
+        let list = AccessList::new(&config).unwrap();
+        assert!(list.is_allowed(&ip("10.1.2.3")));
+        assert!(!list.is_allowed(&ip("10.0.0.7")));
+        assert!(!list.is_allowed(&ip("127.0.0.1")));
+        assert!(AccessList::default().is_allowed(&ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn count_connections() {
+        let connections = Arc::new(Connections::new(3, 2));
+        let first = connections.open(Some(ip("10.0.0.7"))).unwrap();
+        let second = connections.open(Some(ip("10.0.0.7"))).unwrap();
+        assert!(connections.open(Some(ip("10.0.0.7"))).is_err());
+        let other = connections.open(Some(ip("10.0.0.8"))).unwrap();
+        assert!(connections.open(None).is_err());
+        drop(first);
+        assert!(connections.open(Some(ip("10.0.0.8"))).is_ok());
+        drop((second, other));
+        assert_eq!(connections.counts.lock().unwrap().total, 0);
+        assert!(connections.counts.lock().unwrap().ips.is_empty());
+
+        // rejected peers leave nothing behind
+        let closed = Arc::new(Connections::new(3, 0));
+        assert!(closed.open(Some(ip("10.0.0.7"))).is_err());
+        assert!(closed.counts.lock().unwrap().ips.is_empty());
+    }
+}
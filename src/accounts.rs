@@ -0,0 +1,157 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chacha20poly1305::aead::OsRng;
+
+use crate::message::is_valid_nickname;
+use log::error;
+
+/// Passwords shorter than this are refused
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Accounts registered on server, their nicknames are reserved.
+///
+/// The file has a line for each account, like `alice $argon2id$v=19$...`,
+/// passwords are hashed by Argon2 with random salts.
+pub struct Accounts {
+    path: PathBuf,
+    /// Nickname -> hash of password
+    hashes: HashMap<String, String>,
+}
+impl Accounts {
+    /// Load accounts from `path`, there are no accounts if the file does not exist.
+    pub fn load(path: PathBuf) -> io::Result<Accounts> {
+        let hashes = match fs::read_to_string(&path) {
+            Ok(text) => text
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .map(|(nick, hash)| (nick.to_string(), hash.trim().to_string()))
+                .collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Accounts { path, hashes })
+    }
+
+    pub fn is_registered(&self, nick: &str) -> bool {
+        self.hashes.contains_key(nick)
+    }
+
+    /// Check a new account can be registered, before its password is hashed.
+    pub fn check_new(&self, nick: &str, password: &str) -> Result<(), String> {
+        if !is_valid_nickname(nick) {
+            return Err("Nicknames contain only letters, digits, - and _.".to_string());
+        }
+        if is_default_name(nick) {
+            return Err(format!("{} is a name given to guests.", nick));
+        }
+        if self.is_registered(nick) {
+            return Err(format!("{} is registered already.", nick));
+        }
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(format!(
+                "Passwords have at least {} chars.",
+                MIN_PASSWORD_LEN
+            ));
+        }
+        Ok(())
+    }
+
+    /// Get the hash of password of an account, to check with `verify_password`.
+    pub fn hash_of(&self, nick: &str) -> Option<&str> {
+        self.hashes.get(nick).map(String::as_str)
+    }
+
+    /// Add an account with a hash by `hash_password` and save it,
+    /// `Err` contains the reason to show to user.
+    pub fn add(&mut self, nick: &str, hash: String) -> Result<(), String> {
+        if self.is_registered(nick) {
+            return Err(format!("{} is registered already.", nick));
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{} {}", nick, hash))
+            .map_err(|err| {
+                error!("Failed to save account of {}: {}", nick, err);
+                "Failed to save the account.".to_string()
+            })?;
+        self.hashes.insert(nick.to_string(), hash);
+        Ok(())
+    }
+}
+
+/// Check if `nick` is like `client7`, names server gives to new clients.
+///
+/// They can not be registered, or a guest given the name later would hold a registered nickname.
+fn is_default_name(nick: &str) -> bool {
+    nick.strip_prefix("client")
+        .is_some_and(|id| !id.is_empty() && id.chars().all(|ch| ch.is_ascii_digit()))
+}
+
+/// Hash a password with a random salt, it is slow on purpose.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| format!("Failed to hash the password: {}", err))
+}
+
+/// Check a password by the hash of an account, it is slow on purpose.
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn register_and_verify() {
+        let path = std::env::temp_dir().join(format!("chamber-accounts-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut accounts = Accounts::load(path.clone()).unwrap();
+        assert!(accounts.check_new("alice", "short").is_err());
+        assert!(accounts.check_new("a,b", "long enough").is_err());
+        assert!(accounts.check_new("client7", "long enough").is_err());
+        accounts.check_new("client", "long enough").unwrap();
+        accounts.check_new("client7a", "long enough").unwrap();
+        accounts.check_new("alice", "long enough").unwrap();
+        let hash = hash_password("long enough").unwrap();
+        accounts.add("alice", hash.clone()).unwrap();
+        assert!(accounts.check_new("alice", "another one").is_err());
+        assert!(accounts.add("alice", hash).is_err());
+        assert!(accounts.is_registered("alice"));
+        let hash = accounts.hash_of("alice").unwrap();
+        assert!(verify_password(hash, "long enough"));
+        assert!(!verify_password(hash, "long enougH"));
+        assert!(accounts.hash_of("bob").is_none());
+        assert!(!verify_password("not a hash", "long enough"));
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("alice $argon2id$"));
+        assert!(!text.contains("long enough"));
+        let loaded = Accounts::load(path.clone()).unwrap();
+        assert!(verify_password(
+            loaded.hash_of("alice").unwrap(),
+            "long enough"
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
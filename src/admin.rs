@@ -0,0 +1,246 @@
+use std::{
+    error::Error,
+    fs::{self, DirBuilder},
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    process,
+    str::FromStr,
+    sync::mpsc::{self, Sender},
+    thread,
+    time::Duration,
+};
+
+use crate::config::Config;
+use log::warn;
+
+/// Max size of a command line in bytes
+const MAX_COMMAND_SIZE: u64 = 4096;
+/// Seconds to wait for an admin to send a command, or for server to answer it
+const ADMIN_TIMEOUT_SECS: u64 = 5;
+
+const USAGE: &str = "\
+Usage: chamber admin <command>
+
+Commands:
+  clients               List online clients with their addresses and uptime
+  kick <nick> [reason]  Disconnect a client
+  notice <text>         Send a notice to everyone
+  reload                Reload config, bans and access lists
+  stats                 Show stats of server";
+
+/// A command sent to server by `chamber admin`.
+#[derive(Debug, PartialEq)]
+pub enum AdminCommand {
+    Clients,
+    Kick { nick: String, reason: String },
+    Notice(String),
+    Reload,
+    Stats,
+}
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<AdminCommand, String> {
+        let (name, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        let args = args.trim();
+        let command = match (name, args) {
+            ("clients", "") => AdminCommand::Clients,
+            ("kick", args) if !args.is_empty() => {
+                let (nick, reason) = args.split_once(' ').unwrap_or((args, ""));
+                AdminCommand::Kick {
+                    nick: nick.to_string(),
+                    reason: reason.trim().to_string(),
+                }
+            }
+            ("notice", text) if !text.is_empty() => AdminCommand::Notice(text.to_string()),
+            ("reload", "") => AdminCommand::Reload,
+            ("stats", "") => AdminCommand::Stats,
+            _ => return Err(USAGE.to_string()),
+        };
+        Ok(command)
+    }
+}
+
+/// A command waiting for the chamber, which sends its answer back by `reply`.
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    pub reply: Sender<Result<String, String>>,
+}
+
+/// Listen on the admin socket, and pass commands coming in to `sender`.
+///
+/// The socket can only be used by the user running server. A socket left by a server
+/// not running any more is replaced, other files at `path` are left alone.
+pub fn listen(path: &Path, sender: Sender<AdminRequest>) -> io::Result<()> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "another server is using it",
+        ));
+    }
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "it is not a socket",
+            ))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let listener = bind_privately(path)?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(err) = serve(stream, &sender) {
+                warn!("Admin connection failed: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Bind the socket in a directory only the user can enter, and move it to `path`
+/// once others can not use it, so it is never open to them.
+fn bind_privately(path: &Path) -> io::Result<UnixListener> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+    let private = dir.join(format!(".admin-{}", process::id()));
+    let _ = fs::remove_dir_all(&private);
+    DirBuilder::new().mode(0o700).create(&private)?;
+    let temp = private.join("admin.sock");
+    let result = UnixListener::bind(&temp).and_then(|listener| {
+        fs::set_permissions(&temp, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&temp, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&private);
+    result
+}
+
+/// Answer a command, an admin connection carries only one.
+fn serve(stream: UnixStream, sender: &Sender<AdminRequest>) -> io::Result<()> {
+    let timeout = Some(Duration::from_secs(ADMIN_TIMEOUT_SECS));
+    stream.set_read_timeout(timeout)?;
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .take(MAX_COMMAND_SIZE)
+        .read_line(&mut line)?;
+    let answer = match line.parse() {
+        Ok(command) => {
+            let (reply, answer) = mpsc::channel();
+            sender
+                .send(AdminRequest { command, reply })
+                .expect("Failed to send admin command.");
+            answer
+                .recv_timeout(Duration::from_secs(ADMIN_TIMEOUT_SECS))
+                .unwrap_or_else(|_| Err("Server did not answer in time.".to_string()))
+        }
+        Err(usage) => Err(usage),
+    };
+    let text = match answer {
+        Ok(text) => text,
+        Err(reason) => format!("Error: {}", reason),
+    };
+    (&stream).write_all(format!("{}\n", text).as_bytes())
+}
+
+/// `chamber admin <command>`, send a command to the running server and print its answer.
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let line = args.join(" ");
+    line.parse::<AdminCommand>()?;
+    let config = Config::load()?;
+    let path = config
+        .server
+        .admin_socket_path()
+        .ok_or("Admin socket is unknown.")?;
+    let mut stream = UnixStream::connect(&path).map_err(|err| {
+        format!(
+            "Failed to connect to {}, is server running? {}",
+            path.display(),
+            err
+        )
+    })?;
+    stream.write_all(format!("{}\n", line).as_bytes())?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+    match answer.strip_prefix("Error: ") {
+        Some(reason) => Err(reason.trim_end().into()),
+        None => {
+            print!("{}", answer);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!("clients".parse(), Ok(AdminCommand::Clients));
+        assert_eq!(
+            "kick mallory  spamming links".parse(),
+            Ok(AdminCommand::Kick {
+                nick: "mallory".to_string(),
+                reason: "spamming links".to_string(),
+            })
+        );
+        assert_eq!(
+            "kick bob".parse(),
+            Ok(AdminCommand::Kick {
+                nick: "bob".to_string(),
+                reason: String::new(),
+            })
+        );
+        assert_eq!(
+            "notice back in 5 minutes\n".parse(),
+            Ok(AdminCommand::Notice("back in 5 minutes".to_string()))
+        );
+        assert_eq!("reload".parse(), Ok(AdminCommand::Reload));
+        assert!("kick".parse::<AdminCommand>().is_err());
+        assert!("notice".parse::<AdminCommand>().is_err());
+        assert!("stats now".parse::<AdminCommand>().is_err());
+        assert!("shutdown".parse::<AdminCommand>().is_err());
+    }
+
+    #[test]
+    fn bind_socket_privately() {
+        let dir = std::env::temp_dir().join(format!("chamber-admin-{}", process::id()));
+        let path = dir.join("admin.sock");
+        let _listener = bind_privately(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(UnixStream::connect(&path).is_ok());
+        // only the socket is left
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replace_only_sockets() {
+        let dir = std::env::temp_dir().join(format!("chamber-listen-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("admin.sock");
+        fs::write(&path, "keep me").unwrap();
+        let (sender, _receiver) = mpsc::channel();
+        assert!(listen(&path, sender.clone()).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me");
+
+        // a socket left by a server not running any more
+        fs::remove_file(&path).unwrap();
+        drop(UnixListener::bind(&path).unwrap());
+        listen(&path, sender).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
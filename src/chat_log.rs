@@ -0,0 +1,376 @@
+use std::{
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Config, LogConfig, LogFormat},
+    message::{is_valid_nickname, is_valid_room_name, Message, MessageType},
+    search::SearchQuery,
+};
+
+/// Writes msgs to files like `<dir>/lobby/2022-08-01.log`, direct msgs go to `<dir>/@alice/`.
+///
+/// A new file is started every day, and when a file reaches `max_size` the next ones are
+/// `2022-08-01.1.log`, `2022-08-01.2.log` and so on. Msgs you send come back from server
+/// with ids, so they are logged when received like others.
+pub struct ChatLogger {
+    dir: PathBuf,
+    formats: Vec<LogFormat>,
+    max_size: u64,
+}
+
+/// A msg in JSON Lines logs.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LogRecord {
+    pub time: i64,
+    /// Room, or `@nick` for direct msgs
+    pub room: String,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub sender: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub target: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub reply_to: u64,
+    pub content: String,
+}
+
+fn is_zero(id: &u64) -> bool {
+    *id == 0
+}
+
+impl LogRecord {
+    /// Build a record if the msg is worth logging, `name` is your nickname.
+    pub fn new(msg: &Message, name: &str) -> Option<LogRecord> {
+        match msg.msg_type {
+            MessageType::TextMessage
+            | MessageType::ActionMessage
+            | MessageType::DirectMessage
+            | MessageType::RoomJoin
+            | MessageType::RoomPart
+            | MessageType::MessageEdit
+            | MessageType::MessageDelete => {}
+            _ => return None,
+        }
+        // direct msgs, and edits and deletes of them, have targets
+        let room = match msg.msg_target.as_str() {
+            "" => msg.msg_room.clone(),
+            target if msg.msg_sender == name => format!("@{}", target),
+            _ => format!("@{}", msg.msg_sender),
+        };
+        Some(LogRecord {
+            time: msg.msg_time,
+            room,
+            msg_type: msg.msg_type.to_string(),
+            sender: msg.msg_sender.clone(),
+            target: msg.msg_target.clone(),
+            id: msg.msg_id,
+            reply_to: msg.msg_reply_to,
+            content: msg.msg_content.clone(),
+        })
+    }
+
+    pub fn to_msg(&self) -> Message {
+        Message {
+            msg_type: MessageType::convert_to_msg_type(&self.msg_type),
+            msg_sender: self.sender.clone(),
+            msg_room: self.room.clone(),
+            msg_target: self.target.clone(),
+            msg_time: self.time,
+            msg_id: self.id,
+            msg_reply_to: self.reply_to,
+            msg_content: self.content.clone(),
+        }
+    }
+
+    /// Format the record as a line of text logs, lines of content after the first are indented.
+    pub fn to_text(&self) -> String {
+        let time = Local
+            .timestamp_opt(self.time, 0)
+            .single()
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        let mut line = format!("[{}]", time);
+        if self.id != 0 {
+            line += &format!(" ({})", self.id);
+        }
+        if self.reply_to != 0 {
+            line += &format!(" (reply to {})", self.reply_to);
+        }
+        let brief = match self.msg_type.as_str() {
+            "RoomJoin" => format!("* {} joined #{}", self.sender, self.room),
+            "RoomPart" => format!("* {} left #{}", self.sender, self.room),
+            _ => self.to_msg().to_brief_string(),
+        };
+        format!("{} {}", line, brief.replace('\n', "\n    "))
+    }
+
+    /// Date of the record in local time, which names its log file.
+    fn date(&self) -> String {
+        Local
+            .timestamp_opt(self.time, 0)
+            .single()
+            .map(|time| time.format("%Y-%m-%d").to_string())
+            .unwrap_or_default()
+    }
+}
+
+impl ChatLogger {
+    /// Create a logger if logging is enabled in config.
+    pub fn new(config: &LogConfig) -> Option<ChatLogger> {
+        if !config.enabled || config.formats.is_empty() {
+            return None;
+        }
+        Some(ChatLogger {
+            dir: config.dir()?,
+            formats: config.formats.clone(),
+            max_size: config.max_size,
+        })
+    }
+
+    /// Log a msg, msgs not worth logging are ignored.
+    pub fn log(&self, msg: &Message, name: &str) -> io::Result<()> {
+        let record = match LogRecord::new(msg, name) {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+        // names come from server, they must not lead out of the log dir
+        let valid = match record.room.strip_prefix('@') {
+            Some(peer) => is_valid_nickname(peer),
+            None => is_valid_room_name(&record.room),
+        };
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid room name {:?}.", record.room),
+            ));
+        }
+        let dir = self.dir.join(&record.room);
+        fs::create_dir_all(&dir)?;
+        for format in &self.formats {
+            let line = match format {
+                LogFormat::Text => record.to_text(),
+                LogFormat::Jsonl => serde_json::to_string(&record)?,
+            };
+            let path = log_path(&dir, &record.date(), *format, self.max_size);
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the file to append to, skipping files that have reached `max_size`.
+fn log_path(dir: &Path, date: &str, format: LogFormat, max_size: u64) -> PathBuf {
+    let mut part = 0;
+    loop {
+        let name = match part {
+            0 => format!("{}.{}", date, format.extension()),
+            _ => format!("{}.{}.{}", date, part, format.extension()),
+        };
+        let path = dir.join(name);
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.len() >= max_size => part += 1,
+            _ => return path,
+        }
+    }
+}
+
+/// Read JSON Lines logs of `room`, or all rooms, sorted by time.
+pub fn read_logs(dir: &Path, room: Option<&str>) -> io::Result<Vec<LogRecord>> {
+    let mut records = vec![];
+    let room_dirs = match room {
+        Some(room) => vec![dir.join(room)],
+        None => fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_dir())
+            .collect(),
+    };
+    for room_dir in room_dirs {
+        let entries = match fs::read_dir(&room_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(LogFormat::Jsonl.extension()) {
+                continue;
+            }
+            for line in BufReader::new(File::open(&path)?).lines() {
+                // lines cut off by a crash are skipped
+                if let Ok(record) = serde_json::from_str(&line?) {
+                    records.push(record);
+                }
+            }
+        }
+    }
+    // parts of a day may be read in any order
+    records.sort_by_key(|record: &LogRecord| (record.time, record.id));
+    Ok(records)
+}
+
+/// `chamber logs [-r <room>] [query]`, print logs matching a query like `/search`.
+pub fn print_logs(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut room = None;
+    let mut words = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-r" | "--room" => {
+                room = Some(
+                    args.next()
+                        .ok_or("Usage: chamber logs [-r <room>] [query]")?
+                        .trim_start_matches('#')
+                        .to_string(),
+                )
+            }
+            _ => words.push(arg.as_str()),
+        }
+    }
+    let query = match words.is_empty() {
+        true => None,
+        false => Some(SearchQuery::parse(&words.join(" "))?),
+    };
+    let config = Config::load()?;
+    let dir = config.log.dir().ok_or("Log directory is unknown.")?;
+    if !dir.exists() {
+        println!("No logs in {}.", dir.display());
+        return Ok(());
+    }
+    let records = read_logs(&dir, room.as_deref())
+        .map_err(|err| format!("Failed to read logs in {}: {}", dir.display(), err))?;
+    if records.is_empty() {
+        println!("No logs in {}.", dir.display());
+        return Ok(());
+    }
+    let mut stdout = io::stdout().lock();
+    for record in records {
+        if query
+            .as_ref()
+            .map(|query| query.matches(&record.to_msg()))
+            .unwrap_or(true)
+        {
+            let room = match record.room.starts_with('@') {
+                true => record.room.clone(),
+                false => format!("#{}", record.room),
+            };
+            writeln!(stdout, "{} {}", room, record.to_text())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn msg(msg_type: MessageType, sender: &str, target: &str, content: &str) -> Message {
+        Message {
+            msg_type,
+            msg_sender: sender.to_string(),
+            msg_target: target.to_string(),
+            msg_time: 1660000000,
+            msg_id: 7,
+            msg_content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chamber-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn build_records() {
+        let text = LogRecord::new(&msg(MessageType::TextMessage, "bob", "", "hi"), "alice");
+        assert_eq!(text.unwrap().room, "lobby");
+        let sent = LogRecord::new(
+            &msg(MessageType::DirectMessage, "alice", "bob", "hi"),
+            "alice",
+        );
+        assert_eq!(sent.unwrap().room, "@bob");
+        let received = LogRecord::new(
+            &msg(MessageType::DirectMessage, "bob", "alice", "hi"),
+            "alice",
+        );
+        assert_eq!(received.unwrap().room, "@bob");
+        let edited = LogRecord::new(
+            &msg(MessageType::MessageEdit, "bob", "alice", "hey"),
+            "alice",
+        );
+        assert_eq!(edited.unwrap().room, "@bob");
+        let deleted = LogRecord::new(
+            &msg(MessageType::MessageDelete, "alice", "bob", ""),
+            "alice",
+        );
+        assert_eq!(deleted.unwrap().room, "@bob");
+        assert!(LogRecord::new(&msg(MessageType::Typing, "bob", "", ""), "alice").is_none());
+
+        let record = LogRecord::new(
+            &msg(MessageType::ActionMessage, "bob", "", "waves\nagain"),
+            "alice",
+        )
+        .unwrap();
+        let json = serde_json::to_string(&record).unwrap();
+        assert!(!json.contains("reply_to"));
+        assert_eq!(serde_json::from_str::<LogRecord>(&json).unwrap(), record);
+        assert!(record.to_text().ends_with("(7) * bob waves\n    again"));
+    }
+
+    #[test]
+    fn write_and_rotate() {
+        let dir = test_dir("log");
+        let logger = ChatLogger {
+            dir: dir.clone(),
+            formats: vec![LogFormat::Text, LogFormat::Jsonl],
+            max_size: 1,
+        };
+        for (id, content) in [(1, "one"), (2, "two")] {
+            let msg = Message {
+                msg_id: id,
+                ..msg(MessageType::TextMessage, "bob", "", content)
+            };
+            logger.log(&msg, "alice").unwrap();
+        }
+        logger
+            .log(&msg(MessageType::Typing, "bob", "", ""), "alice")
+            .unwrap();
+        let date = LogRecord::new(&msg(MessageType::TextMessage, "", "", ""), "")
+            .unwrap()
+            .date();
+        let room_dir = dir.join("lobby");
+        for name in ["", ".1"] {
+            assert!(room_dir.join(format!("{}{}.log", date, name)).exists());
+            assert!(room_dir.join(format!("{}{}.jsonl", date, name)).exists());
+        }
+        assert!(!room_dir.join(format!("{}.2.log", date)).exists());
+
+        let records = read_logs(&dir, None).unwrap();
+        let contents: Vec<&str> = records.iter().map(|r| r.content.as_str()).collect();
+        assert_eq!(contents, vec!["one", "two"]);
+        assert!(read_logs(&dir, Some("nowhere")).unwrap().is_empty());
+
+        for room in ["..", "../escaped", "/tmp"] {
+            let msg = Message {
+                msg_room: room.to_string(),
+                ..msg(MessageType::TextMessage, "bob", "", "out")
+            };
+            assert!(logger.log(&msg, "alice").is_err());
+        }
+        let msg = msg(MessageType::DirectMessage, "../bob", "alice", "out");
+        assert!(logger.log(&msg, "alice").is_err());
+        assert!(!dir.join("escaped").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
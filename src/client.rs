@@ -1,55 +1,329 @@
+use crossterm::{
+    event::{self, Event, KeyCode, KeyModifiers},
+    terminal::{disable_raw_mode, enable_raw_mode},
+};
 use std::{
-    io::{self, Read, Write},
-    net::TcpStream,
-    str::from_utf8,
+    collections::HashMap,
+    error::Error,
+    io::{self, BufReader, IsTerminal, Write},
+    sync::{Arc, Mutex},
     thread,
 };
 
 use crate::{
-    consts::MSG_BUF_SIZE,
+    chat_log::ChatLogger,
+    command::{CommandAction, CommandContext, CommandRegistry},
+    config::{data_dir, Config},
+    consts::{DEFAULT_ROOM, SERVER_ADDR},
+    e2e::{fingerprint_lines, KeyRing},
+    gate::knock,
+    logger,
     message::{Message, MessageType},
+    transport::Connector,
 };
+use log::debug;
+
+/// Ids of msgs seen by the thread reading from server, used by commands.
+#[derive(Default)]
+struct SeenMsgs {
+    /// Your nickname, direct msgs are encrypted as from it
+    name: String,
+    /// Your last msg in each room, or with each user as `@nick`, for `/edit` and `/delete`
+    own: HashMap<String, u64>,
+    /// The last msg received, for `/react` and `/reply`
+    last: Option<u64>,
+    /// The other user if the last msg received is a direct msg, replies go to them
+    peer: Option<String>,
+}
 
-pub fn start() -> std::io::Result<()> {
-    let mut stream = TcpStream::connect("127.0.0.1:9999")?;
-    let mut stream_clone = stream.try_clone()?;
+pub fn start() -> Result<(), Box<dyn Error>> {
+    let config = Config::load()?;
+    logger::init(&config.logging, true, None)?;
+    let mut logger = ChatLogger::new(&config.log);
+    let mut stream = Connector::new(&config.tls)?.connect(SERVER_ADDR)?;
+    let mut reader = BufReader::new(stream.clone());
+    let mut first_msg = knock(&mut reader, &stream, &config.client)?;
+    let seen_msgs: Arc<Mutex<SeenMsgs>> = Arc::default();
+    let seen_msgs_clone = seen_msgs.clone();
+    let keys = data_dir()
+        .map(|dir| KeyRing::load(&dir.join("keys")))
+        .transpose()
+        .map_err(|err| format!("Failed to load keys: {}", err))?;
+    let keys = Arc::new(Mutex::new(keys));
+    let keys_clone = keys.clone();
 
     // create a new thread to receive msg from server
     thread::spawn(move || {
-        let mut buffer: Vec<u8> = vec![0; MSG_BUF_SIZE];
-        // let mut buffer = String::default();
+        let mut name = String::new();
         loop {
-            if let Ok(msg_size) = stream_clone.read(&mut buffer) {
-                // if let Ok(msg_size) = stream_clone.read_to_string(&mut buffer) {
-                if msg_size > 0 {
-                    // let msg = Message::convert_to_msg(&buffer);
-                    let msg = Message::convert_to_msg(from_utf8(&buffer[..msg_size]).unwrap());
-                    println!("Server broadcast: {}", msg)
+            let received = match first_msg.take() {
+                Some(msg) => Ok(Some(msg)),
+                None => Message::read_from(&mut reader),
+            };
+            if let Ok(Some(mut msg)) = received {
+                let mut keys = keys_clone.lock().unwrap();
+                if let Some(keys) = keys.as_mut() {
+                    match msg.msg_type {
+                        MessageType::DirectMessage => {
+                            keys.open(&mut msg, &name);
+                        }
+                        MessageType::MessageEdit if !msg.msg_target.is_empty() => {
+                            keys.open(&mut msg, &name);
+                        }
+                        MessageType::SearchResult => {
+                            let mut found = msg.found_msg();
+                            if let MessageType::DirectMessage = found.msg_type {
+                                keys.open(&mut found, &name);
+                                msg = Message::search_result(&found);
+                            }
+                        }
+                        MessageType::PublicKey if msg.msg_sender != name => {
+                            if let Some(warning) = keys.add_peer(&msg.msg_sender, &msg.msg_content)
+                            {
+                                println!("{}", warning);
+                            }
+                        }
+                        MessageType::NickChange => {
+                            if let Some(warning) =
+                                keys.rename_peer(&msg.msg_sender, &msg.msg_target)
+                            {
+                                println!("{}", warning);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                let mut seen_msgs = seen_msgs_clone.lock().unwrap();
+                if msg.msg_id != 0 {
+                    seen_msgs.last = Some(msg.msg_id);
+                }
+                // replies to the last msg go where it is
+                match msg.msg_type {
+                    MessageType::DirectMessage if msg.msg_sender == name => {
+                        seen_msgs.peer = Some(msg.msg_target.clone())
+                    }
+                    MessageType::DirectMessage => seen_msgs.peer = Some(msg.msg_sender.clone()),
+                    MessageType::TextMessage | MessageType::ActionMessage => seen_msgs.peer = None,
+                    _ => {}
+                }
+                match msg.msg_type {
+                    // typing events are for the TUI
+                    MessageType::Typing | MessageType::PublicKey => continue,
+                    MessageType::LoginPrompt => {
+                        println!(
+                            "Server asks you to log in ({}), use /login [nick] or /register [nick].",
+                            msg.msg_content
+                        );
+                        continue;
+                    }
+                    MessageType::ClientLogIn => {
+                        name = msg.msg_sender.clone();
+                        seen_msgs.name = name.clone();
+                        if let Some(keys) = keys.as_ref() {
+                            if let Err(err) = keys.public_key_msg().write_to(&mut reader.get_ref())
+                            {
+                                println!("Failed to publish your key: {}", err);
+                            }
+                        }
+                    }
+                    MessageType::NickChange if msg.msg_sender == name => {
+                        name = msg.msg_target.clone();
+                        seen_msgs.name = name.clone();
+                    }
+                    MessageType::TextMessage
+                    | MessageType::ActionMessage
+                    | MessageType::DirectMessage
+                        if msg.msg_sender == name =>
+                    {
+                        let place = match msg.msg_type {
+                            MessageType::DirectMessage => format!("@{}", msg.msg_target),
+                            _ => msg.msg_room.clone(),
+                        };
+                        seen_msgs.own.insert(place, msg.msg_id);
+                    }
+                    MessageType::MessageDelete => seen_msgs.own.retain(|_, id| *id != msg.msg_id),
+                    _ => {}
+                }
+                if let Some(Err(err)) = logger.as_ref().map(|logger| logger.log(&msg, &name)) {
+                    println!("Chat logging is off: {}", err);
+                    logger = None;
+                }
+                // ids are shown so msgs can be picked by commands
+                match msg.msg_type {
+                    MessageType::TextMessage
+                    | MessageType::ActionMessage
+                    | MessageType::DirectMessage
+                        if msg.msg_reply_to != 0 =>
+                    {
+                        println!(
+                            "Server broadcast: [#{}] ({}, reply to {}) {}",
+                            msg.msg_room,
+                            msg.msg_id,
+                            msg.msg_reply_to,
+                            msg.to_brief_string()
+                        )
+                    }
+                    MessageType::TextMessage
+                    | MessageType::ActionMessage
+                    | MessageType::DirectMessage => println!(
+                        "Server broadcast: [#{}] ({}) {}",
+                        msg.msg_room,
+                        msg.msg_id,
+                        msg.to_brief_string()
+                    ),
+                    _ => println!(
+                        "Server broadcast: [#{}] {}",
+                        msg.msg_room,
+                        msg.to_brief_string()
+                    ),
                 }
             } else {
                 println!("Server is offline now.");
-                stream_clone
-                    .shutdown(std::net::Shutdown::Both)
-                    .expect("Failed to shutdown stream.");
+                reader.get_ref().shutdown().unwrap_or_default();
                 break;
             }
         }
     });
 
+    // msgs typed are sent to this room, switched by `/join`
+    let mut current_room = DEFAULT_ROOM.to_string();
+    let commands = CommandRegistry::default();
     loop {
         let mut input = String::new();
         io::stdin()
             .read_line(&mut input)
             .expect("Failed to read input!");
-        let msg_bytes = input.as_bytes();
-        println!("Msg size: {} bytes.", msg_bytes.len());
-        let msg = Message {
-            msg_type: MessageType::TextMessage,
-            msg_sender: "local client: ".to_owned() + &stream.local_addr().unwrap().to_string(),
-            msg_content: from_utf8(msg_bytes).unwrap_or_default().to_string(),
+        let input = input.trim_end_matches(['\r', '\n']);
+        if input.trim().is_empty() {
+            continue;
+        }
+        debug!("Msg size: {} bytes.", input.len());
+        // commands work on msgs with the user of the last direct msg, or in current room
+        let (own, last, peer) = {
+            let seen_msgs = seen_msgs.lock().unwrap();
+            let place = match &seen_msgs.peer {
+                Some(peer) => format!("@{}", peer),
+                None => current_room.clone(),
+            };
+            let own = seen_msgs.own.get(&place).copied();
+            (own, seen_msgs.last, seen_msgs.peer.clone())
+        };
+        let ctx = CommandContext {
+            room: Some(&current_room),
+            peer: peer.as_deref(),
+            last_msg_id: own,
+            selected_msg_id: last,
+        };
+        let mut msg = match commands.parse(input, &ctx) {
+            Ok(CommandAction::Send(msg)) => msg,
+            Ok(CommandAction::Show(lines)) => {
+                lines.iter().for_each(|line| println!("{}", line));
+                continue;
+            }
+            Ok(CommandAction::ShowKeys(nick)) => {
+                let keys = keys.lock().unwrap();
+                for line in fingerprint_lines(keys.as_ref(), nick.as_deref()) {
+                    println!("{}", line);
+                }
+                continue;
+            }
+            Ok(CommandAction::Login { nick, register }) => match read_login(&nick, register) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    println!("{}", err);
+                    continue;
+                }
+            },
+            Ok(CommandAction::Clear) => continue,
+            Ok(CommandAction::Quit) => return Ok(()),
+            Err(reason) => {
+                println!("{}", reason);
+                continue;
+            }
         };
-        stream
-            .write(msg.to_string().as_bytes())
-            .expect("Failed to write!");
+        msg.msg_sender = "local client: ".to_owned() + &stream.local_addr().unwrap().to_string();
+        match msg.msg_type {
+            MessageType::RoomJoin => current_room = msg.msg_room.clone(),
+            MessageType::RoomPart if msg.msg_room == current_room => {
+                current_room = DEFAULT_ROOM.to_string()
+            }
+            _ => {}
+        }
+        // encrypt direct msgs, and edits of them, if keys of receivers are known
+        let peer = match msg.msg_type {
+            MessageType::DirectMessage => Some(msg.msg_target.clone()),
+            MessageType::MessageEdit => peer,
+            _ => None,
+        };
+        if let (Some(peer), Some(keys)) = (peer, keys.lock().unwrap().as_ref()) {
+            let name = seen_msgs.lock().unwrap().name.clone();
+            if !keys.seal(&mut msg, &name, &peer) && !confirm_plaintext(&peer)? {
+                println!("Not sent.");
+                continue;
+            }
+        }
+        msg.write_to(&mut stream).expect("Failed to write!");
+    }
+}
+
+/// Ask before sending a direct msg to `peer` in plaintext, for they have not published a key.
+fn confirm_plaintext(peer: &str) -> io::Result<bool> {
+    print!(
+        "{} has not published a key, send the msg unencrypted? [y/N] ",
+        peer
+    );
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Ask for the nickname if it is not given, and the password, to log in or register.
+fn read_login(nick: &str, register: bool) -> io::Result<Message> {
+    let nick = match nick {
+        "" => {
+            print!("Nickname: ");
+            io::stdout().flush()?;
+            let mut nick = String::new();
+            io::stdin().read_line(&mut nick)?;
+            nick.trim().to_string()
+        }
+        nick => nick.to_string(),
+    };
+    print!("Password: ");
+    io::stdout().flush()?;
+    let password = read_password()?;
+    Ok(Message::login(&nick, &password, register))
+}
+
+/// Read a line from stdin, it is not echoed if stdin is a terminal.
+pub fn read_password() -> io::Result<String> {
+    if !io::stdin().is_terminal() {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        return Ok(line.trim_end_matches(['\r', '\n']).to_string());
     }
+    // keys are read one by one in raw mode, so they are not echoed
+    enable_raw_mode()?;
+    let mut password = String::new();
+    let result = loop {
+        match event::read() {
+            Ok(Event::Key(key)) => match key.code {
+                KeyCode::Enter => break Ok(password),
+                KeyCode::Backspace => {
+                    password.pop();
+                }
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    break Err(io::Error::new(io::ErrorKind::Interrupted, "Cancelled."))
+                }
+                KeyCode::Char(ch) => password.push(ch),
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(err) => break Err(err),
+        }
+    };
+    disable_raw_mode()?;
+    println!();
+    result
 }
@@ -1,59 +1,250 @@
+use chrono::{Local, TimeZone};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyModifiers,
+    },
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use std::{
     error::Error,
-    io::{self, Read, Write},
-    net::TcpStream,
-    str::from_utf8,
+    io::{self, BufReader, Write},
     sync::mpsc,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tui::{
     backend::{Backend, CrosstermBackend},
-    layout::{Alignment, Constraint, Direction, Layout},
-    style::{Color, Style},
-    text::Spans,
-    widgets::{Block, Borders},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
+    style::{Modifier, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState},
     Frame, Terminal,
 };
 use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 use crate::{
-    consts::MSG_BUF_SIZE,
-    message::{Message, MessageType},
-    utils::{char_arr_to_string, string_to_char_vec},
-};
-use crate::{
+    chat_log::ChatLogger,
+    config::{data_dir, Config, NotifyConfig},
+    e2e::{fingerprint_lines, KeyRing},
+    gate::knock,
+    keymap::{Action, KeyScope, Keymap},
+    logger,
+    login::{LoginField, LoginForm},
+    markup,
+    palette::{palette_entries, Palette, PaletteAction, PaletteEntry},
     paragraph_chamber::{Paragraph, Wrap},
+    reflow_chamber::{truncate_line, wrap_lines},
+    search::{find_matches, highlight_matches, LocalSearch, SearchResults},
+    theme::Theme,
+    transport::{ChatStream, Connector},
     utils::char_vec_to_string,
 };
+use crate::{
+    command::{CommandAction, CommandContext, CommandRegistry},
+    consts::{
+        DEFAULT_ROOM, IDLE_SECS, MSG_BUF_SIZE, ROOM_MSGS_SIZE, SERVER_ADDR, TYPING_EXPIRE_SECS,
+        TYPING_INTERVAL_SECS,
+    },
+    message::{parse_reactions, Message, MessageType},
+    utils::{char_arr_to_string, string_to_char_vec},
+};
+use log::{debug, error, info, trace, warn};
 
 enum AppFocus {
     Editor,
     MsgList,
+    RoomList,
     ClientList,
 }
+impl AppFocus {
+    /// Get the block focused after pressing <Tab>.
+    fn next(&self) -> AppFocus {
+        match self {
+            AppFocus::Editor => AppFocus::MsgList,
+            AppFocus::MsgList => AppFocus::RoomList,
+            AppFocus::RoomList => AppFocus::ClientList,
+            AppFocus::ClientList => AppFocus::Editor,
+        }
+    }
+
+    /// Get the block focused after pressing <Shift+Tab>.
+    fn prev(&self) -> AppFocus {
+        match self {
+            AppFocus::Editor => AppFocus::ClientList,
+            AppFocus::MsgList => AppFocus::Editor,
+            AppFocus::RoomList => AppFocus::MsgList,
+            AppFocus::ClientList => AppFocus::RoomList,
+        }
+    }
+}
+
+/// A msg displayed in a room, with changes made after it is received.
+struct ReceivedMsg {
+    msg: Message,
+    /// Content has been changed by its author
+    edited: bool,
+    /// Reactions and their counts
+    reactions: Vec<(String, usize)>,
+}
+impl From<Message> for ReceivedMsg {
+    fn from(msg: Message) -> ReceivedMsg {
+        ReceivedMsg {
+            msg,
+            edited: false,
+            reactions: vec![],
+        }
+    }
+}
+
+/// Matches of the local search in a room, see `App::search_matches`.
+struct MatchCache {
+    room: usize,
+    query: String,
+    matches: Vec<(usize, usize)>,
+}
+
+/// A room joined by this client, every room keeps its own msgs and scroll position.
+///
+/// Direct msgs with another user are kept in a room named after that user.
+struct Room {
+    name: String,
+    is_direct: bool,
+    received_messages: Vec<ReceivedMsg>,
+    /// Number of lines scrolled back from the newest msg
+    scroll_back: usize,
+    /// Number of msgs received while the room is not displayed
+    unread: usize,
+    /// Number of msgs mentioning you not seen yet
+    mentions: usize,
+    /// Users typing in the room, with the time their last typing event came
+    typing: Vec<(String, Instant)>,
+}
+impl Room {
+    fn new(name: &str, is_direct: bool) -> Room {
+        Room {
+            name: name.to_string(),
+            is_direct,
+            received_messages: vec![],
+            scroll_back: 0,
+            unread: 0,
+            mentions: 0,
+            typing: vec![],
+        }
+    }
+
+    /// Clear unread msgs when the room is displayed,
+    /// mentions are cleared only if the newest msgs are displayed.
+    fn mark_read(&mut self) {
+        self.unread = 0;
+        if self.scroll_back == 0 {
+            self.mentions = 0;
+        }
+    }
+
+    /// Drop the oldest msgs beyond `ROOM_MSGS_SIZE`, but not the one at `keep`,
+    /// return where that one is after.
+    fn trim(&mut self, keep: usize) -> usize {
+        let excess = self.received_messages.len().saturating_sub(ROOM_MSGS_SIZE);
+        if excess == 0 {
+            return keep;
+        }
+        let kept = self.received_messages.remove(keep);
+        self.received_messages.drain(..excess);
+        let keep = keep.saturating_sub(excess);
+        self.received_messages.insert(keep, kept);
+        keep
+    }
+
+    /// Get name of the room displayed, like `#lobby` or `@alice`.
+    fn display_name(&self) -> String {
+        if self.is_direct {
+            format!("@{}", self.name)
+        } else {
+            format!("#{}", self.name)
+        }
+    }
+}
 
 struct App {
     focus: AppFocus,
-    received_messages: Vec<Message>,
+    rooms: Vec<Room>,
+    /// Index of the displayed room in `rooms`
+    current_room: usize,
+    /// Name given by server after connecting
+    name: String,
+    /// Names of online clients
+    online_clients: Vec<String>,
+    /// Index of the selected client in `online_clients`
+    selected_client: ListState,
     input_buffer: String,
     cursor_position: usize,
+    /// Id of your msg being edited in editor
+    editing: Option<u64>,
+    /// Id of the selected msg, highlighted when msg window is focused
+    selected_msg: Option<u64>,
     editor_width: usize,
-    stream: Option<TcpStream>,
+    msg_window_height: usize,
+    commands: CommandRegistry,
+    keymap: Keymap,
+    theme: Theme,
+    /// Command palette popup, `None` if it is closed
+    palette: Option<Palette>,
+    /// Scroll offset of keybinding help popup, `None` if it is closed
+    help_scroll: Option<u16>,
+    /// Search results popup, `None` if it is closed
+    search: Option<SearchResults>,
+    /// Search in msg window, `None` if nothing is searched
+    local_search: Option<LocalSearch>,
+    /// Matches of the search, dropped when msgs are changed
+    match_cache: Option<MatchCache>,
+    /// Login screen, `None` if it is closed
+    login: Option<LoginForm>,
+    notify: NotifyConfig,
+    /// Writes msgs to log files, `None` if logging is off or has failed
+    logger: Option<ChatLogger>,
+    /// Keys for encrypted direct msgs, `None` if they can not be loaded
+    keys: Option<KeyRing>,
+    /// Notifications about mentions waiting to be sent to the terminal
+    notifications: Vec<String>,
+    /// Time of the last key press, to guess if you are away
+    last_key_time: Instant,
+    /// Room (name and `is_direct`) you have told others you are typing in, and when
+    typing_sent: Option<(String, bool, Instant)>,
+    should_quit: bool,
+    stream: Option<ChatStream>,
 }
 impl Default for App {
     fn default() -> App {
         App {
             focus: AppFocus::Editor, // default mode
-            received_messages: vec![],
+            rooms: vec![Room::new(DEFAULT_ROOM, false)],
+            current_room: 0,
+            name: String::default(),
+            online_clients: vec![],
+            selected_client: ListState::default(),
             input_buffer: String::default(),
             cursor_position: 0,
+            editing: None,
+            selected_msg: None,
             editor_width: 0,
+            msg_window_height: 0,
+            commands: CommandRegistry::default(),
+            keymap: Keymap::default(),
+            theme: Theme::default(),
+            palette: None,
+            help_scroll: None,
+            search: None,
+            local_search: None,
+            match_cache: None,
+            login: None,
+            notify: NotifyConfig::default(),
+            logger: None,
+            keys: None,
+            notifications: vec![],
+            last_key_time: Instant::now(),
+            typing_sent: None,
+            should_quit: false,
             stream: None,
         }
     }
@@ -109,27 +300,661 @@ impl App {
         width
     }
 
-    /// Get number of actually occupied lines by the msgs received.
+    /// Get number of actually occupied lines by a msg in a room with `msgs`.
+    ///
+    /// Lines are wrapped on word boundaries like the msg window does.
+    fn lines_occupied_by_msg(&self, msg: &ReceivedMsg, msgs: &[ReceivedMsg]) -> usize {
+        let lines = msg_lines(msg, msgs, self.editor_width, &self.theme, &self.name, None);
+        wrap_lines(&lines, self.msg_window_width(), false, false).len()
+    }
+
+    /// Width of text in msg window, the same as the editor.
+    fn msg_window_width(&self) -> u16 {
+        self.editor_width.min(u16::MAX as usize) as u16
+    }
+
+    /// Get number of actually occupied lines by the msgs received in current room.
     fn lines_occupied_by_msg_received(&self) -> usize {
-        let mut lines: usize = 0;
-        for msg in &self.received_messages {
-            let msg_str = msg.to_brief_string();
-            let mut occupied_width = 0;
-            for ch in msg_str.chars() {
-                let ch_width = ch.width().unwrap_or_default();
-                let additional_width = (occupied_width + ch_width) % self.editor_width;
-                if occupied_width + ch_width < self.editor_width {
-                    occupied_width += ch_width;
+        let msgs = &self.rooms[self.current_room].received_messages;
+        msgs.iter()
+            .map(|msg| self.lines_occupied_by_msg(msg, msgs))
+            .sum()
+    }
+
+    /// Get the max number of lines that can be scrolled back in current room.
+    fn max_scroll_back(&self) -> usize {
+        self.lines_occupied_by_msg_received()
+            .saturating_sub(self.msg_window_height)
+    }
+
+    /// Scroll msgs in current room, positive `lines` means scrolling back to older msgs.
+    fn scroll_msgs(&mut self, lines: isize) {
+        let max_scroll_back = self.max_scroll_back();
+        let room = &mut self.rooms[self.current_room];
+        room.scroll_back = room
+            .scroll_back
+            .saturating_add_signed(lines)
+            .min(max_scroll_back);
+        room.mark_read();
+    }
+
+    /// Display another room, `step` can be negative.
+    fn switch_room(&mut self, step: isize) {
+        let room_num = self.rooms.len() as isize;
+        self.current_room = (self.current_room as isize + step).rem_euclid(room_num) as usize;
+        self.rooms[self.current_room].mark_read();
+    }
+
+    /// Move the highlight through msgs in current room, `step` can be negative.
+    ///
+    /// Only msgs with ids can be selected, the newest one is selected first,
+    /// and msgs are scrolled to keep the selected one displayed.
+    fn select_msg(&mut self, step: isize) {
+        let msgs = &self.rooms[self.current_room].received_messages;
+        let selectable: Vec<usize> = (0..msgs.len())
+            .filter(|index| msgs[*index].msg.msg_id != 0)
+            .collect();
+        if selectable.is_empty() {
+            self.selected_msg = None;
+            return;
+        }
+        let position = match self.selected_msg.and_then(|id| {
+            selectable
+                .iter()
+                .position(|index| msgs[*index].msg.msg_id == id)
+        }) {
+            Some(position) => {
+                (position as isize + step).clamp(0, selectable.len() as isize - 1) as usize
+            }
+            None => selectable.len() - 1,
+        };
+        let index = selectable[position];
+        self.selected_msg = Some(msgs[index].msg.msg_id);
+        self.scroll_to_msg(index);
+    }
+
+    /// Scroll current room as little as possible to display the msg at `index`.
+    fn scroll_to_msg(&mut self, index: usize) {
+        let msgs = &self.rooms[self.current_room].received_messages;
+        let lines_below = self.lines_below_msg(index);
+        let lines = self.lines_occupied_by_msg(&msgs[index], msgs);
+        self.scroll_to_lines(lines_below, lines);
+    }
+
+    /// Get number of lines taken by msgs after the one at `index` in current room.
+    fn lines_below_msg(&self, index: usize) -> usize {
+        let msgs = &self.rooms[self.current_room].received_messages;
+        msgs[index + 1..]
+            .iter()
+            .map(|msg| self.lines_occupied_by_msg(msg, msgs))
+            .sum()
+    }
+
+    /// Scroll current room as little as possible to display `lines` lines,
+    /// which have `lines_below` lines under them.
+    fn scroll_to_lines(&mut self, lines_below: usize, lines: usize) {
+        let room = &self.rooms[self.current_room];
+        let scroll_back = if room.scroll_back > lines_below {
+            lines_below
+        } else if lines_below + lines > room.scroll_back + self.msg_window_height {
+            (lines_below + lines).saturating_sub(self.msg_window_height)
+        } else {
+            room.scroll_back
+        };
+        let scroll_back = scroll_back.min(self.max_scroll_back());
+        let room = &mut self.rooms[self.current_room];
+        room.scroll_back = scroll_back;
+        room.mark_read();
+    }
+
+    /// Get id of the selected msg, or of the last msg having an id in current room.
+    fn selected_msg_id(&self) -> Option<u64> {
+        let mut ids = self.rooms[self.current_room]
+            .received_messages
+            .iter()
+            .rev()
+            .map(|received| received.msg.msg_id)
+            .filter(|id| *id != 0);
+        match self.selected_msg {
+            Some(selected) => ids.find(|id| *id == selected),
+            None => ids.next(),
+        }
+    }
+
+    /// Get lines of the msg at `index` in current room, with matches of the search highlighted.
+    ///
+    /// `current` is the index of the current match in this msg.
+    fn highlighted_msg_lines(&self, index: usize, current: Option<usize>) -> Vec<Spans<'static>> {
+        let msgs = &self.rooms[self.current_room].received_messages;
+        let search = self
+            .local_search
+            .as_ref()
+            .map(|search| (search.query.as_str(), current));
+        msg_lines(
+            &msgs[index],
+            msgs,
+            self.editor_width,
+            &self.theme,
+            &self.name,
+            search,
+        )
+    }
+
+    /// Get all matches of the search in contents of msgs in current room
+    /// as (index of msg, index of match in msg), the oldest comes first.
+    ///
+    /// Matches are kept until the query, current room or its msgs change.
+    fn search_matches(&mut self) -> &[(usize, usize)] {
+        let query = match &self.local_search {
+            Some(search) if !search.query.is_empty() => search.query.clone(),
+            _ => return &[],
+        };
+        let cached = matches!(
+            &self.match_cache,
+            Some(cache) if cache.room == self.current_room && cache.query == query
+        );
+        if !cached {
+            let msgs = &self.rooms[self.current_room].received_messages;
+            let matches = msgs
+                .iter()
+                .enumerate()
+                .flat_map(|(index, received)| {
+                    let count = find_matches(&displayed_content(&received.msg), &query).len();
+                    (0..count).map(move |nth| (index, nth))
+                })
+                .collect();
+            self.match_cache = Some(MatchCache {
+                room: self.current_room,
+                query,
+                matches,
+            });
+        }
+        self.match_cache
+            .as_ref()
+            .map(|cache| cache.matches.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Get the current match of the search, see `search_matches`.
+    fn current_match(&mut self) -> Option<(usize, usize)> {
+        let current = self.local_search.as_ref()?.current;
+        let matches = self.search_matches();
+        matches
+            .len()
+            .checked_sub(current + 1)
+            .map(|index| matches[index])
+    }
+
+    /// Move to another match of the search, positive `step` goes to older matches.
+    fn select_match(&mut self, step: isize) {
+        let match_num = self.search_matches().len();
+        let search = match self.local_search.as_mut() {
+            Some(search) => search,
+            None => return,
+        };
+        if match_num == 0 {
+            search.current = 0;
+            return;
+        }
+        search.current = (search.current as isize + step).clamp(0, match_num as isize - 1) as usize;
+        self.scroll_to_current_match();
+    }
+
+    /// Scroll to the line where the current match is displayed, lines are wrapped
+    /// like the msg window does.
+    fn scroll_to_current_match(&mut self) {
+        let (index, nth) = match self.current_match() {
+            Some(current) => current,
+            None => return,
+        };
+        let lines = self.highlighted_msg_lines(index, Some(nth));
+        let rows = wrap_lines(&lines, self.msg_window_width(), false, false);
+        // the current match is found by its style
+        let row = rows
+            .iter()
+            .position(|row| {
+                row.iter()
+                    .any(|grapheme| grapheme.style.bg == Some(self.theme.search_current))
+            })
+            .unwrap_or_default();
+        let lines_below = self.lines_below_msg(index) + rows.len().saturating_sub(row + 1);
+        self.scroll_to_lines(lines_below, 1);
+    }
+
+    /// Handle a key when the search in msg window is typed, the query is typed like in editor.
+    fn handle_local_search_key(&mut self, key: KeyEvent) {
+        let action = self.keymap.action(KeyScope::Editor, key);
+        let search = match self.local_search.as_mut() {
+            Some(search) => search,
+            None => return,
+        };
+        match (action, key.code) {
+            (Some(Action::Cancel), _) | (_, KeyCode::Esc) => self.local_search = None,
+            (Some(Action::Send), _) => search.typing = false,
+            (Some(Action::FocusNext), _) => {
+                search.typing = false;
+                self.focus = self.focus.next();
+            }
+            (Some(Action::FocusPrev), _) => {
+                search.typing = false;
+                self.focus = self.focus.prev();
+            }
+            (Some(Action::DeleteBackward), _) => {
+                search.query.pop();
+                search.current = 0;
+                self.scroll_to_current_match();
+            }
+            (None, KeyCode::Char(ch)) if (key.modifiers - KeyModifiers::SHIFT).is_empty() => {
+                search.query.push(ch);
+                search.current = 0;
+                self.scroll_to_current_match();
+            }
+            _ => {}
+        }
+    }
+
+    /// Select the msg quoted by the selected reply, and scroll to it.
+    fn jump_to_parent(&mut self) {
+        let msgs = &self.rooms[self.current_room].received_messages;
+        let reply_to = self
+            .selected_msg_id()
+            .and_then(|id| msgs.iter().find(|received| received.msg.msg_id == id))
+            .map(|received| received.msg.msg_reply_to)
+            .unwrap_or_default();
+        if reply_to == 0 {
+            self.show_local(
+                MessageType::Error,
+                "The selected msg is not a reply.".to_string(),
+            );
+            return;
+        }
+        match msgs
+            .iter()
+            .position(|received| received.msg.msg_id == reply_to)
+        {
+            Some(index) => {
+                self.selected_msg = Some(reply_to);
+                self.scroll_to_msg(index);
+            }
+            None => self.show_local(
+                MessageType::Error,
+                format!(
+                    "Msg {} is not here, it may be cleared or sent before you came.",
+                    reply_to
+                ),
+            ),
+        }
+    }
+
+    /// Get index of a joined room, or of the direct msgs with a user if `is_direct`.
+    fn room_index(&self, name: &str, is_direct: bool) -> Option<usize> {
+        self.rooms
+            .iter()
+            .position(|room| room.name == name && room.is_direct == is_direct)
+    }
+
+    /// Get index of a room, the room is created if it does not exist.
+    fn room_index_or_insert(&mut self, name: &str, is_direct: bool) -> usize {
+        match self.room_index(name, is_direct) {
+            Some(index) => index,
+            None => {
+                self.rooms.push(Room::new(name, is_direct));
+                self.rooms.len() - 1
+            }
+        }
+    }
+
+    /// Move selection in the client list, `step` can be negative.
+    fn select_client(&mut self, step: isize) {
+        if self.online_clients.is_empty() {
+            self.selected_client.select(None);
+            return;
+        }
+        let client_num = self.online_clients.len() as isize;
+        let selected = match self.selected_client.selected() {
+            Some(selected) => (selected as isize + step).rem_euclid(client_num) as usize,
+            None => 0,
+        };
+        self.selected_client.select(Some(selected));
+    }
+
+    /// Open direct msgs with the selected client.
+    fn open_direct_msgs(&mut self) {
+        if let Some(name) = self
+            .selected_client
+            .selected()
+            .and_then(|selected| self.online_clients.get(selected))
+            .cloned()
+        {
+            self.current_room = self.room_index_or_insert(&name, true);
+            self.rooms[self.current_room].mark_read();
+            self.focus = AppFocus::Editor;
+        }
+    }
+
+    /// Push a msg into a room, and keep what is displayed if the room has been scrolled back.
+    ///
+    /// You are notified if the msg mentions you while it can not be seen,
+    /// or no key is pressed for a while.
+    fn push_msg(&mut self, index: usize, msg: Message) {
+        let received = ReceivedMsg::from(msg);
+        let lines = self.lines_occupied_by_msg(&received, &self.rooms[index].received_messages);
+        let msg = &received.msg;
+        let mentioned = mentions_user(msg, &self.name);
+        let room = &mut self.rooms[index];
+        let hidden = index != self.current_room || room.scroll_back > 0;
+        if room.scroll_back > 0 {
+            room.scroll_back += lines;
+        }
+        if index != self.current_room {
+            room.unread += 1;
+        }
+        if mentioned && hidden {
+            room.mentions += 1;
+        }
+        // whoever sends a msg has stopped typing
+        room.typing.retain(|(name, _)| *name != msg.msg_sender);
+        // crossterm does not report focus of the terminal, so being idle is taken as away
+        let away = self.last_key_time.elapsed() >= Duration::from_secs(IDLE_SECS);
+        if mentioned && (hidden || away) {
+            self.notifications.push(format!(
+                "{} in {}: {}",
+                msg.msg_sender,
+                room.display_name(),
+                msg.msg_content
+            ));
+        }
+        room.received_messages.push(received);
+        room.trim(room.received_messages.len() - 1);
+        self.match_cache = None;
+    }
+
+    /// Find a msg in all rooms by its id.
+    fn find_msg(&self, id: u64) -> Option<(usize, usize)> {
+        self.rooms
+            .iter()
+            .enumerate()
+            .find_map(|(room_index, room)| {
+                room.received_messages
+                    .iter()
+                    .position(|received| received.msg.msg_id == id)
+                    .map(|msg_index| (room_index, msg_index))
+            })
+    }
+
+    /// Get your last msg in current room which can be edited.
+    fn last_own_msg(&self) -> Option<&Message> {
+        self.rooms[self.current_room]
+            .received_messages
+            .iter()
+            .rev()
+            .map(|received| &received.msg)
+            .find(|msg| {
+                msg.msg_id != 0
+                    && msg.msg_sender == self.name
+                    && matches!(
+                        msg.msg_type,
+                        MessageType::TextMessage
+                            | MessageType::ActionMessage
+                            | MessageType::DirectMessage
+                    )
+            })
+    }
+
+    /// Put your last msg in current room into editor to edit it.
+    fn edit_last_msg(&mut self) {
+        if let Some((id, content)) = self
+            .last_own_msg()
+            .map(|msg| (msg.msg_id, msg.msg_content.clone()))
+        {
+            self.editing = Some(id);
+            self.input_buffer = content;
+            self.cursor_position = self.input_buffer.chars().count();
+        }
+    }
+
+    /// Stop editing a msg, what is in editor is dropped.
+    fn cancel_editing(&mut self) {
+        self.editing = None;
+        self.input_buffer.clear();
+        self.cursor_position = 0;
+    }
+
+    /// Apply an edit, a deletion or reactions from server to the msg displayed.
+    fn change_msg(&mut self, msg: Message) {
+        let (room_index, msg_index) = match self.find_msg(msg.msg_id) {
+            Some(found) => found,
+            None => return,
+        };
+        let received = &mut self.rooms[room_index].received_messages[msg_index];
+        if matches!(msg.msg_type, MessageType::MessageEdit) {
+            received.msg.msg_content = msg.msg_content;
+            received.edited = true;
+        } else if matches!(msg.msg_type, MessageType::Reaction) {
+            received.reactions = parse_reactions(&msg.msg_content);
+        } else {
+            self.rooms[room_index].received_messages.remove(msg_index);
+            if self.editing == Some(msg.msg_id) {
+                self.cancel_editing();
+            }
+        }
+        self.match_cache = None;
+        // lines of the room are changed, keep scroll position valid
+        if room_index == self.current_room {
+            self.scroll_msgs(0);
+        }
+    }
+
+    /// Update users typing in a room by a typing event from server.
+    fn set_typing(&mut self, msg: Message) {
+        let index = if msg.msg_target.is_empty() {
+            self.room_index(&msg.msg_room, false)
+        } else {
+            self.room_index(&msg.msg_sender, true)
+        };
+        if let Some(index) = index {
+            let typing = &mut self.rooms[index].typing;
+            typing.retain(|(name, _)| *name != msg.msg_sender);
+            if msg.msg_content == "start" {
+                typing.push((msg.msg_sender, Instant::now()));
+            }
+        }
+    }
+
+    /// Forget users whose typing events are too old, they may have left without a `stop`.
+    fn expire_typing(&mut self) {
+        for room in self.rooms.iter_mut() {
+            room.typing
+                .retain(|(_, time)| time.elapsed() < Duration::from_secs(TYPING_EXPIRE_SECS));
+        }
+    }
+
+    /// Tell others whether you are typing in current room.
+    ///
+    /// A `start` is sent again only after `TYPING_INTERVAL_SECS`,
+    /// and a `stop` is sent when the editor is cleared or another room is displayed.
+    fn update_typing(&mut self) -> std::io::Result<()> {
+        let room = &self.rooms[self.current_room];
+        // editing a msg or typing a command is not taken as typing
+        let is_typing = !self.input_buffer.trim().is_empty()
+            && !self.input_buffer.starts_with('/')
+            && self.editing.is_none();
+        if let Some((name, is_direct, time)) = &self.typing_sent {
+            let same_room = *name == room.name && *is_direct == room.is_direct;
+            if is_typing && same_room && time.elapsed() < Duration::from_secs(TYPING_INTERVAL_SECS)
+            {
+                return Ok(());
+            }
+            if !is_typing || !same_room {
+                self.send_typing(name, *is_direct, false)?;
+                self.typing_sent = None;
+            }
+        }
+        if is_typing {
+            let room = &self.rooms[self.current_room];
+            self.send_typing(&room.name, room.is_direct, true)?;
+            self.typing_sent = Some((room.name.clone(), room.is_direct, Instant::now()));
+        }
+        Ok(())
+    }
+
+    fn send_typing(&self, room: &str, is_direct: bool, is_typing: bool) -> std::io::Result<()> {
+        let msg = if is_direct {
+            Message::typing(DEFAULT_ROOM, room, is_typing)
+        } else {
+            Message::typing(room, "", is_typing)
+        };
+        match self.stream.as_ref() {
+            Some(mut stream) => msg.write_to(&mut stream),
+            None => Ok(()),
+        }
+    }
+
+    /// Get number of mentions not seen in all rooms.
+    fn mentions(&self) -> usize {
+        self.rooms.iter().map(|room| room.mentions).sum()
+    }
+
+    /// Put a msg from server into the room it belongs to.
+    fn handle_received_msg(&mut self, mut msg: Message) {
+        trace!("Received {}", msg);
+        if let Some(keys) = &self.keys {
+            // edits of direct msgs have targets
+            let is_direct = match msg.msg_type {
+                MessageType::DirectMessage => true,
+                MessageType::MessageEdit => !msg.msg_target.is_empty(),
+                _ => false,
+            };
+            if is_direct {
+                keys.open(&mut msg, &self.name);
+            }
+        }
+        if let Some(logger) = &self.logger {
+            if let Err(err) = logger.log(&msg, &self.name) {
+                // stop logging instead of showing the error for every msg
+                self.logger = None;
+                warn!("Chat logging is off: {}", err);
+                self.show_local(MessageType::Error, format!("Chat logging is off: {}", err));
+            }
+        }
+        match msg.msg_type {
+            MessageType::ClientLogIn => {
+                self.name = msg.msg_sender.clone();
+                self.push_msg(self.current_room, msg);
+                let published = match (&self.keys, self.stream.as_ref()) {
+                    (Some(keys), Some(mut stream)) => keys.public_key_msg().write_to(&mut stream),
+                    _ => Ok(()),
+                };
+                if let Err(err) = published {
+                    self.show_local(
+                        MessageType::Error,
+                        format!("Failed to publish your key: {}", err),
+                    );
+                }
+            }
+            MessageType::PublicKey if msg.msg_sender != self.name => {
+                let warning = self
+                    .keys
+                    .as_mut()
+                    .and_then(|keys| keys.add_peer(&msg.msg_sender, &msg.msg_content));
+                self.warn_about_key(&msg.msg_sender, warning);
+            }
+            MessageType::RoomJoin => {
+                let index = self.room_index_or_insert(&msg.msg_room, false);
+                if msg.msg_sender == self.name {
+                    self.current_room = index;
+                }
+                self.push_msg(index, msg);
+            }
+            MessageType::RoomPart if msg.msg_sender == self.name => {
+                if let Some(index) = self.room_index(&msg.msg_room, false) {
+                    self.rooms.remove(index);
+                    if self.current_room >= index {
+                        self.current_room = self.current_room.saturating_sub(1);
+                    }
+                    self.rooms[self.current_room].mark_read();
+                    self.push_msg(self.current_room, msg);
+                }
+            }
+            MessageType::ClientListUpdate => {
+                let selected_name = self
+                    .selected_client
+                    .selected()
+                    .and_then(|selected| self.online_clients.get(selected))
+                    .cloned();
+                // the list looks like `0:name,1:name`
+                self.online_clients = msg
+                    .msg_content
+                    .split(',')
+                    .filter_map(|client| client.split_once(':'))
+                    .map(|(_, name)| name.to_string())
+                    .collect();
+                // keep the selected client selected
+                let selected = selected_name
+                    .and_then(|name| self.online_clients.iter().position(|n| *n == name));
+                self.selected_client.select(selected);
+            }
+            MessageType::NickChange => {
+                if msg.msg_sender == self.name {
+                    self.name = msg.msg_target.clone();
+                }
+                // direct msgs with the user are kept after renaming
+                if let Some(index) = self.room_index(&msg.msg_sender, true) {
+                    self.rooms[index].name = msg.msg_target.clone();
+                }
+                let warning = self
+                    .keys
+                    .as_mut()
+                    .and_then(|keys| keys.rename_peer(&msg.msg_sender, &msg.msg_target));
+                let new_name = msg.msg_target.clone();
+                self.push_msg(self.current_room, msg);
+                self.warn_about_key(&new_name, warning);
+            }
+            MessageType::DirectMessage => {
+                let peer = if msg.msg_sender == self.name {
+                    msg.msg_target.clone()
                 } else {
-                    occupied_width = additional_width;
-                    lines += 1;
+                    msg.msg_sender.clone()
+                };
+                let index = self.room_index_or_insert(&peer, true);
+                self.push_msg(index, msg);
+            }
+            MessageType::Typing => self.set_typing(msg),
+            MessageType::Search => self.search = Some(SearchResults::new(&msg.msg_content)),
+            MessageType::SearchResult => {
+                let mut found = msg.found_msg();
+                if let (Some(keys), MessageType::DirectMessage) = (&self.keys, &found.msg_type) {
+                    keys.open(&mut found, &self.name);
+                }
+                if let Some(search) = self.search.as_mut() {
+                    search.push(found);
+                }
+            }
+            MessageType::MessageEdit | MessageType::MessageDelete | MessageType::Reaction => {
+                self.change_msg(msg)
+            }
+            MessageType::LoginPrompt => {
+                let required = msg.msg_content == "required";
+                self.login = Some(LoginForm::new("", false, required));
+            }
+            MessageType::Login => {
+                self.login = None;
+                self.push_msg(self.current_room, msg);
+            }
+            MessageType::Error => {
+                // failures of logging in are shown in the login screen too
+                if let Some(login) = self.login.as_mut() {
+                    login.error = Some(msg.msg_content.clone());
                 }
+                self.push_msg(self.current_room, msg)
             }
-            if occupied_width > 0 {
-                lines += 1;
+            _ => {
+                let index = self
+                    .room_index(&msg.msg_room, false)
+                    .unwrap_or(self.current_room);
+                self.push_msg(index, msg);
             }
         }
-        lines
     }
 
     /// remove a char just before the cursor
@@ -202,35 +1027,495 @@ impl App {
         self.cursor_position += steps_to_move;
     }
 
-    /// send msg in `input_buffer` to server
+    /// Show a warning about the key of `nick` in direct msgs with them, or in current room.
+    fn warn_about_key(&mut self, nick: &str, warning: Option<String>) {
+        if let Some(warning) = warning {
+            let msg = Message {
+                msg_type: MessageType::Error,
+                msg_sender: "localhost".to_string(),
+                msg_content: warning,
+                ..Default::default()
+            };
+            let index = self.room_index(nick, true).unwrap_or(self.current_room);
+            self.push_msg(index, msg);
+        }
+    }
+
+    /// Encrypt a direct msg, or an edit of one, if the key of its receiver is known.
+    fn seal_msg(&self, msg: &mut Message) {
+        let keys = match &self.keys {
+            Some(keys) => keys,
+            None => return,
+        };
+        let peer = match msg.msg_type {
+            MessageType::DirectMessage => Some(msg.msg_target.clone()),
+            MessageType::MessageEdit => self
+                .rooms
+                .iter()
+                .find(|room| {
+                    room.is_direct
+                        && room
+                            .received_messages
+                            .iter()
+                            .any(|received| received.msg.msg_id == msg.msg_id)
+                })
+                .map(|room| room.name.clone()),
+            _ => None,
+        };
+        if let Some(peer) = peer {
+            keys.seal(msg, &self.name, &peer);
+        }
+    }
+
+    /// Show a line in current room, it is not sent to server.
+    fn show_local(&mut self, msg_type: MessageType, content: String) {
+        let msg = Message {
+            msg_type,
+            msg_sender: "localhost".to_string(),
+            msg_content: content,
+            ..Default::default()
+        };
+        self.push_msg(self.current_room, msg);
+    }
+
+    /// Complete the command or nickname before cursor.
+    ///
+    /// If there is nothing to complete, focus moves to the next block like in other blocks.
+    fn complete_input(&mut self) {
+        let completion = match self.commands.complete(
+            &self.input_buffer,
+            self.cursor_position,
+            &self.online_clients,
+        ) {
+            Some(completion) => completion,
+            None => {
+                self.focus = self.focus.next();
+                return;
+            }
+        };
+        if completion.candidates.len() > 1 {
+            self.show_local(MessageType::Notice, completion.candidates.join("  "));
+        }
+        self.input_buffer = completion.input;
+        self.cursor_position = completion.cursor_position;
+    }
+
+    /// remove the char just after the cursor
+    fn remove_a_char_after_cursor(&mut self) {
+        if self.cursor_position < self.input_buffer.chars().count() {
+            self.cursor_position += 1;
+            self.remove_a_char_before_cursor();
+        }
+    }
+
+    /// Do what an action means in the focused block.
+    fn run_action(&mut self, action: Action) -> std::io::Result<()> {
+        match (action, &self.focus) {
+            (Action::Send, AppFocus::Editor) => self.send_msg()?,
+            (Action::Send, AppFocus::RoomList) => self.focus = AppFocus::Editor,
+            (Action::Send, AppFocus::ClientList) => self.open_direct_msgs(),
+            (Action::Send, AppFocus::MsgList) if self.selected_msg_id().is_some() => {
+                // react to the selected msg
+                self.input_buffer = "/react ".to_string();
+                self.cursor_position = self.input_buffer.chars().count();
+                self.focus = AppFocus::Editor;
+            }
+            (Action::Reply, AppFocus::MsgList) => {
+                if let Some(id) = self.selected_msg_id() {
+                    self.input_buffer = format!("/reply #{} ", id);
+                    self.cursor_position = self.input_buffer.chars().count();
+                    self.focus = AppFocus::Editor;
+                }
+            }
+            (Action::JumpToParent, AppFocus::MsgList) => self.jump_to_parent(),
+            (Action::Search, AppFocus::MsgList) => {
+                self.local_search = Some(LocalSearch {
+                    typing: true,
+                    ..Default::default()
+                })
+            }
+            (Action::NextMatch, AppFocus::MsgList) => self.select_match(1),
+            (Action::PrevMatch, AppFocus::MsgList) => self.select_match(-1),
+            (Action::Cancel, AppFocus::MsgList) if self.local_search.is_some() => {
+                self.local_search = None
+            }
+            (Action::Cancel, AppFocus::Editor) if self.editing.is_some() => self.cancel_editing(),
+            (Action::Cancel, _) => {
+                self.selected_msg = None;
+                self.focus = AppFocus::Editor
+            }
+            (Action::Quit, _) => self.should_quit = true,
+            (Action::FocusNext, _) => self.focus = self.focus.next(),
+            (Action::FocusPrev, _) => self.focus = self.focus.prev(),
+            (Action::NextRoom, _) => self.switch_room(1),
+            (Action::PrevRoom, _) => self.switch_room(-1),
+            (Action::MoveUp, AppFocus::Editor) if self.input_buffer.is_empty() => {
+                self.edit_last_msg()
+            }
+            (Action::MoveUp, AppFocus::Editor) => self.move_cursor_up(),
+            (Action::MoveUp, AppFocus::MsgList) => self.select_msg(-1),
+            (Action::MoveUp, AppFocus::RoomList) => self.switch_room(-1),
+            (Action::MoveUp, AppFocus::ClientList) => self.select_client(-1),
+            (Action::MoveDown, AppFocus::Editor) => self.move_cursor_down(),
+            (Action::MoveDown, AppFocus::MsgList) => self.select_msg(1),
+            (Action::MoveDown, AppFocus::RoomList) => self.switch_room(1),
+            (Action::MoveDown, AppFocus::ClientList) => self.select_client(1),
+            (Action::MoveLeft, AppFocus::Editor) => {
+                self.cursor_position = self.cursor_position.saturating_sub(1)
+            }
+            (Action::MoveRight, AppFocus::Editor) => {
+                self.cursor_position =
+                    (self.cursor_position + 1).min(self.input_buffer.chars().count())
+            }
+            (Action::MoveHome, AppFocus::Editor) => self.cursor_position = 0,
+            (Action::MoveHome, AppFocus::MsgList) => self.scroll_msgs(isize::MAX),
+            (Action::MoveEnd, AppFocus::Editor) => {
+                self.cursor_position = self.input_buffer.chars().count()
+            }
+            (Action::MoveEnd, AppFocus::MsgList) => self.rooms[self.current_room].scroll_back = 0,
+            (Action::PageUp, _) => self.scroll_msgs(self.msg_window_height as isize),
+            (Action::PageDown, _) => self.scroll_msgs(-(self.msg_window_height as isize)),
+            (Action::DeleteBackward, AppFocus::Editor) => self.remove_a_char_before_cursor(),
+            (Action::DeleteForward, AppFocus::Editor) => self.remove_a_char_after_cursor(),
+            (Action::ClearInput, _) => {
+                self.input_buffer.clear();
+                self.cursor_position = 0;
+            }
+            (Action::Complete, AppFocus::Editor) => self.complete_input(),
+            (Action::Complete, _) => self.focus = self.focus.next(),
+            (Action::OpenPalette, _) => self.palette = Some(Palette::default()),
+            (Action::ShowHelp, _) => self.help_scroll = Some(0),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Handle a key pressed.
+    fn handle_key(&mut self, key: KeyEvent) -> std::io::Result<()> {
+        self.last_key_time = Instant::now();
+        let input_before = self.input_buffer.clone();
+        let room_before = self.current_room;
+        self.handle_key_inner(key)?;
+        if self.input_buffer != input_before || self.current_room != room_before {
+            self.update_typing()?;
+        }
+        Ok(())
+    }
+
+    fn handle_key_inner(&mut self, key: KeyEvent) -> std::io::Result<()> {
+        // popups take all keys when they are open
+        if self.login.is_some() {
+            return self.handle_login_key(key);
+        }
+        if self.palette.is_some() {
+            return self.handle_palette_key(key);
+        }
+        if let Some(help_scroll) = self.help_scroll {
+            self.help_scroll = match self.keymap.action(KeyScope::Browse, key) {
+                Some(Action::MoveUp) => Some(help_scroll.saturating_sub(1)),
+                Some(Action::MoveDown) => Some(help_scroll.saturating_add(1)),
+                Some(Action::PageUp) => Some(help_scroll.saturating_sub(10)),
+                Some(Action::PageDown) => Some(help_scroll.saturating_add(10)),
+                _ => None,
+            };
+            return Ok(());
+        }
+        if self.search.is_some() {
+            self.handle_search_key(key);
+            return Ok(());
+        }
+        if matches!(&self.local_search, Some(search) if search.typing) {
+            self.handle_local_search_key(key);
+            return Ok(());
+        }
+
+        let scope = match self.focus {
+            AppFocus::Editor => KeyScope::Editor,
+            _ => KeyScope::Browse,
+        };
+        match (self.keymap.action(scope, key), key.code) {
+            (Some(action), _) => self.run_action(action)?,
+            // length of msg should be shorter
+            // for there are msg type and sender in Message
+            (None, KeyCode::Char(ch))
+                if matches!(self.focus, AppFocus::Editor)
+                    && (key.modifiers - KeyModifiers::SHIFT).is_empty()
+                    && self.input_buffer.len() < MSG_BUF_SIZE =>
+            {
+                self.input_buffer
+                    .insert(self.len_of_str_before_cursor(), ch);
+                self.cursor_position += 1;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Handle a key in the login screen, the fields are typed like in editor.
+    fn handle_login_key(&mut self, key: KeyEvent) -> std::io::Result<()> {
+        let action = self.keymap.action(KeyScope::Editor, key);
+        let login = match self.login.as_mut() {
+            Some(login) => login,
+            None => return Ok(()),
+        };
+        match (action, key.code) {
+            (Some(Action::Quit), _) => self.should_quit = true,
+            // you can not chat without logging in if it is required
+            (Some(Action::Cancel), _) | (_, KeyCode::Esc) if login.required => {
+                self.should_quit = true
+            }
+            (Some(Action::Cancel), _) | (_, KeyCode::Esc) => self.login = None,
+            (Some(Action::Send), _) => match login.submit() {
+                Ok(msg) => {
+                    if let Some(mut stream) = self.stream.as_ref() {
+                        msg.write_to(&mut stream)?;
+                    }
+                }
+                Err(reason) => login.error = Some(reason),
+            },
+            (Some(Action::DeleteBackward), _) => login.pop(),
+            (_, KeyCode::Tab | KeyCode::BackTab | KeyCode::Up | KeyCode::Down) => {
+                login.switch_field()
+            }
+            (_, KeyCode::Char('r')) if key.modifiers == KeyModifiers::CONTROL => {
+                login.register = !login.register
+            }
+            (None, KeyCode::Char(ch)) if (key.modifiers - KeyModifiers::SHIFT).is_empty() => {
+                login.push(ch)
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Do what is chosen in command palette.
+    fn run_palette_action(&mut self, action: PaletteAction) -> std::io::Result<()> {
+        match action {
+            PaletteAction::Run(action) => self.run_action(action)?,
+            PaletteAction::Command(name) => {
+                let needs_args = self
+                    .commands
+                    .find(name)
+                    .map(|cmd| cmd.usage.contains('<'))
+                    .unwrap_or(false);
+                self.focus = AppFocus::Editor;
+                if needs_args {
+                    // let user type arguments
+                    self.input_buffer = format!("/{} ", name);
+                    self.cursor_position = self.input_buffer.chars().count();
+                } else {
+                    self.input_buffer = format!("/{}", name);
+                    self.send_msg()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a key when search results are shown.
+    fn handle_search_key(&mut self, key: KeyEvent) {
+        let search = match self.search.as_mut() {
+            Some(search) => search,
+            None => return,
+        };
+        match (self.keymap.action(KeyScope::Browse, key), key.code) {
+            (Some(Action::MoveUp), _) => search.select(-1),
+            (Some(Action::MoveDown), _) => search.select(1),
+            (Some(Action::PageUp), _) => search.select(-10),
+            (Some(Action::PageDown), _) => search.select(10),
+            (Some(Action::MoveHome), _) => search.select(isize::MIN / 2),
+            (Some(Action::MoveEnd), _) => search.select(isize::MAX / 2),
+            (Some(Action::Send), _) => {
+                let found = search.results.get(search.selected).cloned();
+                self.search = None;
+                if let Some(found) = found {
+                    self.show_found_msg(found);
+                }
+            }
+            (Some(Action::Cancel), _) | (_, KeyCode::Esc) => self.search = None,
+            _ => {}
+        }
+    }
+
+    /// Select a msg found by a search and scroll to it.
+    ///
+    /// The msg is put into its room by its id if it was sent before you came.
+    fn show_found_msg(&mut self, found: Message) {
+        let index = if matches!(found.msg_type, MessageType::DirectMessage) {
+            let peer = if found.msg_sender == self.name {
+                &found.msg_target
+            } else {
+                &found.msg_sender
+            };
+            Some(self.room_index_or_insert(&peer.clone(), true))
+        } else {
+            self.room_index(&found.msg_room, false)
+        };
+        let index = match index {
+            Some(index) => index,
+            None => {
+                self.show_local(
+                    MessageType::Error,
+                    format!("You are not in #{} now.", found.msg_room),
+                );
+                return;
+            }
+        };
+        self.current_room = index;
+        let id = found.msg_id;
+        let msgs = &mut self.rooms[index].received_messages;
+        let msg_index = match msgs.iter().position(|received| received.msg.msg_id == id) {
+            Some(msg_index) => msg_index,
+            None => {
+                // ids grow with time, so the msg goes before the first newer one
+                let msg_index = msgs
+                    .iter()
+                    .position(|received| received.msg.msg_id > id)
+                    .unwrap_or(msgs.len());
+                msgs.insert(msg_index, ReceivedMsg::from(found));
+                self.match_cache = None;
+                self.rooms[index].trim(msg_index)
+            }
+        };
+        self.selected_msg = Some(id);
+        self.focus = AppFocus::MsgList;
+        self.scroll_to_msg(msg_index);
+    }
+
+    /// Handle a key when command palette is open, the filter is typed like in editor.
+    fn handle_palette_key(&mut self, key: KeyEvent) -> std::io::Result<()> {
+        let entries = palette_entries(&self.commands, &self.keymap);
+        let action = self.keymap.action(KeyScope::Editor, key);
+        let palette = match self.palette.as_mut() {
+            Some(palette) => palette,
+            None => return Ok(()),
+        };
+        let matched_num = palette.filter(&entries).len();
+        match (action, key.code) {
+            (Some(Action::Cancel | Action::OpenPalette), _) | (_, KeyCode::Esc) => {
+                self.palette = None
+            }
+            (Some(Action::MoveUp), _) => palette.select(-1, matched_num),
+            (Some(Action::MoveDown), _) => palette.select(1, matched_num),
+            (Some(Action::DeleteBackward), _) => {
+                palette.filter.pop();
+                palette.selected = 0;
+            }
+            (Some(Action::ClearInput), _) => {
+                palette.filter.clear();
+                palette.selected = 0;
+            }
+            (Some(Action::Send), _) => {
+                let action = palette
+                    .filter(&entries)
+                    .get(palette.selected)
+                    .map(|entry| entry.action);
+                self.palette = None;
+                if let Some(action) = action {
+                    self.run_palette_action(action)?;
+                }
+            }
+            (None, KeyCode::Char(ch)) => {
+                palette.filter.push(ch);
+                palette.selected = 0;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Handle input in `input_buffer`, a command or a msg to send to server
     fn send_msg(&mut self) -> std::io::Result<()> {
         // do not send empty or blank string
         if self.input_buffer.is_empty() || self.input_buffer.trim().is_empty() {
             return Ok(());
         }
-        let msg_content: String = self.input_buffer.drain(..).collect();
-        let msg = Message {
-            msg_type: MessageType::TextMessage,
-            msg_sender: self
-                .stream
-                .as_ref()
-                .unwrap()
-                .local_addr()
-                .expect("Failed to get local addr.")
-                .to_string(),
-            msg_content,
-        };
-        self.stream
-            .as_ref()
-            .unwrap()
-            .write(msg.to_string().as_bytes())?;
+        let input: String = self.input_buffer.drain(..).collect();
         self.cursor_position = 0;
+        let editing = self.editing.take();
+        let room = &self.rooms[self.current_room];
+        let action = if let (Some(id), false) = (editing, input.starts_with('/')) {
+            Ok(CommandAction::Send(Message::edit(id, &input)))
+        } else if room.is_direct && !input.starts_with('/') {
+            Ok(CommandAction::Send(Message::direct(&room.name, &input)))
+        } else {
+            let ctx = CommandContext {
+                room: if room.is_direct {
+                    None
+                } else {
+                    Some(&room.name)
+                },
+                peer: room.is_direct.then_some(room.name.as_str()),
+                last_msg_id: self.last_own_msg().map(|msg| msg.msg_id),
+                selected_msg_id: self.selected_msg_id(),
+            };
+            self.commands.parse(&input, &ctx)
+        };
+        match action {
+            Ok(CommandAction::Send(mut msg)) => {
+                msg.msg_sender = self
+                    .stream
+                    .as_ref()
+                    .unwrap()
+                    .local_addr()
+                    .expect("Failed to get local addr.")
+                    .to_string();
+                self.seal_msg(&mut msg);
+                msg.write_to(&mut self.stream.as_ref().unwrap())?;
+                debug!("Sent {} to #{}", msg.msg_type, msg.msg_room);
+                // jump to the newest msg after sending
+                self.rooms[self.current_room].scroll_back = 0;
+            }
+            Ok(CommandAction::Show(lines)) => {
+                for line in lines {
+                    self.show_local(MessageType::Notice, line);
+                }
+            }
+            Ok(CommandAction::ShowKeys(nick)) => {
+                for line in fingerprint_lines(self.keys.as_ref(), nick.as_deref()) {
+                    self.show_local(MessageType::Notice, line);
+                }
+            }
+            Ok(CommandAction::Login { nick, register }) => {
+                self.login = Some(LoginForm::new(&nick, register, false))
+            }
+            Ok(CommandAction::Clear) => {
+                let room = &mut self.rooms[self.current_room];
+                room.received_messages.clear();
+                room.scroll_back = 0;
+                self.match_cache = None;
+            }
+            Ok(CommandAction::Quit) => self.should_quit = true,
+            Err(reason) => self.show_local(MessageType::Error, reason),
+        }
 
         Ok(())
     }
 }
 
 pub fn ui_init() -> Result<(), Box<dyn Error>> {
+    // load config before taking over the terminal, so errors can be printed
+    let config = Config::load()?;
+    logger::init(
+        &config.logging,
+        false,
+        config.logging.ui_file_path().as_deref(),
+    )?;
+    let keymap = Keymap::from_config(&config.keys)?;
+    let theme = Theme::load(config.theme.as_deref().unwrap_or("dark"), &config.themes)?;
+    let notify = config.notify;
+    let connector = Connector::new(&config.tls)?;
+    // connect before taking over the terminal, the password of server may be asked for
+    let stream = connector.connect(SERVER_ADDR)?;
+    let mut reader = BufReader::new(stream.clone());
+    let first_msg = knock(&mut reader, &stream, &config.client)?;
+    info!("Connected to {}.", SERVER_ADDR);
+    let logger = ChatLogger::new(&config.log);
+    let keys = data_dir()
+        .map(|dir| KeyRing::load(&dir.join("keys")))
+        .transpose()
+        .map_err(|err| format!("Failed to load keys: {}", err))?;
+
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -239,8 +1524,16 @@ pub fn ui_init() -> Result<(), Box<dyn Error>> {
     let mut terminal = Terminal::new(backend)?;
 
     // create app and run it
-    let app = App::default();
-    let res = run_app(&mut terminal, app);
+    let app = App {
+        keymap,
+        theme,
+        notify,
+        logger,
+        keys,
+        stream: Some(stream),
+        ..Default::default()
+    };
+    let res = run_app(&mut terminal, app, reader, first_msg);
 
     // restore terminal
     disable_raw_mode()?;
@@ -252,37 +1545,40 @@ pub fn ui_init() -> Result<(), Box<dyn Error>> {
     terminal.show_cursor()?;
 
     if let Err(err) = res {
+        error!("TUI stopped: {}", err);
         println!("{:?}", err)
     }
 
     Ok(())
 }
 
-fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Result<()> {
-    // connect to server
-    let stream = TcpStream::connect("127.0.0.1:9999")?;
-    let mut stream_clone = stream.try_clone()?;
-    app.stream = Some(stream);
+fn run_app<B: Backend + Write>(
+    terminal: &mut Terminal<B>,
+    mut app: App,
+    mut reader: BufReader<ChatStream>,
+    first_msg: Option<Message>,
+) -> io::Result<()> {
+    if let Some(msg) = first_msg {
+        app.handle_received_msg(msg);
+    }
 
     let (msg_sender, msg_receiver) = mpsc::channel::<Message>();
 
     // create a thread to read msg from server
     thread::spawn(move || {
-        let mut buffer: Vec<u8> = vec![0; MSG_BUF_SIZE];
         loop {
-            if let Ok(msg_size) = stream_clone.read(&mut buffer) {
-                if msg_size > 0 {
-                    let msg = Message::convert_to_msg(from_utf8(&buffer[..msg_size]).unwrap());
-                    msg_sender
-                        .send(msg)
-                        .expect("Failed to send msg to msg_receiver.");
-                }
+            if let Ok(Some(msg)) = Message::read_from(&mut reader) {
+                msg_sender
+                    .send(msg)
+                    .expect("Failed to send msg to msg_receiver.");
             } else {
                 // should try to re-connect, or just quit
+                warn!("Lost connection to server.");
                 let msg = Message {
                     msg_type: MessageType::Error,
                     msg_sender: "localhost".to_string(),
                     msg_content: "Lost connection.".to_string(),
+                    ..Default::default()
                 };
                 msg_sender
                     .send(msg)
@@ -294,81 +1590,223 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Result<(
 
     // draw ui in this loop
     loop {
+        if app.should_quit {
+            return Ok(());
+        }
+        app.expire_typing();
         terminal.draw(|frame| ui(frame, &mut app))?;
 
         // handle received msg
-        if let Ok(msg) = msg_receiver.try_recv() {
-            match msg.msg_type {
-                MessageType::ClientListUpdate => {}
-                MessageType::TextMessage => {
-                    app.received_messages.push(msg);
-                }
-                MessageType::Error => {}
-                _ => {}
-            }
+        while let Ok(msg) = msg_receiver.try_recv() {
+            app.handle_received_msg(msg);
+        }
+        for notification in app.notifications.drain(..) {
+            notify(terminal.backend_mut(), &app.notify, &notification)?;
         }
 
         // check events 10 times every second
         if event::poll(Duration::from_millis(100))? {
             if let Event::Key(key) = event::read()? {
-                match app.focus {
-                    AppFocus::Editor => {
-                        match key.code {
-                            KeyCode::Enter => {
-                                app.send_msg().expect("Failed to send msg with app.");
-                            }
-                            KeyCode::Char(ch) => {
-                                // length of msg should be shorter
-                                // for there are msg type and sender in Message
-                                if app.input_buffer.as_bytes().len() < MSG_BUF_SIZE {
-                                    app.input_buffer.insert(app.len_of_str_before_cursor(), ch);
-                                    app.cursor_position += 1;
-                                }
-                            }
-                            KeyCode::Backspace => {
-                                app.remove_a_char_before_cursor();
-                            }
-                            KeyCode::Left => {
-                                app.cursor_position -= if app.cursor_position > 0 { 1 } else { 0 }
-                            }
-                            KeyCode::Right => {
-                                app.cursor_position +=
-                                    if app.cursor_position < app.input_buffer.chars().count() {
-                                        1
-                                    } else {
-                                        0
-                                    };
-                            }
-                            KeyCode::Up => {
-                                app.move_cursor_up();
-                            }
-                            KeyCode::Down => {
-                                app.move_cursor_down();
-                            }
-                            KeyCode::Esc => {
-                                // should set input_mode to STOPPED
-                                // app.input_mode = InputMode::Stopped;
-                                return Ok(());
-                            }
-                            _ => {}
-                        }
-                    }
-                    AppFocus::MsgList => match key.code {
-                        KeyCode::Up => {}
-                        KeyCode::Down => {}
-                        _ => {}
-                    },
-                    AppFocus::ClientList => match key.code {
-                        KeyCode::Up => {}
-                        KeyCode::Down => {}
-                        _ => {}
-                    },
-                }
+                app.handle_key(key)?;
             }
         }
     }
 }
 
+/// Describe users typing, `None` if nobody is typing.
+fn typing_status(names: &[&str]) -> Option<String> {
+    match names {
+        [] => None,
+        [name] => Some(format!("{} is typing…", name)),
+        [first, second] => Some(format!("{} and {} are typing…", first, second)),
+        _ => Some(format!("{} people are typing…", names.len())),
+    }
+}
+
+/// Describe the search in msg window and its matches.
+fn search_status(search: &LocalSearch, match_num: usize) -> String {
+    let cursor = if search.typing { "_" } else { "" };
+    if search.query.is_empty() {
+        format!("/{}", cursor)
+    } else if match_num == 0 {
+        format!("/{}{} (no matches)", search.query, cursor)
+    } else {
+        format!(
+            "/{}{} ({}/{})",
+            search.query,
+            cursor,
+            match_num - search.current.min(match_num - 1),
+            match_num
+        )
+    }
+}
+
+/// Ring the bell and send a desktop notification by OSC 9, which most terminals show.
+fn notify<W: Write>(writer: &mut W, config: &NotifyConfig, text: &str) -> io::Result<()> {
+    if config.bell {
+        writer.write_all(b"\x07")?;
+    }
+    if config.desktop {
+        // control chars would end the escape sequence early
+        let text: String = text.chars().filter(|ch| !ch.is_control()).collect();
+        writer.write_all(format!("\x1b]9;{}\x07", text).as_bytes())?;
+    }
+    writer.flush()
+}
+
+/// Check if a msg from others mentions `name`.
+fn mentions_user(msg: &Message, name: &str) -> bool {
+    matches!(
+        msg.msg_type,
+        MessageType::TextMessage | MessageType::ActionMessage | MessageType::DirectMessage
+    ) && msg.msg_sender != name
+        && markup::is_mentioned(&msg.msg_content, name)
+}
+
+/// Turn a msg into styled lines: time, colored nickname and formatted content,
+/// then reactions beneath it if there are any.
+///
+/// A reply starts with an excerpt of the msg it quotes, found in `msgs` of the same room
+/// and cut to `width`. Msgs mentioning `name` are highlighted.
+fn msg_lines(
+    received: &ReceivedMsg,
+    msgs: &[ReceivedMsg],
+    width: usize,
+    theme: &Theme,
+    name: &str,
+    search: Option<(&str, Option<usize>)>,
+) -> Vec<Spans<'static>> {
+    let msg = &received.msg;
+    let mut spans = vec![];
+    if let Some(time) = Local.timestamp_opt(msg.msg_time, 0).single() {
+        spans.push(Span::styled(
+            time.format("[%H:%M] ").to_string(),
+            Style::default().fg(theme.timestamp),
+        ));
+    }
+    match msg.msg_type {
+        MessageType::TextMessage => {
+            spans.push(Span::styled(
+                msg.msg_sender.clone(),
+                theme.nick_style(&msg.msg_sender),
+            ));
+            spans.push(Span::styled(": ", theme.text_style()));
+            spans.extend(content_spans(
+                &msg.msg_content,
+                theme.text_style(),
+                theme,
+                search,
+            ));
+        }
+        MessageType::ActionMessage => {
+            spans.push(Span::styled("* ", theme.text_style()));
+            spans.push(Span::styled(
+                msg.msg_sender.clone(),
+                theme.nick_style(&msg.msg_sender),
+            ));
+            spans.push(Span::styled(" ", theme.text_style()));
+            spans.extend(content_spans(
+                &msg.msg_content,
+                theme.text_style(),
+                theme,
+                search,
+            ));
+        }
+        // direct msgs look different from msgs in rooms
+        MessageType::DirectMessage => {
+            let style = Style::default().fg(theme.direct);
+            spans.push(Span::styled(
+                format!("[DM] {} -> {}: ", msg.msg_sender, msg.msg_target),
+                style,
+            ));
+            spans.extend(content_spans(&msg.msg_content, style, theme, search));
+        }
+        MessageType::Error => spans.push(Span::styled(
+            msg.to_brief_string(),
+            Style::default().fg(theme.error),
+        )),
+        _ => spans.push(Span::styled(
+            msg.to_brief_string(),
+            Style::default().fg(theme.system),
+        )),
+    }
+    if received.edited {
+        spans.push(Span::styled(" (edited)", Style::default().fg(theme.system)));
+    }
+    if mentions_user(msg, name) {
+        // matches of the search keep their background
+        for span in spans.iter_mut().filter(|span| span.style.bg.is_none()) {
+            span.style = span.style.bg(theme.mention);
+        }
+    }
+    let mut lines = vec![];
+    if msg.msg_reply_to != 0 {
+        let quote = match msgs
+            .iter()
+            .find(|parent| parent.msg.msg_id == msg.msg_reply_to)
+        {
+            Some(parent) => format!(
+                "↪ {}: {}",
+                parent.msg.msg_sender,
+                markup::to_plain(&parent.msg.msg_content)
+            ),
+            None => format!("↪ msg {} is not here", msg.msg_reply_to),
+        };
+        lines.push(Spans::from(Span::styled(
+            truncate_line(&quote, width.min(u16::MAX as usize) as u16),
+            Style::default()
+                .fg(theme.system)
+                .add_modifier(Modifier::ITALIC),
+        )));
+    }
+    lines.push(Spans::from(spans));
+    if !received.reactions.is_empty() {
+        let reactions: Vec<String> = received
+            .reactions
+            .iter()
+            .map(|(token, count)| format!("{} {}", token, count))
+            .collect();
+        lines.push(Spans::from(Span::styled(
+            format!("  {}", reactions.join("  ")),
+            Style::default().fg(theme.system),
+        )));
+    }
+    lines
+}
+
+/// Get spans of msg content, with matches of the local search highlighted.
+///
+/// `search` is the query and the index of the current match in this content.
+fn content_spans(
+    content: &str,
+    style: Style,
+    theme: &Theme,
+    search: Option<(&str, Option<usize>)>,
+) -> Vec<Span<'static>> {
+    let mut lines = [Spans::from(markup::to_spans(content, style, theme))];
+    if let Some((query, current)) = search {
+        highlight_matches(
+            &mut lines,
+            query,
+            current,
+            Style::default().bg(theme.search_match),
+            Style::default().bg(theme.search_current),
+        );
+    }
+    let [line] = lines;
+    line.0
+}
+
+/// Get msg content as displayed by `content_spans`, which is all the local search looks in.
+fn displayed_content(msg: &Message) -> String {
+    match msg.msg_type {
+        MessageType::TextMessage | MessageType::ActionMessage | MessageType::DirectMessage => {
+            markup::to_plain(&msg.msg_content)
+        }
+        _ => String::new(),
+    }
+}
+
 fn ui<B: Backend>(frame: &mut Frame<B>, app: &mut App) {
     let size = frame.size();
 
@@ -384,23 +1822,81 @@ fn ui<B: Backend>(frame: &mut Frame<B>, app: &mut App) {
         .constraints([Constraint::Percentage(80), Constraint::Percentage(20)])
         .split(chunks[0]);
 
-    // display all msgs received
-    // should do some scroll operation to ensure the newest msg appear at bottom of msg_block
+    // right part of window includes joined rooms and online clients
+    let right_chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(30), Constraint::Percentage(70)])
+        .split(chunks[1]);
+
+    // update size of msg block and editor block
+    app.editor_width = left_chunks[1].width.saturating_sub(2) as usize;
+    app.msg_window_height = left_chunks[0].height.saturating_sub(2) as usize;
+
+    // matches of the search are found before msgs are borrowed for display
+    let current_match = app.current_match();
+    let match_num = app.search_matches().len();
+
+    // display all msgs received in current room
+    let room = &app.rooms[app.current_room];
+    let mut msg_title = if room.scroll_back > 0 {
+        format!(
+            "Chamber Message Window - {} (scrolled back {} lines)",
+            room.display_name(),
+            room.scroll_back
+        )
+    } else {
+        format!("Chamber Message Window - {}", room.display_name())
+    };
+    let mentions = app.mentions();
+    if mentions > 0 {
+        msg_title.push_str(&format!(" [{} @mentions]", mentions));
+    }
+    if room.is_direct {
+        let encrypted = app
+            .keys
+            .as_ref()
+            .and_then(|keys| keys.peer_fingerprint(&room.name))
+            .is_some();
+        msg_title.push_str(if encrypted {
+            " [encrypted, /keys to verify]"
+        } else {
+            " [not encrypted]"
+        });
+    }
     let msg_block = Block::default()
         .borders(Borders::ALL)
-        .title("Chamber Message Window")
-        .title_alignment(Alignment::Left);
-    let msgs_spans: Vec<Spans> = app
+        .title(Span::styled(msg_title, app.theme.title_style()))
+        .title_alignment(Alignment::Left)
+        .style(
+            app.theme
+                .block_style(matches!(app.focus, AppFocus::MsgList)),
+        );
+    let selected_msg = match app.focus {
+        AppFocus::MsgList => app.selected_msg,
+        _ => None,
+    };
+    let msgs_spans: Vec<Spans> = room
         .received_messages
         .iter()
-        .map(|i| Spans::from(i.to_brief_string()))
+        .enumerate()
+        .flat_map(|(index, msg)| {
+            let current = current_match
+                .filter(|(match_index, _)| *match_index == index)
+                .map(|(_, nth)| nth);
+            let mut lines = app.highlighted_msg_lines(index, current);
+            if selected_msg.is_some() && selected_msg == Some(msg.msg.msg_id) {
+                for span in lines.iter_mut().flat_map(|line| line.0.iter_mut()) {
+                    span.style = span.style.add_modifier(Modifier::REVERSED);
+                }
+            }
+            lines
+        })
         .collect();
-    // scroll to display the newest msg
-    let offset_y = if app.lines_occupied_by_msg_received() as u16 <= left_chunks[0].height - 2 {
-        0
-    } else {
-        app.lines_occupied_by_msg_received() as u16 - (left_chunks[0].height - 2)
-    };
+    // scroll to display the newest msg, or the position scrolled back to
+    let offset_y = app
+        .lines_occupied_by_msg_received()
+        .saturating_sub(app.msg_window_height)
+        .saturating_sub(room.scroll_back) as u16;
     let msg_para = Paragraph::new(msgs_spans)
         .wrap(Wrap {
             trim: false,
@@ -409,42 +1905,399 @@ fn ui<B: Backend>(frame: &mut Frame<B>, app: &mut App) {
         .block(msg_block)
         .scroll((offset_y, 0));
     frame.render_widget(msg_para, left_chunks[0]);
+    // show the search or who is typing on the bottom border of msg block
+    let typing: Vec<&str> = room.typing.iter().map(|(name, _)| name.as_str()).collect();
+    let status = match &app.local_search {
+        Some(search) => Some(search_status(search, match_num)),
+        None => typing_status(&typing),
+    };
+    if let Some(status) = status {
+        let area = left_chunks[0];
+        if area.height >= 2 && area.width >= 2 {
+            let status_area = Rect::new(area.x + 1, area.bottom() - 1, area.width - 2, 1);
+            let status = Span::styled(
+                status,
+                Style::default()
+                    .fg(app.theme.system)
+                    .add_modifier(Modifier::ITALIC),
+            );
+            frame.render_widget(Paragraph::new(status), status_area);
+        }
+    }
 
-    // should display online clients
-    let online_clents = Block::default()
-        .borders(Borders::ALL)
-        .title("Online clients")
-        .title_alignment(Alignment::Left);
-    frame.render_widget(online_clents, chunks[1]);
+    // display joined rooms, current room is marked and others show unread msgs
+    let room_items: Vec<ListItem> = app
+        .rooms
+        .iter()
+        .enumerate()
+        .map(|(index, room)| {
+            let mentions = if room.mentions > 0 {
+                format!(" @{}", room.mentions)
+            } else {
+                String::new()
+            };
+            let item = if index == app.current_room {
+                ListItem::new(format!("> {}{}", room.display_name(), mentions))
+                    .style(Style::default().add_modifier(Modifier::BOLD))
+            } else if room.unread > 0 {
+                ListItem::new(format!(
+                    "  {} ({}){}",
+                    room.display_name(),
+                    room.unread,
+                    mentions
+                ))
+            } else {
+                ListItem::new(format!("  {}", room.display_name()))
+            };
+            if room.is_direct {
+                item.style(Style::default().fg(app.theme.direct))
+            } else {
+                item
+            }
+        })
+        .collect();
+    let room_list = List::new(room_items).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(Span::styled("Rooms", app.theme.title_style()))
+            .title_alignment(Alignment::Left)
+            .style(
+                app.theme
+                    .block_style(matches!(app.focus, AppFocus::RoomList)),
+            ),
+    );
+    frame.render_widget(room_list, right_chunks[0]);
+
+    // display online clients, press <Enter> on one to open direct msgs
+    let client_items: Vec<ListItem> = app
+        .online_clients
+        .iter()
+        .map(|name| {
+            let nick = Span::styled(name.as_str(), app.theme.nick_style(name));
+            if *name == app.name {
+                ListItem::new(Spans::from(vec![nick, Span::raw(" (you)")]))
+            } else {
+                ListItem::new(Spans::from(nick))
+            }
+        })
+        .collect();
+    let online_clents = List::new(client_items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(Span::styled(
+                    format!("Online clients ({})", app.online_clients.len()),
+                    app.theme.title_style(),
+                ))
+                .title_alignment(Alignment::Left)
+                .style(
+                    app.theme
+                        .block_style(matches!(app.focus, AppFocus::ClientList)),
+                ),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(online_clents, right_chunks[1], &mut app.selected_client);
 
     // editor is a block to input msgs
+    let editing = if app.editing.is_some() {
+        format!(
+            "Editing msg, <{}> to cancel. ",
+            app.keymap.keys_for(Action::Cancel)
+        )
+    } else {
+        String::new()
+    };
     let editor_title = format!(
-        "Press <Enter> to send, cursor position: {}, char num: {}, bytes: {}",
+        "{}Press <{}> to send, <{}> for help, cursor position: {}, char num: {}, bytes: {}",
+        editing,
+        app.keymap.keys_for(Action::Send),
+        app.keymap.keys_for(Action::ShowHelp),
         app.cursor_position,
         app.input_buffer.chars().count(),
         app.input_buffer.len()
     );
     let editor_block = Block::default()
         .borders(Borders::ALL)
-        .title(editor_title)
+        .title(Span::styled(editor_title, app.theme.title_style()))
         .title_alignment(Alignment::Left)
-        .style(match app.focus {
-            AppFocus::Editor => Style::default().fg(Color::Green),
-            _ => Style::default(),
-        });
-    let msg_in_editor = Paragraph::new(app.input_buffer.as_ref())
-        .wrap(Wrap {
-            trim: false,
-            break_words: true,
-        })
-        .block(editor_block);
-    // update width of editor block
-    app.editor_width = left_chunks[1].width as usize - 2;
+        .style(app.theme.block_style(matches!(app.focus, AppFocus::Editor)));
+    let msg_in_editor = Paragraph::new(Span::styled(
+        app.input_buffer.as_str(),
+        app.theme.text_style(),
+    ))
+    .wrap(Wrap {
+        trim: false,
+        break_words: true,
+    })
+    .block(editor_block);
     // get actually occupied width by msg in editor
-    let msg_split_width: usize = app.width_occupied_by_str_before_cursor();
+    if app.editor_width > 0 {
+        let msg_split_width: usize = app.width_occupied_by_str_before_cursor();
+        frame.set_cursor(
+            left_chunks[1].x + (msg_split_width % app.editor_width) as u16 + 1,
+            left_chunks[1].y + (msg_split_width / app.editor_width) as u16 + 1,
+        );
+    }
+    frame.render_widget(msg_in_editor, left_chunks[1]);
+    if let Some(palette) = &app.palette {
+        render_palette(
+            frame,
+            &app.theme,
+            palette,
+            &palette_entries(&app.commands, &app.keymap),
+        );
+    }
+    if let Some(help_scroll) = app.help_scroll {
+        render_help(frame, &app.theme, &app.keymap, help_scroll);
+    }
+    if let Some(search) = &app.search {
+        render_search(frame, &app.theme, search);
+    }
+    if let Some(login) = &app.login {
+        render_login(frame, &app.theme, login);
+    }
+}
+
+/// Get a rect in the center of `area`, the size is given in percentage.
+fn centered_rect(percent_x: u16, percent_y: u16, area: Rect) -> Rect {
+    let vertical_chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((100 - percent_y) / 2),
+            Constraint::Percentage(percent_y),
+            Constraint::Percentage((100 - percent_y) / 2),
+        ])
+        .split(area);
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage((100 - percent_x) / 2),
+            Constraint::Percentage(percent_x),
+            Constraint::Percentage((100 - percent_x) / 2),
+        ])
+        .split(vertical_chunks[1])[1]
+}
+
+/// Draw command palette over other blocks.
+fn render_palette<B: Backend>(
+    frame: &mut Frame<B>,
+    theme: &Theme,
+    palette: &Palette,
+    entries: &[PaletteEntry],
+) {
+    let area = centered_rect(60, 60, frame.size());
+    let matched = palette.filter(entries);
+    let keys_width = matched
+        .iter()
+        .map(|entry| entry.keys.width())
+        .max()
+        .unwrap_or_default();
+    let items: Vec<ListItem> = matched
+        .iter()
+        .map(|entry| {
+            ListItem::new(Spans::from(vec![
+                Span::styled(
+                    format!("{:width$}  ", entry.keys, width = keys_width),
+                    Style::default().fg(theme.focused_border),
+                ),
+                Span::styled(entry.label.as_str(), theme.text_style()),
+            ]))
+        })
+        .collect();
+    let mut state = ListState::default();
+    if !matched.is_empty() {
+        state.select(Some(palette.selected));
+    }
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(Span::styled(
+                    format!(
+                        "Command palette: {}_ (<Enter> to run, <Esc> to close)",
+                        palette.filter
+                    ),
+                    theme.title_style(),
+                ))
+                .title_alignment(Alignment::Left)
+                .style(theme.block_style(true)),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    frame.render_widget(Clear, area);
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+/// Draw search results over other blocks, each result takes a line.
+fn render_search<B: Backend>(frame: &mut Frame<B>, theme: &Theme, search: &SearchResults) {
+    let area = centered_rect(80, 60, frame.size());
+    let width = area.width.saturating_sub(2);
+    let items: Vec<ListItem> = search
+        .results
+        .iter()
+        .map(|found| {
+            let time = Local
+                .timestamp_opt(found.msg_time, 0)
+                .single()
+                .map(|time| time.format("%m-%d %H:%M ").to_string())
+                .unwrap_or_default();
+            let author = if found.msg_target.is_empty() {
+                format!("#{} {}", found.msg_room, found.msg_sender)
+            } else {
+                format!("[DM] {} -> {}", found.msg_sender, found.msg_target)
+            };
+            let line = format!(
+                "{}{}: {}",
+                time,
+                author,
+                markup::to_plain(&found.msg_content)
+            );
+            ListItem::new(Span::styled(
+                truncate_line(&line, width),
+                theme.text_style(),
+            ))
+        })
+        .collect();
+    let title = if search.results.is_empty() {
+        format!("No msgs found for \"{}\" (<Esc> to close)", search.query)
+    } else {
+        format!(
+            "{} msgs found for \"{}\" (<Enter> to show, <Esc> to close)",
+            search.results.len(),
+            search.query
+        )
+    };
+    let mut state = ListState::default();
+    if !search.results.is_empty() {
+        state.select(Some(search.selected));
+    }
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(Span::styled(title, theme.title_style()))
+                .title_alignment(Alignment::Left)
+                .style(theme.block_style(true)),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    frame.render_widget(Clear, area);
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+/// Draw the login screen, it hides the chat if logging in is required.
+fn render_login<B: Backend>(frame: &mut Frame<B>, theme: &Theme, login: &LoginForm) {
+    if login.required {
+        let background = Block::default()
+            .borders(Borders::ALL)
+            .title(Span::styled("Chamber", theme.title_style()))
+            .style(theme.block_style(false));
+        frame.render_widget(Clear, frame.size());
+        frame.render_widget(background, frame.size());
+    }
+    let area = centered_rect(50, 40, frame.size());
+    let label_style = Style::default().fg(theme.focused_border);
+    let mut lines = vec![
+        Spans::from(Span::styled(
+            if login.register {
+                "Register a new account, or <Ctrl-r> to log in"
+            } else {
+                "Log in, or <Ctrl-r> to register a new account"
+            },
+            theme.text_style(),
+        )),
+        Spans::default(),
+        Spans::from(vec![
+            Span::styled("Nickname: ", label_style),
+            Span::styled(login.nick.clone(), theme.text_style()),
+        ]),
+        Spans::from(vec![
+            Span::styled("Password: ", label_style),
+            Span::styled(login.masked_password(), theme.text_style()),
+        ]),
+        Spans::default(),
+    ];
+    if let Some(error) = &login.error {
+        lines.push(Spans::from(Span::styled(
+            error.clone(),
+            Style::default().fg(theme.error),
+        )));
+    }
+    let title = if login.required {
+        "Log in to chat (<Tab> to switch, <Enter> to submit, <Esc> to quit)"
+    } else {
+        "Log in (<Tab> to switch, <Enter> to submit, <Esc> to skip)"
+    };
+    let form = Paragraph::new(lines).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(Span::styled(title, theme.title_style()))
+            .title_alignment(Alignment::Left)
+            .style(theme.block_style(true)),
+    );
+    frame.render_widget(Clear, area);
+    frame.render_widget(form, area);
+    // put the cursor at end of the field typed in
+    let (row, typed) = match login.field {
+        LoginField::Nick => (2, login.nick.width()),
+        LoginField::Password => (3, login.password.chars().count()),
+    };
     frame.set_cursor(
-        left_chunks[1].x + (msg_split_width % app.editor_width) as u16 + 1,
-        left_chunks[1].y + (msg_split_width / app.editor_width) as u16 + 1,
+        (area.x + 1 + "Nickname: ".len() as u16 + typed as u16).min(area.right().saturating_sub(2)),
+        area.y + 1 + row,
     );
-    frame.render_widget(msg_in_editor, left_chunks[1]);
+}
+
+/// Draw keybindings of `keymap` over other blocks.
+fn render_help<B: Backend>(frame: &mut Frame<B>, theme: &Theme, keymap: &Keymap, scroll: u16) {
+    let area = centered_rect(70, 80, frame.size());
+    let keys_width = keymap
+        .bindings()
+        .iter()
+        .map(|binding| binding.key.to_string().width())
+        .max()
+        .unwrap_or_default();
+    let mut lines: Vec<Spans> = vec![];
+    for scope in [KeyScope::Global, KeyScope::Editor, KeyScope::Browse] {
+        let bindings: Vec<_> = keymap
+            .bindings()
+            .iter()
+            .filter(|binding| binding.scope == scope)
+            .collect();
+        if bindings.is_empty() {
+            continue;
+        }
+        lines.push(Spans::from(Span::styled(
+            scope.to_string(),
+            theme.title_style(),
+        )));
+        for binding in bindings {
+            lines.push(Spans::from(vec![
+                Span::styled(
+                    format!("  {:width$}  ", binding.key.to_string(), width = keys_width),
+                    Style::default().fg(theme.focused_border),
+                ),
+                Span::styled(
+                    format!(
+                        "{} ({})",
+                        binding.action.description(),
+                        binding.action.name()
+                    ),
+                    theme.text_style(),
+                ),
+            ]));
+        }
+    }
+    let help = Paragraph::new(lines)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(Span::styled(
+                    "Keybindings (any other key to close)",
+                    theme.title_style(),
+                ))
+                .title_alignment(Alignment::Left)
+                .style(theme.block_style(true)),
+        )
+        .scroll((scroll, 0));
+    frame.render_widget(Clear, area);
+    frame.render_widget(help, area);
 }
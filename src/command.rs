@@ -0,0 +1,875 @@
+use std::net::IpAddr;
+
+use crate::{
+    consts::DEFAULT_ROOM,
+    message::{is_valid_nickname, is_valid_reaction, is_valid_room_name, Message, MessageType},
+    moderation::parse_duration,
+    search::SearchQuery,
+};
+
+/// What a client should do after a command is parsed.
+pub enum CommandAction {
+    /// Send the msg to server
+    Send(Message),
+    /// Show some lines locally, nothing is sent
+    Show(Vec<String>),
+    /// Clear msgs of current room
+    Clear,
+    /// Show fingerprints of your key and the key of a user, if one is given
+    ShowKeys(Option<String>),
+    /// Ask for the password, then log in as the user or register them
+    Login { nick: String, register: bool },
+    /// Exit the client
+    Quit,
+}
+
+/// Where a command is typed.
+pub struct CommandContext<'a> {
+    /// Name of current room, `None` if direct msgs are displayed
+    pub room: Option<&'a str>,
+    /// Nickname of the user whose direct msgs are displayed, `None` in rooms
+    pub peer: Option<&'a str>,
+    /// Id of your last msg here, for `/edit` and `/delete`
+    pub last_msg_id: Option<u64>,
+    /// Id of the selected msg, or the last msg here, for `/react` and `/reply`
+    pub selected_msg_id: Option<u64>,
+}
+impl<'a> CommandContext<'a> {
+    /// Get current room, commands typed in direct msgs work on the default room.
+    fn room_or_default(&self) -> &'a str {
+        self.room.unwrap_or(DEFAULT_ROOM)
+    }
+}
+
+/// Handle arguments of a command, return `Err` with a reason if the arguments are wrong.
+type CommandHandler = fn(&CommandContext, &str, &CommandRegistry) -> Result<CommandAction, String>;
+
+/// A command starts with `/`, like `/join rust`.
+pub struct Command {
+    /// Name without `/`
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+    handler: CommandHandler,
+}
+
+/// Result of tab completion.
+#[derive(Debug, PartialEq)]
+pub struct Completion {
+    /// Input after completion
+    pub input: String,
+    /// Cursor position (number of chars before it) after completion
+    pub cursor_position: usize,
+    /// All candidates matched, shown when the completion is ambiguous
+    pub candidates: Vec<String>,
+}
+
+/// All commands a client knows.
+///
+/// Adding a command is just registering a handler:
+/// ```rust
+/// registry.register(Command {
+///     name: "ping",
+///     usage: "/ping",
+///     description: "Say pong locally",
+///     handler: |_, _, _| Ok(CommandAction::Show(vec!["pong".to_string()])),
+/// });
+/// ```
+pub struct CommandRegistry {
+    commands: Vec<Command>,
+}
+impl Default for CommandRegistry {
+    fn default() -> CommandRegistry {
+        let mut registry = CommandRegistry { commands: vec![] };
+        registry.register(Command {
+            name: "help",
+            usage: "/help [command]",
+            description: "Show all commands or usage of a command",
+            handler: help,
+        });
+        registry.register(Command {
+            name: "nick",
+            usage: "/nick <name>",
+            description: "Change your nickname",
+            handler: nick,
+        });
+        registry.register(Command {
+            name: "me",
+            usage: "/me <action>",
+            description: "Describe what you are doing",
+            handler: me,
+        });
+        registry.register(Command {
+            name: "msg",
+            usage: "/msg <nick> <text>",
+            description: "Send a direct msg to a user",
+            handler: msg,
+        });
+        registry.register(Command {
+            name: "edit",
+            usage: "/edit <text>",
+            description: "Change your last msg here",
+            handler: edit,
+        });
+        registry.register(Command {
+            name: "delete",
+            usage: "/delete",
+            description: "Delete your last msg here",
+            handler: delete,
+        });
+        registry.register(Command {
+            name: "react",
+            usage: "/react [msg id] <emoji>",
+            description: "React to the selected or given msg, again to take it back",
+            handler: react,
+        });
+        registry.register(Command {
+            name: "reply",
+            usage: "/reply [#msg id] <text>",
+            description: "Reply to the selected or given msg, quoting it",
+            handler: reply,
+        });
+        registry.register(Command {
+            name: "search",
+            usage: "/search <text> [from:nick] [before:date] [after:date]",
+            description: "Search msgs kept by server, dates look like 2022-08-01",
+            handler: search,
+        });
+        registry.register(Command {
+            name: "keys",
+            usage: "/keys [nick]",
+            description:
+                "Show fingerprints of keys for encrypted direct msgs, compare them in person",
+            handler: keys,
+        });
+        registry.register(Command {
+            name: "login",
+            usage: "/login [nick]",
+            description: "Log in to your account, the password is asked for",
+            handler: login,
+        });
+        registry.register(Command {
+            name: "register",
+            usage: "/register [nick]",
+            description: "Register an account to keep your nickname, the password is asked for",
+            handler: register,
+        });
+        registry.register(Command {
+            name: "join",
+            usage: "/join <room>",
+            description: "Join a room, it is created if it does not exist",
+            handler: join,
+        });
+        registry.register(Command {
+            name: "part",
+            usage: "/part [room]",
+            description: "Leave current room or the given room",
+            handler: part,
+        });
+        registry.register(Command {
+            name: "rooms",
+            usage: "/rooms",
+            description: "List all rooms on the server",
+            handler: rooms,
+        });
+        registry.register(Command {
+            name: "stats",
+            usage: "/stats",
+            description: "Show stats of the server",
+            handler: stats,
+        });
+        registry.register(Command {
+            name: "kick",
+            usage: "/kick <nick> [reason]",
+            description: "Disconnect a user, for operators",
+            handler: kick,
+        });
+        registry.register(Command {
+            name: "ban",
+            usage: "/ban [-ip] <nick or IP> [reason]",
+            description: "Ban a nickname or an IP, -ip bans the IP of a user, for operators",
+            handler: ban,
+        });
+        registry.register(Command {
+            name: "unban",
+            usage: "/unban <nick or IP>",
+            description: "Take back a ban, for operators",
+            handler: unban,
+        });
+        registry.register(Command {
+            name: "mute",
+            usage: "/mute <nick> <duration> [reason]",
+            description: "Stop a user sending msgs for a while like 30s, 10m or 2h, for operators",
+            handler: mute,
+        });
+        registry.register(Command {
+            name: "unmute",
+            usage: "/unmute <nick>",
+            description: "Let a muted user send msgs again, for operators",
+            handler: unmute,
+        });
+        registry.register(Command {
+            name: "op",
+            usage: "/op <nick>",
+            description: "Make a user an operator, for operators",
+            handler: op,
+        });
+        registry.register(Command {
+            name: "deop",
+            usage: "/deop <nick>",
+            description: "Take operator status from a user, for operators",
+            handler: deop,
+        });
+        registry.register(Command {
+            name: "clear",
+            usage: "/clear",
+            description: "Clear msgs of current room",
+            handler: |_, _, _| Ok(CommandAction::Clear),
+        });
+        registry.register(Command {
+            name: "quit",
+            usage: "/quit",
+            description: "Exit Chamber",
+            handler: |_, _, _| Ok(CommandAction::Quit),
+        });
+        registry
+    }
+}
+impl CommandRegistry {
+    /// Add a command, a command with the same name is replaced.
+    pub fn register(&mut self, command: Command) {
+        self.commands.retain(|cmd| cmd.name != command.name);
+        self.commands.push(command);
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|cmd| cmd.name == name)
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    /// Parse user input.
+    ///
+    /// Input not starting with `/` is a `TextMessage` to current room,
+    /// and `//` can be used to send text starting with `/`.
+    /// `Err` contains the msg that should be shown to user.
+    pub fn parse(&self, input: &str, ctx: &CommandContext) -> Result<CommandAction, String> {
+        let command_line = match input.strip_prefix('/') {
+            Some(command_line) if !command_line.starts_with('/') => command_line,
+            _ => {
+                let content = input.strip_prefix('/').unwrap_or(input);
+                return Ok(CommandAction::Send(Message {
+                    msg_room: ctx.room_or_default().to_string(),
+                    msg_content: content.to_string(),
+                    ..Default::default()
+                }));
+            }
+        };
+        let (name, args) = command_line
+            .split_once(char::is_whitespace)
+            .unwrap_or((command_line, ""));
+        let command = self
+            .find(name)
+            .ok_or_else(|| format!("Unknown command /{}, type /help to list commands.", name))?;
+        (command.handler)(ctx, args.trim(), self).map_err(|reason| {
+            if reason.is_empty() {
+                format!("Usage: {}", command.usage)
+            } else {
+                format!("{} Usage: {}", reason, command.usage)
+            }
+        })
+    }
+
+    /// Complete the word before cursor.
+    ///
+    /// The first word starting with `/` is completed with command names,
+    /// other words are completed with `nicknames`, and `@` of a mention is kept.
+    pub fn complete(
+        &self,
+        input: &str,
+        cursor_position: usize,
+        nicknames: &[String],
+    ) -> Option<Completion> {
+        let chars: Vec<char> = input.chars().collect();
+        let cursor_position = cursor_position.min(chars.len());
+        let word_start = chars[..cursor_position]
+            .iter()
+            .rposition(|ch| ch.is_whitespace())
+            .map(|pos| pos + 1)
+            .unwrap_or(0);
+        let word: String = chars[word_start..cursor_position].iter().collect();
+
+        let (prefix, candidates): (&str, Vec<String>) = match word.strip_prefix('/') {
+            Some(name) if word_start == 0 => (
+                "/",
+                self.commands
+                    .iter()
+                    .filter(|cmd| cmd.name.starts_with(name))
+                    .map(|cmd| cmd.name.to_string())
+                    .collect(),
+            ),
+            _ if word.is_empty() => return None,
+            _ if word.starts_with('@') => (
+                "@",
+                nicknames
+                    .iter()
+                    .filter(|nick| nick.starts_with(&word[1..]))
+                    .cloned()
+                    .collect(),
+            ),
+            _ => (
+                "",
+                nicknames
+                    .iter()
+                    .filter(|nick| nick.starts_with(&word))
+                    .cloned()
+                    .collect(),
+            ),
+        };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let mut completed = format!("{}{}", prefix, common_prefix(&candidates));
+        if candidates.len() == 1 {
+            completed.push(' ');
+        }
+        let before: String = chars[..word_start].iter().collect();
+        let after: String = chars[cursor_position..].iter().collect();
+        Some(Completion {
+            input: format!("{}{}{}", before, completed, after),
+            cursor_position: word_start + completed.chars().count(),
+            candidates,
+        })
+    }
+}
+
+/// Get the longest common prefix of `words`.
+fn common_prefix(words: &[String]) -> String {
+    let mut prefix: Vec<char> = words[0].chars().collect();
+    for word in &words[1..] {
+        let same = prefix
+            .iter()
+            .zip(word.chars())
+            .take_while(|(a, b)| **a == *b)
+            .count();
+        prefix.truncate(same);
+    }
+    prefix.iter().collect()
+}
+
+fn help(
+    _: &CommandContext,
+    args: &str,
+    registry: &CommandRegistry,
+) -> Result<CommandAction, String> {
+    let name = args.trim_start_matches('/');
+    if !name.is_empty() {
+        let command = registry
+            .find(name)
+            .ok_or_else(|| format!("Unknown command /{}.", name))?;
+        return Ok(CommandAction::Show(vec![format!(
+            "{} - {}",
+            command.usage, command.description
+        )]));
+    }
+    let mut lines = vec!["Commands (start with // to send text beginning with /):".to_string()];
+    lines.extend(
+        registry
+            .commands()
+            .iter()
+            .map(|cmd| format!("  {} - {}", cmd.usage, cmd.description)),
+    );
+    Ok(CommandAction::Show(lines))
+}
+
+fn nick(_: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    if !is_valid_nickname(args) {
+        return Err("Nicknames contain only letters, digits, - and _.".to_string());
+    }
+    Ok(CommandAction::Send(Message {
+        msg_type: MessageType::NickChange,
+        msg_content: args.to_string(),
+        ..Default::default()
+    }))
+}
+
+fn me(ctx: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    let room = ctx.room.ok_or("/me only works in rooms.")?;
+    if args.is_empty() {
+        return Err(String::default());
+    }
+    Ok(CommandAction::Send(Message {
+        msg_type: MessageType::ActionMessage,
+        msg_room: room.to_string(),
+        msg_content: args.to_string(),
+        ..Default::default()
+    }))
+}
+
+fn msg(_: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    match args.split_once(char::is_whitespace) {
+        Some((target, content)) if !content.trim().is_empty() => Ok(CommandAction::Send(
+            Message::direct(target, content.trim_start()),
+        )),
+        _ => Err(String::default()),
+    }
+}
+
+fn edit(ctx: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    let id = ctx.last_msg_id.ok_or("You have no msg here to edit.")?;
+    if args.trim().is_empty() {
+        return Err(String::default());
+    }
+    Ok(CommandAction::Send(Message::edit(id, args)))
+}
+
+fn delete(ctx: &CommandContext, _: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    let id = ctx.last_msg_id.ok_or("You have no msg here to delete.")?;
+    Ok(CommandAction::Send(Message::delete(id)))
+}
+
+fn react(ctx: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    let (id, token) = match args.split_once(char::is_whitespace) {
+        Some((id, token)) => (
+            id.trim_start_matches('#')
+                .parse()
+                .map_err(|_| "Msg ids are numbers.")?,
+            token.trim(),
+        ),
+        None if args.is_empty() => return Err(String::default()),
+        None => (
+            ctx.selected_msg_id
+                .ok_or("Which msg do you want to react to?")?,
+            args,
+        ),
+    };
+    if !is_valid_reaction(token) {
+        return Err(
+            "Reactions are emoji or short words without spaces, commas or colons.".to_string(),
+        );
+    }
+    Ok(CommandAction::Send(Message::reaction(id, token)))
+}
+
+fn reply(ctx: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    if args.is_empty() {
+        return Err(String::default());
+    }
+    // a bare number may start the text, so given ids start with `#`
+    let (id, content) = match args.strip_prefix('#') {
+        Some(rest) => {
+            let (id, content) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            (
+                id.parse().map_err(|_| "Msg ids are numbers.")?,
+                content.trim(),
+            )
+        }
+        None => (
+            ctx.selected_msg_id
+                .ok_or("Which msg do you want to reply to?")?,
+            args,
+        ),
+    };
+    if content.is_empty() {
+        return Err(String::default());
+    }
+    let mut msg = match ctx.peer {
+        Some(peer) => Message::direct(peer, content),
+        None => Message {
+            msg_room: ctx.room_or_default().to_string(),
+            msg_content: content.to_string(),
+            ..Default::default()
+        },
+    };
+    msg.msg_reply_to = id;
+    Ok(CommandAction::Send(msg))
+}
+
+fn search(ctx: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    if args.is_empty() {
+        return Err(String::default());
+    }
+    let query = SearchQuery::parse(args)?;
+    Ok(CommandAction::Send(Message {
+        msg_type: MessageType::Search,
+        msg_room: ctx.room_or_default().to_string(),
+        msg_content: query.to_string(),
+        ..Default::default()
+    }))
+}
+
+fn keys(ctx: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    let nick = match args {
+        "" => ctx.peer,
+        nick => Some(nick.trim_start_matches('@')),
+    };
+    Ok(CommandAction::ShowKeys(nick.map(str::to_string)))
+}
+
+fn login(_: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    login_action(args, false)
+}
+
+fn register(_: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    login_action(args, true)
+}
+
+/// Passwords are never typed in commands, so they do not stay in the editor or history.
+fn login_action(args: &str, register: bool) -> Result<CommandAction, String> {
+    if !args.is_empty() && !is_valid_nickname(args) {
+        return Err("Nicknames contain only letters, digits, - and _.".to_string());
+    }
+    Ok(CommandAction::Login {
+        nick: args.to_string(),
+        register,
+    })
+}
+
+fn join(_: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    let room = args.trim_start_matches('#');
+    if !is_valid_room_name(room) {
+        return Err("Room names contain only letters, digits, - and _.".to_string());
+    }
+    Ok(CommandAction::Send(Message {
+        msg_type: MessageType::RoomJoin,
+        msg_room: room.to_string(),
+        ..Default::default()
+    }))
+}
+
+fn part(ctx: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    let room = match args.trim_start_matches('#') {
+        "" => ctx.room.ok_or("Which room do you want to leave?")?,
+        room => room,
+    };
+    Ok(CommandAction::Send(Message {
+        msg_type: MessageType::RoomPart,
+        msg_room: room.to_string(),
+        ..Default::default()
+    }))
+}
+
+fn rooms(ctx: &CommandContext, _: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    Ok(CommandAction::Send(Message {
+        msg_type: MessageType::RoomList,
+        msg_room: ctx.room_or_default().to_string(),
+        ..Default::default()
+    }))
+}
+
+fn stats(ctx: &CommandContext, _: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    Ok(CommandAction::Send(Message {
+        msg_type: MessageType::Stats,
+        msg_room: ctx.room_or_default().to_string(),
+        ..Default::default()
+    }))
+}
+
+/// Split arguments into a nickname and the rest, `Err` shows usage if there is no nickname.
+fn nick_and_rest(args: &str) -> Result<(&str, &str), String> {
+    let (nick, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    match nick.trim_start_matches('@') {
+        "" => Err(String::default()),
+        nick if is_valid_nickname(nick) => Ok((nick, rest.trim())),
+        _ => Err("Nicknames contain only letters, digits, - and _.".to_string()),
+    }
+}
+
+fn kick(_: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    let (nick, reason) = nick_and_rest(args)?;
+    Ok(CommandAction::Send(Message::moderation(
+        "kick", nick, reason,
+    )))
+}
+
+fn ban(_: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    let (action, args) = match args.strip_prefix("-ip") {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => {
+            ("banip", rest.trim_start())
+        }
+        _ => ("ban", args),
+    };
+    let (target, reason) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    if action == "ban" && target.parse::<IpAddr>().is_ok() {
+        return Ok(CommandAction::Send(Message::moderation(
+            action,
+            target,
+            reason.trim(),
+        )));
+    }
+    let (nick, reason) = nick_and_rest(args)?;
+    Ok(CommandAction::Send(Message::moderation(
+        action, nick, reason,
+    )))
+}
+
+fn unban(_: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    if args.parse::<IpAddr>().is_ok() {
+        return Ok(CommandAction::Send(Message::moderation("unban", args, "")));
+    }
+    let (nick, _) = nick_and_rest(args)?;
+    Ok(CommandAction::Send(Message::moderation("unban", nick, "")))
+}
+
+fn mute(_: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    let (nick, rest) = nick_and_rest(args)?;
+    let (duration, _) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    if duration.is_empty() {
+        return Err(String::default());
+    }
+    parse_duration(duration)
+        .ok_or("Durations look like 30s, 10m, 2h or 1d, and are a year at most.")?;
+    Ok(CommandAction::Send(Message::moderation("mute", nick, rest)))
+}
+
+fn unmute(_: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    let (nick, _) = nick_and_rest(args)?;
+    Ok(CommandAction::Send(Message::moderation("unmute", nick, "")))
+}
+
+fn op(_: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    let (nick, _) = nick_and_rest(args)?;
+    Ok(CommandAction::Send(Message::moderation("op", nick, "")))
+}
+
+fn deop(_: &CommandContext, args: &str, _: &CommandRegistry) -> Result<CommandAction, String> {
+    let (nick, _) = nick_and_rest(args)?;
+    Ok(CommandAction::Send(Message::moderation("deop", nick, "")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(input: &str, room: Option<&str>) -> Result<CommandAction, String> {
+        CommandRegistry::default().parse(
+            input,
+            &CommandContext {
+                room,
+                peer: None,
+                last_msg_id: None,
+                selected_msg_id: None,
+            },
+        )
+    }
+
+    fn sent(action: Result<CommandAction, String>) -> Message {
+        match action {
+            Ok(CommandAction::Send(msg)) => msg,
+            _ => panic!("Command should send a msg."),
+        }
+    }
+
+    #[test]
+    fn plain_text() {
+        let msg = sent(parse("hi /join", Some("rust")));
+        assert!(matches!(msg.msg_type, MessageType::TextMessage));
+        assert_eq!(msg.msg_room, "rust");
+        assert_eq!(msg.msg_content, "hi /join");
+
+        let msg = sent(parse("//join is a command", Some("rust")));
+        assert!(matches!(msg.msg_type, MessageType::TextMessage));
+        assert_eq!(msg.msg_content, "/join is a command");
+    }
+
+    #[test]
+    fn room_commands() {
+        let msg = sent(parse("/join #rust", None));
+        assert!(matches!(msg.msg_type, MessageType::RoomJoin));
+        assert_eq!(msg.msg_room, "rust");
+
+        let msg = sent(parse("/part", Some("rust")));
+        assert!(matches!(msg.msg_type, MessageType::RoomPart));
+        assert_eq!(msg.msg_room, "rust");
+
+        assert!(parse("/part", None).is_err());
+        assert!(parse("/join a,b", None).is_err());
+
+        let msg = sent(parse("/stats", Some("rust")));
+        assert!(matches!(msg.msg_type, MessageType::Stats));
+        assert_eq!(msg.msg_room, "rust");
+    }
+
+    #[test]
+    fn direct_msg_command() {
+        let msg = sent(parse("/msg bob  hi, bob", Some(DEFAULT_ROOM)));
+        assert!(matches!(msg.msg_type, MessageType::DirectMessage));
+        assert_eq!(msg.msg_target, "bob");
+        assert_eq!(msg.msg_content, "hi, bob");
+
+        let err = parse("/msg bob", None).err().unwrap();
+        assert_eq!(err, "Usage: /msg <nick> <text>");
+    }
+
+    #[test]
+    fn local_commands() {
+        assert!(matches!(parse("/clear", None), Ok(CommandAction::Clear)));
+        assert!(matches!(parse("/quit", None), Ok(CommandAction::Quit)));
+        assert!(matches!(parse("/help", None), Ok(CommandAction::Show(_))));
+        assert!(parse("/nope", None).is_err());
+        assert!(matches!(
+            sent(parse("/me waves", Some("rust"))).msg_type,
+            MessageType::ActionMessage
+        ));
+        assert!(matches!(
+            sent(parse("/nick alice", None)).msg_type,
+            MessageType::NickChange
+        ));
+        let msg = sent(parse("/search from:bob  hi there before:100", None));
+        assert!(matches!(msg.msg_type, MessageType::Search));
+        assert_eq!(msg.msg_content, "from:bob before:100 hi there");
+        assert!(parse("/search after:soon", None).is_err());
+        assert!(matches!(
+            parse("/keys @bob", None),
+            Ok(CommandAction::ShowKeys(Some(nick))) if nick == "bob"
+        ));
+        assert!(matches!(
+            parse("/keys", Some("rust")),
+            Ok(CommandAction::ShowKeys(None))
+        ));
+        assert!(matches!(
+            parse("/register alice", None),
+            Ok(CommandAction::Login { nick, register: true }) if nick == "alice"
+        ));
+        assert!(matches!(
+            parse("/login", None),
+            Ok(CommandAction::Login { nick, register: false }) if nick.is_empty()
+        ));
+        assert!(parse("/login a,b", None).is_err());
+    }
+
+    #[test]
+    fn msg_id_commands() {
+        let registry = CommandRegistry::default();
+        let ctx = CommandContext {
+            room: Some("rust"),
+            peer: None,
+            last_msg_id: Some(7),
+            selected_msg_id: Some(9),
+        };
+        let msg = sent(registry.parse("/edit fixed typo", &ctx));
+        assert!(matches!(msg.msg_type, MessageType::MessageEdit));
+        assert_eq!(msg.msg_id, 7);
+        assert_eq!(msg.msg_content, "fixed typo");
+        let msg = sent(registry.parse("/delete", &ctx));
+        assert!(matches!(msg.msg_type, MessageType::MessageDelete));
+        assert_eq!(msg.msg_id, 7);
+
+        assert!(registry.parse("/edit", &ctx).is_err());
+
+        let msg = sent(registry.parse("/react 👍", &ctx));
+        assert!(matches!(msg.msg_type, MessageType::Reaction));
+        assert_eq!(msg.msg_id, 9);
+        assert_eq!(msg.msg_content, "👍");
+        let msg = sent(registry.parse("/react #3 +1", &ctx));
+        assert_eq!(msg.msg_id, 3);
+        assert_eq!(msg.msg_content, "+1");
+        assert!(registry.parse("/react", &ctx).is_err());
+        assert!(registry.parse("/react x +1", &ctx).is_err());
+        assert!(parse("/react +1", Some("rust")).is_err());
+        assert!(parse("/edit fixed typo", Some("rust")).is_err());
+        assert!(parse("/delete", Some("rust")).is_err());
+    }
+
+    #[test]
+    fn reply_command() {
+        let registry = CommandRegistry::default();
+        let mut ctx = CommandContext {
+            room: Some("rust"),
+            peer: None,
+            last_msg_id: None,
+            selected_msg_id: Some(9),
+        };
+        let msg = sent(registry.parse("/reply 2 of them", &ctx));
+        assert!(matches!(msg.msg_type, MessageType::TextMessage));
+        assert_eq!(msg.msg_room, "rust");
+        assert_eq!(msg.msg_reply_to, 9);
+        assert_eq!(msg.msg_content, "2 of them");
+        let msg = sent(registry.parse("/reply #3 sure", &ctx));
+        assert_eq!(msg.msg_reply_to, 3);
+        assert_eq!(msg.msg_content, "sure");
+        assert!(registry.parse("/reply #3", &ctx).is_err());
+        assert!(registry.parse("/reply #x sure", &ctx).is_err());
+        assert!(parse("/reply sure", Some("rust")).is_err());
+
+        ctx.room = None;
+        ctx.peer = Some("bob");
+        let msg = sent(registry.parse("/reply sure", &ctx));
+        assert!(matches!(msg.msg_type, MessageType::DirectMessage));
+        assert_eq!(msg.msg_target, "bob");
+        assert_eq!(msg.msg_reply_to, 9);
+    }
+
+    #[test]
+    fn moderation_commands() {
+        let msg = sent(parse("/kick @mallory too loud", None));
+        assert!(matches!(msg.msg_type, MessageType::Moderation));
+        assert_eq!(msg.msg_target, "mallory");
+        assert_eq!(msg.msg_content, "kick too loud");
+        let msg = sent(parse("/ban 10.0.0.7", None));
+        assert_eq!(msg.msg_target, "10.0.0.7");
+        assert_eq!(msg.msg_content, "ban");
+        let msg = sent(parse("/ban -ip mallory spam", None));
+        assert_eq!(msg.msg_target, "mallory");
+        assert_eq!(msg.msg_content, "banip spam");
+        assert_eq!(sent(parse("/unban ::1", None)).msg_target, "::1");
+        let msg = sent(parse("/mute mallory 10m calm down", None));
+        assert_eq!(msg.msg_content, "mute 10m calm down");
+        assert_eq!(sent(parse("/op alice", None)).msg_content, "op");
+        assert_eq!(sent(parse("/deop alice", None)).msg_content, "deop");
+
+        assert!(parse("/kick", None).is_err());
+        assert!(parse("/ban -ip", None).is_err());
+        assert!(parse("/mute mallory", None).is_err());
+        assert!(parse("/mute mallory soon", None).is_err());
+        assert!(parse("/op a,b", None).is_err());
+    }
+
+    #[test]
+    fn register_command() {
+        let mut registry = CommandRegistry::default();
+        registry.register(Command {
+            name: "ping",
+            usage: "/ping",
+            description: "Say pong locally",
+            handler: |_, _, _| Ok(CommandAction::Show(vec!["pong".to_string()])),
+        });
+        let ctx = CommandContext {
+            room: None,
+            peer: None,
+            last_msg_id: None,
+            selected_msg_id: None,
+        };
+        match registry.parse("/ping", &ctx) {
+            Ok(CommandAction::Show(lines)) => assert_eq!(lines, vec!["pong"]),
+            _ => panic!("/ping should be registered."),
+        }
+    }
+
+    #[test]
+    fn complete_commands_and_nicknames() {
+        let registry = CommandRegistry::default();
+        let nicknames = vec!["alice".to_string(), "alex".to_string(), "bob".to_string()];
+
+        let completion = registry.complete("/jo", 3, &nicknames).unwrap();
+        assert_eq!(completion.input, "/join ");
+        assert_eq!(completion.cursor_position, 6);
+
+        let completion = registry.complete("/msg al hi", 7, &nicknames).unwrap();
+        assert_eq!(completion.input, "/msg al hi");
+        assert_eq!(completion.candidates, vec!["alice", "alex"]);
+
+        let completion = registry.complete("/msg b", 6, &nicknames).unwrap();
+        assert_eq!(completion.input, "/msg bob ");
+
+        let completion = registry.complete("hi @b", 5, &nicknames).unwrap();
+        assert_eq!(completion.input, "hi @bob ");
+        assert_eq!(completion.cursor_position, 8);
+
+        assert!(registry.complete("/xyz", 4, &nicknames).is_none());
+        assert!(registry.complete("", 0, &nicknames).is_none());
+    }
+}
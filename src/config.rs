@@ -0,0 +1,494 @@
+use std::{collections::HashMap, env, error::Error, fs, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::{moderation::MAX_DURATION_SECS, theme::ThemeConfig};
+
+/// Settings loaded from the config file, every field has a default value.
+///
+/// The file is `$CHAMBER_CONFIG`, or `chamber/config.toml` in
+/// `$XDG_CONFIG_HOME` (`~/.config` if not set).
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub keys: KeysConfig,
+    /// Name of the theme, `dark` if not set
+    pub theme: Option<String>,
+    /// Themes defined by user
+    pub themes: HashMap<String, ThemeConfig>,
+    pub notify: NotifyConfig,
+    pub log: LogConfig,
+    pub logging: LoggingConfig,
+    pub tls: TlsConfig,
+    pub server: ServerConfig,
+    pub client: ClientConfig,
+}
+
+/// Keybindings of the TUI client.
+///
+/// ```toml
+/// [keys]
+/// preset = "emacs"
+///
+/// [keys.global]
+/// "ctrl-q" = "quit"
+///
+/// [keys.editor]
+/// "ctrl-k" = "clear-input"
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    /// `default`, `emacs` or `vi`
+    pub preset: Option<String>,
+    /// key -> action, working in all blocks
+    pub global: HashMap<String, String>,
+    /// key -> action, working in editor
+    pub editor: HashMap<String, String>,
+    /// key -> action, working in msg window, room list and client list
+    pub browse: HashMap<String, String>,
+}
+
+/// How to notify you when someone mentions you by `@nick`.
+///
+/// ```toml
+/// [notify]
+/// bell = false
+/// ```
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// Ring the terminal bell
+    pub bell: bool,
+    /// Send a desktop notification by OSC 9 escape sequence
+    pub desktop: bool,
+}
+impl Default for NotifyConfig {
+    fn default() -> NotifyConfig {
+        NotifyConfig {
+            bell: true,
+            desktop: true,
+        }
+    }
+}
+
+/// Logging chat to files, see `chat_log`.
+///
+/// ```toml
+/// [log]
+/// enabled = true
+/// dir = "~/chamber-logs"
+/// formats = ["jsonl"]
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub enabled: bool,
+    /// `chamber/logs` in `$XDG_DATA_HOME` (`~/.local/share` if not set) by default
+    pub dir: Option<String>,
+    pub formats: Vec<LogFormat>,
+    /// Size in bytes before a log file is rotated, files are also rotated every day
+    pub max_size: u64,
+}
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            enabled: false,
+            dir: None,
+            formats: vec![LogFormat::Text, LogFormat::Jsonl],
+            max_size: 1024 * 1024,
+        }
+    }
+}
+impl LogConfig {
+    /// Get the directory of logs, `~` at start of the configured one is your home.
+    pub fn dir(&self) -> Option<PathBuf> {
+        match self.dir.as_deref() {
+            Some(dir) => expand_home(dir),
+            None => data_dir().map(|dir| dir.join("logs")),
+        }
+    }
+}
+
+/// Diagnostics of server and clients, see `logger`. Chat logs are set in `[log]`.
+///
+/// ```toml
+/// [logging]
+/// level = "info,server=debug"
+/// json = true
+/// file = "/var/log/chamber/server.log"
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Level of all modules and levels of some, `$CHAMBER_LOG` overrides it
+    pub level: String,
+    /// Write a JSON object per line instead of text
+    pub json: bool,
+    /// File server logs to besides stderr
+    pub file: Option<String>,
+    /// File the TUI logs to, `chamber/ui.log` in `$XDG_DATA_HOME` by default
+    pub ui_file: Option<String>,
+}
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            level: "info".to_string(),
+            json: false,
+            file: None,
+            ui_file: None,
+        }
+    }
+}
+impl LoggingConfig {
+    pub fn file_path(&self) -> Option<PathBuf> {
+        self.file.as_deref().and_then(expand_home)
+    }
+
+    pub fn ui_file_path(&self) -> Option<PathBuf> {
+        match self.ui_file.as_deref() {
+            Some(path) => expand_home(path),
+            None => data_dir().map(|dir| dir.join("ui.log")),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Lines for reading, `.log`
+    Text,
+    /// A JSON object per line, `.jsonl`, read by `chamber logs`
+    Jsonl,
+}
+impl LogFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            LogFormat::Text => "log",
+            LogFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+/// TLS between clients and server, see `transport`. `chamber cert` makes a self-signed cert.
+///
+/// ```toml
+/// [tls]
+/// enabled = true
+/// # server
+/// cert = "~/.config/chamber/cert.pem"
+/// key = "~/.config/chamber/key.pem"
+/// # client, trust certs issued by these
+/// ca = "~/.config/chamber/cert.pem"
+/// ```
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// PEM file of the server's certificate chain
+    pub cert: Option<String>,
+    /// PEM file of the server's private key
+    pub key: Option<String>,
+    /// PEM file of CA certs trusted by clients
+    pub ca: Option<String>,
+    /// SHA-256 fingerprint of the only server cert trusted by clients, `ca` is ignored if set
+    pub fingerprint: Option<String>,
+}
+impl TlsConfig {
+    pub fn cert_path(&self) -> Option<PathBuf> {
+        self.cert.as_deref().and_then(expand_home)
+    }
+
+    pub fn key_path(&self) -> Option<PathBuf> {
+        self.key.as_deref().and_then(expand_home)
+    }
+
+    pub fn ca_path(&self) -> Option<PathBuf> {
+        self.ca.as_deref().and_then(expand_home)
+    }
+}
+
+/// Settings only used by server.
+///
+/// ```toml
+/// [server]
+/// accounts = true
+/// require_login = true
+/// password = "open sesame"
+/// invites = true
+/// operators = ["alice"]
+/// deny = ["203.0.113.0/24"]
+/// ```
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Let users register accounts, registered nicknames can only be used after logging in
+    pub accounts: bool,
+    /// File of accounts, `chamber/accounts` in `$XDG_DATA_HOME` by default
+    pub accounts_file: Option<String>,
+    /// Only users logged in can chat, needs `accounts`
+    pub require_login: bool,
+    /// Password shared by everyone allowed to connect
+    pub password: Option<String>,
+    /// Let clients with single-use invites made by `chamber invite` connect
+    pub invites: bool,
+    /// File of unused invites, `chamber/invites` in `$XDG_DATA_HOME` by default
+    pub invites_file: Option<String>,
+    /// Accounts who are operators, needs `accounts`
+    pub operators: Vec<String>,
+    /// File of banned nicknames and IPs, `chamber/bans` in `$XDG_DATA_HOME` by default
+    pub bans_file: Option<String>,
+    /// Address of the HTTP endpoint of metrics for Prometheus, like `127.0.0.1:9100`,
+    /// metrics are only shown by `/stats` and `chamber admin stats` if not set
+    pub metrics_addr: Option<String>,
+    /// Unix socket for `chamber admin`, `chamber/admin.sock` in `$XDG_DATA_HOME` by default
+    pub admin_socket: Option<String>,
+    /// IPs or CIDRs like `10.0.0.0/8` allowed to connect, everyone if empty
+    pub allow: Vec<String>,
+    /// IPs or CIDRs never allowed to connect
+    pub deny: Vec<String>,
+    pub limits: LimitsConfig,
+}
+impl ServerConfig {
+    pub fn accounts_path(&self) -> Option<PathBuf> {
+        match self.accounts_file.as_deref() {
+            Some(path) => expand_home(path),
+            None => data_dir().map(|dir| dir.join("accounts")),
+        }
+    }
+
+    pub fn invites_path(&self) -> Option<PathBuf> {
+        match self.invites_file.as_deref() {
+            Some(path) => expand_home(path),
+            None => data_dir().map(|dir| dir.join("invites")),
+        }
+    }
+
+    pub fn admin_socket_path(&self) -> Option<PathBuf> {
+        match self.admin_socket.as_deref() {
+            Some(path) => expand_home(path),
+            None => data_dir().map(|dir| dir.join("admin.sock")),
+        }
+    }
+
+    pub fn bans_path(&self) -> Option<PathBuf> {
+        match self.bans_file.as_deref() {
+            Some(path) => expand_home(path),
+            None => data_dir().map(|dir| dir.join("bans")),
+        }
+    }
+}
+
+/// Limits on connections and how fast and how much clients send, see `access` and `flood`.
+///
+/// ```toml
+/// [server.limits]
+/// msgs_per_sec = 2.0
+/// burst = 10
+/// on_flood = "disconnect"
+/// max_connections = 100
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Msgs a connection can send per second in the long run
+    pub msgs_per_sec: f64,
+    /// Msgs a connection can send at once after being quiet
+    pub burst: u32,
+    /// Like `msgs_per_sec`, but for all connections from an IP
+    pub ip_msgs_per_sec: f64,
+    pub ip_burst: u32,
+    /// Msgs dropped for being too fast before the client is taken as flooding,
+    /// each msg let through takes one away
+    pub strikes: u32,
+    pub on_flood: FloodAction,
+    /// Seconds a flooding client is muted for, a year at most
+    pub mute_secs: u64,
+    /// Max size of a msg line in bytes, longer ones are refused
+    pub max_msg_size: usize,
+    /// Max number of connections open at once
+    pub max_connections: usize,
+    /// Max number of connections open at once from an IP
+    pub max_connections_per_ip: usize,
+}
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            msgs_per_sec: 2.0,
+            burst: 10,
+            ip_msgs_per_sec: 5.0,
+            ip_burst: 30,
+            strikes: 20,
+            on_flood: FloodAction::Mute,
+            mute_secs: 60,
+            max_msg_size: 8 * 1024,
+            max_connections: 1024,
+            max_connections_per_ip: 16,
+        }
+    }
+}
+
+/// What server does with a flooding client.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FloodAction {
+    Mute,
+    Disconnect,
+}
+
+/// How clients answer a server asking for its password or an invite, see `gate`.
+///
+/// You are asked when connecting if neither is set.
+///
+/// ```toml
+/// [client]
+/// password = "open sesame"
+/// ```
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub password: Option<String>,
+    pub invite: Option<String>,
+}
+
+/// Get `chamber` in `$XDG_DATA_HOME` (`~/.local/share` if not set), where keys and logs are kept.
+pub fn data_dir() -> Option<PathBuf> {
+    let data_home = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| expand_home("~/.local/share"))?;
+    Some(data_home.join("chamber"))
+}
+
+/// Take `~` at start of a path as your home.
+fn expand_home(path: &str) -> Option<PathBuf> {
+    match path.strip_prefix("~/") {
+        Some(rest) => env::var_os("HOME").map(|home| PathBuf::from(home).join(rest)),
+        None => Some(PathBuf::from(path)),
+    }
+}
+
+impl Config {
+    /// Get path of the config file.
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("CHAMBER_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+        let config_dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_dir.join("chamber").join("config.toml"))
+    }
+
+    /// Load config from the config file, default config is used if the file does not exist.
+    pub fn load() -> Result<Config, Box<dyn Error>> {
+        match Config::path() {
+            Some(path) if path.exists() => {
+                let content = fs::read_to_string(&path)?;
+                Config::parse(&content)
+                    .map_err(|err| format!("Failed to load {}: {}", path.display(), err).into())
+            }
+            _ => Ok(Config::default()),
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Config, Box<dyn Error>> {
+        let config: Config = toml::from_str(content)?;
+        if config.server.limits.mute_secs > MAX_DURATION_SECS {
+            return Err(format!(
+                "mute_secs in [server.limits] is {} at most.",
+                MAX_DURATION_SECS
+            )
+            .into());
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(
+            r#"
+            theme = "mine"
+
+            [themes.mine]
+            base = "light"
+            nick_colors = ["red", "blue"]
+
+            [keys]
+            preset = "vi"
+
+            [keys.editor]
+            "ctrl-k" = "clear-input"
+
+            [log]
+            enabled = true
+            formats = ["jsonl"]
+
+            [logging]
+            level = "warn,server=debug"
+            json = true
+
+            [tls]
+            enabled = true
+            ca = "/etc/chamber/ca.pem"
+
+            [server]
+            accounts = true
+            accounts_file = "/var/lib/chamber/accounts"
+            password = "open sesame"
+            operators = ["alice"]
+            allow = ["10.0.0.0/8", "::1"]
+
+            [server.limits]
+            burst = 5
+            on_flood = "disconnect"
+
+            [client]
+            invite = "abc"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.keys.preset.as_deref(), Some("vi"));
+        assert_eq!(config.keys.editor["ctrl-k"], "clear-input");
+        assert!(config.keys.global.is_empty());
+        assert_eq!(config.theme.as_deref(), Some("mine"));
+        assert_eq!(config.themes["mine"].base.as_deref(), Some("light"));
+        assert!(config.notify.bell);
+        assert!(config.log.enabled);
+        assert_eq!(config.log.formats, vec![LogFormat::Jsonl]);
+        assert_eq!(config.log.max_size, 1024 * 1024);
+        assert_eq!(config.logging.level, "warn,server=debug");
+        assert!(config.logging.json);
+        assert!(config.logging.file_path().is_none());
+        assert!(config.tls.enabled);
+        assert_eq!(
+            config.tls.ca_path(),
+            Some(PathBuf::from("/etc/chamber/ca.pem"))
+        );
+        assert_eq!(config.tls.cert_path(), None);
+        assert!(config.server.accounts);
+        assert!(!config.server.require_login);
+        assert_eq!(
+            config.server.accounts_path(),
+            Some(PathBuf::from("/var/lib/chamber/accounts"))
+        );
+        assert_eq!(config.server.password.as_deref(), Some("open sesame"));
+        assert!(!config.server.invites);
+        assert_eq!(config.server.operators, vec!["alice"]);
+        assert_eq!(config.server.limits.burst, 5);
+        assert_eq!(config.server.limits.on_flood, FloodAction::Disconnect);
+        assert_eq!(config.server.limits.max_msg_size, 8 * 1024);
+        assert_eq!(config.server.limits.max_connections_per_ip, 16);
+        assert_eq!(config.server.allow, vec!["10.0.0.0/8", "::1"]);
+        assert!(config.server.deny.is_empty());
+        assert_eq!(config.client.invite.as_deref(), Some("abc"));
+
+        assert!(Config::parse("").is_ok());
+        assert!(Config::parse("[unknown]").is_err());
+        assert!(Config::parse("[server.limits]\nmute_secs = 18446744073709551615").is_err());
+    }
+}
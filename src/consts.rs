@@ -1,4 +1,39 @@
 pub const ARG_CLIENT: &str = "client";
 pub const ARG_SERVER: &str = "server";
+/// Print chat logs, see `chat_log`
+pub const ARG_LOGS: &str = "logs";
+/// Generate a self-signed cert for TLS, see `transport`
+pub const ARG_CERT: &str = "cert";
+/// Make invites for a server with `invites` on, see `gate`
+pub const ARG_INVITE: &str = "invite";
+/// Control the running server, see `admin`
+pub const ARG_ADMIN: &str = "admin";
 
 pub const MSG_BUF_SIZE: usize = 256;
+/// Number of recent msgs kept in a room of the TUI, older ones are dropped
+pub const ROOM_MSGS_SIZE: usize = 1000;
+
+/// Room every client joins after connecting
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// Seconds without key presses before the user is taken as away
+pub const IDLE_SECS: u64 = 60;
+
+/// Seconds before telling others again that you are still typing
+pub const TYPING_INTERVAL_SECS: u64 = 3;
+/// Seconds before forgetting someone typing if no more typing events come
+pub const TYPING_EXPIRE_SECS: u64 = 6;
+
+/// Number of recent msgs kept by server, only they can be edited or deleted
+pub const HISTORY_SIZE: usize = 1000;
+/// Max number of msgs a search returns, the newest ones are returned
+pub const SEARCH_RESULTS: usize = 50;
+
+/// Address of the server, clients connect to it and server listens on it
+pub const SERVER_ADDR: &str = "127.0.0.1:9999";
+/// Seconds to wait for a peer during the TLS handshake
+pub const TLS_HANDSHAKE_SECS: u64 = 10;
+/// Seconds for a new client to give the server password or an invite
+pub const AUTH_SECS: u64 = 60;
+/// Max number of passwords of a client being checked at once, as each takes a while
+pub const PASSWORD_JOBS_PER_CLIENT: usize = 1;
@@ -0,0 +1,384 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::message::{Message, MessageType};
+
+/// Content of encrypted direct msgs starts with this, followed by base64 of nonce and ciphertext.
+pub const ENCRYPTED_PREFIX: &str = "e2e:";
+
+const NONCE_SIZE: usize = 12;
+
+/// Parse a public key in base64, as published in `PublicKey` msgs.
+pub fn parse_public_key(key: &str) -> Option<PublicKey> {
+    let bytes: [u8; 32] = STANDARD.decode(key).ok()?.try_into().ok()?;
+    Some(PublicKey::from(bytes))
+}
+
+/// Fingerprint of a public key for people to compare, like `1A2B 3C4D ...`.
+pub fn fingerprint(key: &PublicKey) -> String {
+    Sha256::digest(key.as_bytes())[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Lines showing fingerprints of your key and the key of `nick`, for `/keys`.
+pub fn fingerprint_lines(keys: Option<&KeyRing>, nick: Option<&str>) -> Vec<String> {
+    let keys = match keys {
+        Some(keys) => keys,
+        None => return vec!["Encrypted direct msgs are off.".to_string()],
+    };
+    let mut lines = vec![format!("Your key: {}", keys.own_fingerprint())];
+    if let Some(nick) = nick {
+        lines.push(match keys.peer_fingerprint(nick) {
+            Some(fingerprint) => format!("Key of {}: {}", nick, fingerprint),
+            None => format!(
+                "{} has not published a key, direct msgs with them are not encrypted.",
+                nick
+            ),
+        });
+    }
+    lines
+}
+
+/// Keys for end-to-end encrypted direct msgs.
+///
+/// Your identity key is kept in `identity.key`, and keys of others are remembered
+/// by nickname in `known_keys`, so a key changed since it was first seen can be warned about.
+pub struct KeyRing {
+    secret: StaticSecret,
+    public: PublicKey,
+    /// Nickname -> key of users published to server
+    peers: HashMap<String, PublicKey>,
+    /// Nickname -> key in base64 seen before
+    known: HashMap<String, String>,
+    /// Where `known` is saved, `None` if it is not saved
+    known_path: Option<PathBuf>,
+}
+impl KeyRing {
+    /// Load keys from `dir`, an identity key is generated if there is none.
+    pub fn load(dir: &Path) -> io::Result<KeyRing> {
+        fs::create_dir_all(dir)?;
+        let identity_path = dir.join("identity.key");
+        let secret = match fs::read_to_string(&identity_path) {
+            Ok(text) => {
+                let bytes: [u8; 32] = STANDARD
+                    .decode(text.trim())
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{} is broken.", identity_path.display()),
+                        )
+                    })?;
+                StaticSecret::from(bytes)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let secret = StaticSecret::random_from_rng(OsRng);
+                // only you can read your key
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(&identity_path)?;
+                writeln!(file, "{}", STANDARD.encode(secret.as_bytes()))?;
+                secret
+            }
+            Err(err) => return Err(err),
+        };
+        let known_path = dir.join("known_keys");
+        let known = match fs::read_to_string(&known_path) {
+            Ok(text) => text
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .map(|(nick, key)| (nick.to_string(), key.trim().to_string()))
+                .collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        let mut keys = KeyRing::new(secret);
+        keys.known = known;
+        keys.known_path = Some(known_path);
+        Ok(keys)
+    }
+
+    fn new(secret: StaticSecret) -> KeyRing {
+        KeyRing {
+            public: PublicKey::from(&secret),
+            secret,
+            peers: HashMap::new(),
+            known: HashMap::new(),
+            known_path: None,
+        }
+    }
+
+    /// A msg publishing your key to server.
+    pub fn public_key_msg(&self) -> Message {
+        Message {
+            msg_type: MessageType::PublicKey,
+            msg_content: STANDARD.encode(self.public.as_bytes()),
+            ..Default::default()
+        }
+    }
+
+    pub fn own_fingerprint(&self) -> String {
+        fingerprint(&self.public)
+    }
+
+    pub fn peer_fingerprint(&self, nick: &str) -> Option<String> {
+        self.peers.get(nick).map(fingerprint)
+    }
+
+    /// Remember the key published by `nick`, return a warning if it is not the key seen before.
+    pub fn add_peer(&mut self, nick: &str, key: &str) -> Option<String> {
+        let public = parse_public_key(key)?;
+        self.peers.insert(nick.to_string(), public);
+        let warning = match self.known.get(nick) {
+            Some(known) if known == key => return None,
+            Some(_) => Some(format!(
+                "The key of {} has changed! Check the new fingerprint {} with them before \
+                 trusting direct msgs.",
+                nick,
+                fingerprint(&public)
+            )),
+            None => None,
+        };
+        self.known.insert(nick.to_string(), key.to_string());
+        if let Err(err) = self.save_known() {
+            return Some(format!("Failed to save known keys: {}", err));
+        }
+        warning
+    }
+
+    /// Move the key of a user who changes nickname, return a warning like `add_peer`.
+    pub fn rename_peer(&mut self, old: &str, new: &str) -> Option<String> {
+        let public = self.peers.remove(old)?;
+        self.add_peer(new, &STANDARD.encode(public.as_bytes()))
+    }
+
+    fn save_known(&self) -> io::Result<()> {
+        let path = match &self.known_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut lines: Vec<String> = self
+            .known
+            .iter()
+            .map(|(nick, key)| format!("{} {}\n", nick, key))
+            .collect();
+        lines.sort();
+        fs::write(path, lines.concat())
+    }
+
+    /// Cipher shared with `peer`, both sides get the same one.
+    fn cipher(&self, peer: &str) -> Option<ChaCha20Poly1305> {
+        let shared = self.secret.diffie_hellman(self.peers.get(peer)?);
+        let key = Sha256::new()
+            .chain_update(b"chamber e2e v1")
+            .chain_update(shared.as_bytes())
+            .finalize();
+        Some(ChaCha20Poly1305::new(&key))
+    }
+
+    /// Encrypt content of a msg from `name` to `peer`, return `false` if the key of `peer` is unknown.
+    pub fn seal(&self, msg: &mut Message, name: &str, peer: &str) -> bool {
+        let cipher = match self.cipher(peer) {
+            Some(cipher) => cipher,
+            None => return false,
+        };
+        let id = match msg.msg_type {
+            MessageType::MessageEdit => msg.msg_id,
+            _ => 0,
+        };
+        let aad = associated_data(name, peer, id);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: msg.msg_content.as_bytes(),
+            aad: &aad,
+        };
+        match cipher.encrypt(&nonce, payload) {
+            Ok(ciphertext) => {
+                let mut data = nonce.to_vec();
+                data.extend(ciphertext);
+                msg.msg_content = format!("{}{}", ENCRYPTED_PREFIX, STANDARD.encode(data));
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Decrypt content of a direct msg or an edit of it, `name` is your nickname.
+    ///
+    /// Msgs not encrypted are left as they are, return `false` if decryption fails.
+    pub fn open(&self, msg: &mut Message, name: &str) -> bool {
+        let data = match msg.msg_content.strip_prefix(ENCRYPTED_PREFIX) {
+            Some(data) => data,
+            None => return true,
+        };
+        let peer = if msg.msg_sender == name {
+            &msg.msg_target
+        } else {
+            &msg.msg_sender
+        };
+        // content of a msg found later is the last edit of it, which is bound to its id
+        let ids = match msg.msg_type {
+            MessageType::MessageEdit => vec![msg.msg_id],
+            _ => vec![msg.msg_id, 0],
+        };
+        let content = STANDARD
+            .decode(data)
+            .ok()
+            .filter(|data| data.len() > NONCE_SIZE)
+            .zip(self.cipher(peer))
+            .and_then(|(data, cipher)| {
+                let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+                ids.iter().find_map(|id| {
+                    let aad = associated_data(&msg.msg_sender, &msg.msg_target, *id);
+                    let payload = Payload {
+                        msg: ciphertext,
+                        aad: &aad,
+                    };
+                    cipher.decrypt(Nonce::from_slice(nonce), payload).ok()
+                })
+            })
+            .and_then(|plain| String::from_utf8(plain).ok());
+        match content {
+            Some(content) => {
+                msg.msg_content = content;
+                true
+            }
+            None => {
+                msg.msg_content = "(An encrypted msg, it can not be decrypted.)".to_string();
+                false
+            }
+        }
+    }
+}
+
+/// Data encrypted content is bound to, so server can not pass it off as from someone else,
+/// to someone else, or as an edit of another msg.
+///
+/// Ids of new direct msgs are given by server after they are sent, so only edits are bound to one.
+fn associated_data(sender: &str, target: &str, id: u64) -> Vec<u8> {
+    format!("{}\n{}\n{}", sender, target, id).into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key_ring() -> KeyRing {
+        KeyRing::new(StaticSecret::random_from_rng(OsRng))
+    }
+
+    #[test]
+    fn encrypt_direct_msgs() {
+        let mut alice = key_ring();
+        let mut bob = key_ring();
+        let eve = key_ring();
+        alice.add_peer("bob", &bob.public_key_msg().msg_content);
+        bob.add_peer("alice", &alice.public_key_msg().msg_content);
+
+        let mut msg = Message::direct("bob", "meet at noon");
+        msg.msg_sender = "alice".to_string();
+        assert!(!eve.seal(&mut msg.clone(), "eve", "bob"));
+        assert!(alice.seal(&mut msg, "alice", "bob"));
+        assert!(msg.msg_content.starts_with(ENCRYPTED_PREFIX));
+        assert!(!msg.msg_content.contains("noon"));
+
+        // the sender can read the echo from server too
+        let mut echo = msg.clone();
+        assert!(alice.open(&mut echo, "alice"));
+        assert_eq!(echo.msg_content, "meet at noon");
+        let mut received = msg.clone();
+        assert!(bob.open(&mut received, "bob"));
+        assert_eq!(received.msg_content, "meet at noon");
+        let mut stolen = msg.clone();
+        assert!(!eve.open(&mut stolen, "bob"));
+        // msgs found by search have ids
+        let mut found = Message {
+            msg_id: 7,
+            ..msg.clone()
+        };
+        assert!(bob.open(&mut found, "bob"));
+
+        let mut edit = Message {
+            msg_type: MessageType::MessageEdit,
+            msg_sender: "alice".to_string(),
+            msg_target: "bob".to_string(),
+            msg_id: 7,
+            msg_content: "meet at one".to_string(),
+            ..Default::default()
+        };
+        assert!(alice.seal(&mut edit, "alice", "bob"));
+        assert!(bob.open(&mut edit.clone(), "bob"));
+        let mut found = Message {
+            msg_type: MessageType::DirectMessage,
+            ..edit.clone()
+        };
+        assert!(bob.open(&mut found, "bob"));
+        assert_eq!(found.msg_content, "meet at one");
+        // content can not be moved to another msg, or passed off as from someone else
+        let mut moved = Message {
+            msg_id: 8,
+            ..edit.clone()
+        };
+        assert!(!bob.open(&mut moved, "bob"));
+        let mut forged = Message {
+            msg_sender: "carol".to_string(),
+            ..msg.clone()
+        };
+        bob.add_peer("carol", &alice.public_key_msg().msg_content);
+        assert!(!bob.open(&mut forged, "bob"));
+
+        let mut plain = Message::direct("bob", "hi");
+        assert!(bob.open(&mut plain, "bob"));
+        assert_eq!(plain.msg_content, "hi");
+    }
+
+    #[test]
+    fn warn_about_changed_keys() {
+        let mut alice = key_ring();
+        let bob = key_ring();
+        let bob_key = bob.public_key_msg().msg_content;
+        assert_eq!(alice.add_peer("bob", &bob_key), None);
+        assert_eq!(alice.add_peer("bob", &bob_key), None);
+        assert_eq!(alice.peer_fingerprint("bob"), Some(bob.own_fingerprint()));
+        assert_eq!(bob.own_fingerprint().len(), 39);
+
+        let warning = alice.add_peer("bob", &key_ring().public_key_msg().msg_content);
+        assert!(warning.unwrap().contains("has changed"));
+        assert_eq!(alice.rename_peer("bob", "robert"), None);
+        assert!(alice.peer_fingerprint("bob").is_none());
+        assert!(alice.add_peer("carol", "not a key").is_none());
+        assert!(alice.peer_fingerprint("carol").is_none());
+    }
+
+    #[test]
+    fn load_keys() {
+        let dir = std::env::temp_dir().join(format!("chamber-keys-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let bob_key = key_ring().public_key_msg().msg_content;
+        let mut keys = KeyRing::load(&dir).unwrap();
+        keys.add_peer("bob", &bob_key);
+        let loaded = KeyRing::load(&dir).unwrap();
+        assert_eq!(loaded.own_fingerprint(), keys.own_fingerprint());
+        assert_eq!(loaded.known.get("bob"), Some(&bob_key));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::config::LimitsConfig;
+
+/// Seconds between forgetting full buckets of IPs, which are the same as new ones
+const PRUNE_SECS: u64 = 60;
+
+/// Lets msgs pass at `rate` per second, and up to `capacity` at once after being idle.
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(capacity: u32, rate: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            capacity: capacity.max(1) as f64,
+            rate,
+            tokens: capacity.max(1) as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let secs = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + secs * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Take a token, `false` if there is none left.
+    pub fn take(&mut self, now: Instant) -> bool {
+        if !self.has_token(now) {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    fn has_token(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// Buckets of IPs, shared by connections from the same IP.
+pub struct IpBuckets {
+    capacity: u32,
+    rate: f64,
+    /// Buckets and when full ones were last forgotten
+    buckets: Mutex<(HashMap<IpAddr, TokenBucket>, Instant)>,
+}
+impl IpBuckets {
+    pub fn new(limits: &LimitsConfig) -> IpBuckets {
+        IpBuckets {
+            capacity: limits.ip_burst,
+            rate: limits.ip_msgs_per_sec,
+            buckets: Mutex::new((HashMap::new(), Instant::now())),
+        }
+    }
+
+    /// Take a token from the bucket of `ip` and one from `own`, or none if either is empty.
+    pub fn take(&self, ip: IpAddr, own: &mut TokenBucket, now: Instant) -> bool {
+        let (buckets, pruned) = &mut *self.buckets.lock().unwrap();
+        if now.saturating_duration_since(*pruned) >= Duration::from_secs(PRUNE_SECS) {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+            *pruned = now;
+        }
+        let bucket = buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(self.capacity, self.rate, now));
+        if !own.has_token(now) || !bucket.has_token(now) {
+            return false;
+        }
+        own.take(now) && bucket.take(now)
+    }
+}
+
+/// What to do with a msg just received.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Pass,
+    /// Drop the msg, `warn` is `true` for the first one dropped in a row
+    Drop {
+        warn: bool,
+    },
+    /// Drop the msg, the client keeps sending too fast
+    Flooding,
+}
+
+/// Rate limits of a connection, by its own bucket and the one of its IP.
+///
+/// Each dropped msg is a strike, and each msg let through takes one away.
+/// Too many strikes mean the client is flooding.
+pub struct Limiter {
+    bucket: TokenBucket,
+    ip: Option<(IpAddr, Arc<IpBuckets>)>,
+    strikes: u32,
+    max_strikes: u32,
+    warned: bool,
+}
+impl Limiter {
+    pub fn new(limits: &LimitsConfig, ip: Option<(IpAddr, Arc<IpBuckets>)>) -> Limiter {
+        Limiter {
+            bucket: TokenBucket::new(limits.burst, limits.msgs_per_sec, Instant::now()),
+            ip,
+            strikes: 0,
+            max_strikes: limits.strikes.max(1),
+            warned: false,
+        }
+    }
+
+    pub fn check(&mut self, now: Instant) -> Verdict {
+        let passed = match &self.ip {
+            Some((ip, buckets)) => buckets.take(*ip, &mut self.bucket, now),
+            None => self.bucket.take(now),
+        };
+        if passed {
+            self.strikes = self.strikes.saturating_sub(1);
+            self.warned = false;
+            return Verdict::Pass;
+        }
+        self.strikes += 1;
+        if self.strikes >= self.max_strikes {
+            self.strikes = 0;
+            return Verdict::Flooding;
+        }
+        let warn = !self.warned;
+        self.warned = true;
+        Verdict::Drop { warn }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits() -> LimitsConfig {
+        LimitsConfig {
+            msgs_per_sec: 1.0,
+            burst: 3,
+            ip_msgs_per_sec: 1.0,
+            ip_burst: 4,
+            strikes: 3,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn refill_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, 2.0, start);
+        assert!(bucket.take(start));
+        assert!(bucket.take(start));
+        assert!(!bucket.take(start));
+        assert!(bucket.take(start + Duration::from_millis(500)));
+        assert!(!bucket.take(start + Duration::from_millis(600)));
+        // never more than the capacity
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.take(later));
+        assert!(bucket.take(later));
+        assert!(!bucket.take(later));
+    }
+
+    #[test]
+    fn limit_connections() {
+        let limits = limits();
+        let now = Instant::now();
+        let mut limiter = Limiter::new(&limits, None);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(now), Verdict::Pass);
+        }
+        assert_eq!(limiter.check(now), Verdict::Drop { warn: true });
+        assert_eq!(limiter.check(now), Verdict::Drop { warn: false });
+        assert_eq!(limiter.check(now), Verdict::Flooding);
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check(later), Verdict::Pass);
+        assert_eq!(limiter.check(later), Verdict::Drop { warn: true });
+
+        // connections from an IP share its bucket
+        let ip = "10.0.0.7".parse().unwrap();
+        let buckets = Arc::new(IpBuckets::new(&limits));
+        let mut first = Limiter::new(&limits, Some((ip, buckets.clone())));
+        let mut second = Limiter::new(&limits, Some((ip, buckets.clone())));
+        let now = Instant::now();
+        assert_eq!(first.check(now), Verdict::Pass);
+        assert_eq!(first.check(now), Verdict::Pass);
+        assert_eq!(second.check(now), Verdict::Pass);
+        assert_eq!(second.check(now), Verdict::Pass);
+        assert_eq!(second.check(now), Verdict::Drop { warn: true });
+        let mut other = Limiter::new(&limits, Some(("10.0.0.8".parse().unwrap(), buckets)));
+        assert_eq!(other.check(now), Verdict::Pass);
+        // msgs dropped for the IP do not spend tokens of the connection
+        assert_eq!(first.check(now), Verdict::Drop { warn: true });
+        assert_eq!(first.bucket.tokens, 1.0);
+    }
+
+    #[test]
+    fn forget_full_buckets() {
+        let limits = limits();
+        let buckets = IpBuckets::new(&limits);
+        let now = Instant::now();
+        let mut own = TokenBucket::new(10, 1.0, now);
+        assert!(buckets.take("10.0.0.7".parse().unwrap(), &mut own, now));
+        assert!(buckets.take("10.0.0.8".parse().unwrap(), &mut own, now));
+        let later = now + Duration::from_secs(PRUNE_SECS);
+        assert!(buckets.take("10.0.0.9".parse().unwrap(), &mut own, later));
+        assert_eq!(buckets.buckets.lock().unwrap().0.len(), 1);
+    }
+}
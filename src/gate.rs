@@ -0,0 +1,325 @@
+use std::{
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    client::read_password,
+    config::{ClientConfig, Config, ServerConfig},
+    message::{Message, MessageType},
+    transport::ChatStream,
+};
+use log::error;
+
+/// Decides who may connect to server: everyone, or those knowing the password or holding an invite.
+///
+/// Invites are kept in a file, a token per line, and each one lets a client in only once.
+pub struct Gate {
+    password: Option<String>,
+    /// File of unused invites, `None` if invites are off
+    invites_path: Option<PathBuf>,
+    /// Max size of the answer in bytes, like other msgs
+    max_msg_size: usize,
+}
+impl Gate {
+    pub fn new(config: &ServerConfig) -> Result<Gate, Box<dyn Error>> {
+        let invites_path = match config.invites {
+            true => Some(config.invites_path().ok_or("Invites file is unknown.")?),
+            false => None,
+        };
+        Ok(Gate {
+            password: config
+                .password
+                .clone()
+                .filter(|password| !password.is_empty()),
+            invites_path,
+            max_msg_size: config.limits.max_msg_size,
+        })
+    }
+
+    /// What new clients are asked for, `None` if everyone may connect.
+    pub fn request(&self) -> Option<&'static str> {
+        match (&self.password, &self.invites_path) {
+            (Some(_), Some(_)) => Some("password or invite"),
+            (Some(_), None) => Some("password"),
+            (None, Some(_)) => Some("invite"),
+            (None, None) => None,
+        }
+    }
+
+    /// Check a password or take an invite, `Err` contains the reason to show to the client.
+    pub fn check(&self, secret: &str) -> Result<(), String> {
+        let request = match self.request() {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        if secret.is_empty() {
+            return Err(format!("This chamber is private, a {} is needed.", request));
+        }
+        if let Some(password) = &self.password {
+            // compare digests, so the time taken does not tell how much is right
+            if Sha256::digest(password) == Sha256::digest(secret) {
+                return Ok(());
+            }
+        }
+        if let Some(path) = &self.invites_path {
+            if take_invite(path, secret).map_err(|err| {
+                error!("Failed to read invites in {}: {}", path.display(), err);
+                "Invites can not be checked now.".to_string()
+            })? {
+                return Ok(());
+            }
+        }
+        Err(match request {
+            "password" => "Wrong password.",
+            "invite" => "Invalid or used invite.",
+            _ => "Wrong password, or invalid or used invite.",
+        }
+        .to_string())
+    }
+
+    /// Ask a new client for the password or an invite, `Err` if it is not let in.
+    ///
+    /// The answer is read from `reader` of the stream, which goes on reading msgs after this.
+    /// The client is told why it is rejected, and should be disconnected after that.
+    pub fn admit<R: BufRead>(&self, reader: &mut R, stream: &ChatStream) -> Result<(), String> {
+        let request = match self.request() {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        let ask = Message {
+            msg_type: MessageType::AuthRequest,
+            msg_sender: "server".to_string(),
+            msg_content: request.to_string(),
+            ..Default::default()
+        };
+        ask.write_to(&mut &*stream)
+            .map_err(|err| format!("Failed to ask for a {}: {}", request, err))?;
+        let answer = match Message::read_limited(reader, self.max_msg_size) {
+            Ok(Some(msg)) if matches!(msg.msg_type, MessageType::Auth) => msg.msg_content,
+            _ => return Err(format!("No {} is given.", request)),
+        };
+        let reply = match self.check(&answer) {
+            Ok(()) => Message {
+                msg_type: MessageType::Auth,
+                msg_sender: "server".to_string(),
+                msg_content: "Welcome.".to_string(),
+                ..Default::default()
+            },
+            Err(reason) => Message {
+                msg_type: MessageType::Error,
+                msg_sender: "server".to_string(),
+                msg_content: reason,
+                ..Default::default()
+            },
+        };
+        let _ = reply.write_to(&mut &*stream);
+        match reply.msg_type {
+            MessageType::Auth => Ok(()),
+            _ => Err(reply.msg_content),
+        }
+    }
+}
+
+/// Lock the invites file until the returned file is dropped.
+///
+/// Threads of new connections take invites, and `chamber invite` adds them, at the same time.
+fn lock_invites(path: &Path) -> io::Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.with_extension("lock"))?;
+    lock.lock()?;
+    Ok(lock)
+}
+
+/// Remove `token` from the invites file, return `false` if it is not there.
+///
+/// The file is replaced at once, so it is never left half written.
+fn take_invite(path: &Path, token: &str) -> io::Result<bool> {
+    let _lock = lock_invites(path)?;
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let mut found = false;
+    let rest: String = text
+        .lines()
+        .filter(|line| {
+            let matched = !found && line.trim() == token;
+            found |= matched;
+            !matched
+        })
+        .map(|line| format!("{}\n", line))
+        .collect();
+    if found {
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, rest)?;
+        fs::rename(&temp, path)?;
+    }
+    Ok(found)
+}
+
+/// Make `count` invites and add them to the invites file.
+fn create_invites(path: &Path, count: usize) -> io::Result<Vec<String>> {
+    let _lock = lock_invites(path)?;
+    let tokens: Vec<String> = (0..count)
+        .map(|_| {
+            let mut bytes = [0_u8; 16];
+            OsRng.fill_bytes(&mut bytes);
+            URL_SAFE_NO_PAD.encode(bytes)
+        })
+        .collect();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    for token in &tokens {
+        writeln!(file, "{}", token)?;
+    }
+    Ok(tokens)
+}
+
+/// `chamber invite [count]`, make invites for the server and print them.
+pub fn gen_invites(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let count = match args.first() {
+        Some(count) => count.parse().map_err(|_| "Usage: chamber invite [count]")?,
+        None => 1,
+    };
+    let config = Config::load()?;
+    if !config.server.invites {
+        return Err("Invites are off, set invites = true in [server] first.".into());
+    }
+    let path = config
+        .server
+        .invites_path()
+        .ok_or("Invites file is unknown.")?;
+    let tokens = create_invites(&path, count)
+        .map_err(|err| format!("Failed to write {}: {}", path.display(), err))?;
+    for token in tokens {
+        println!("{}", token);
+    }
+    Ok(())
+}
+
+/// Answer server if it asks for its password or an invite right after connecting.
+///
+/// The answer is taken from config, or asked for on stdin. Return the first msg that is
+/// not part of this, or `Err` with the reason if server rejects you.
+pub fn knock<R: BufRead>(
+    reader: &mut R,
+    stream: &ChatStream,
+    config: &ClientConfig,
+) -> Result<Option<Message>, Box<dyn Error>> {
+    let ask = match Message::read_from(reader)? {
+        Some(msg) if matches!(msg.msg_type, MessageType::AuthRequest) => msg,
+        msg => return Ok(msg),
+    };
+    let answer = match config.password.as_ref().or(config.invite.as_ref()) {
+        Some(answer) => answer.clone(),
+        None => {
+            print!("This chamber is private, type the {}: ", ask.msg_content);
+            io::stdout().flush()?;
+            read_password()?
+        }
+    };
+    let msg = Message {
+        msg_type: MessageType::Auth,
+        msg_content: answer,
+        ..Default::default()
+    };
+    msg.write_to(&mut &*stream)?;
+    match Message::read_from(reader)? {
+        Some(msg) if matches!(msg.msg_type, MessageType::Auth) => Ok(None),
+        Some(msg) if matches!(msg.msg_type, MessageType::Error) => {
+            Err(format!("Server rejected you: {}", msg.msg_content).into())
+        }
+        _ => Err("Server closed the connection.".into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gate(password: Option<&str>, invites: bool, name: &str) -> Gate {
+        let path = std::env::temp_dir().join(format!("chamber-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = ServerConfig {
+            password: password.map(str::to_string),
+            invites,
+            invites_file: Some(path.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        Gate::new(&config).unwrap()
+    }
+
+    #[test]
+    fn check_password() {
+        let open = gate(None, false, "open");
+        assert_eq!(open.request(), None);
+        assert!(open.check("").is_ok());
+
+        let gate = gate(Some("open sesame"), false, "password");
+        assert_eq!(gate.request(), Some("password"));
+        assert!(gate.check("open sesame").is_ok());
+        assert!(gate.check("open sesame").is_ok());
+        assert_eq!(gate.check("open"), Err("Wrong password.".to_string()));
+        assert!(gate.check("").unwrap_err().contains("private"));
+    }
+
+    #[test]
+    fn take_invites_once() {
+        let gate = gate(Some("open sesame"), true, "invites");
+        assert_eq!(gate.request(), Some("password or invite"));
+        let path = gate.invites_path.clone().unwrap();
+        assert!(gate.check("nope").is_err());
+        let tokens = create_invites(&path, 2).unwrap();
+        assert_eq!(tokens[0].len(), 22);
+        assert_ne!(tokens[0], tokens[1]);
+        assert!(gate.check(&tokens[1]).is_ok());
+        assert!(gate.check(&tokens[1]).is_err());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", tokens[0])
+        );
+        assert!(gate.check(&tokens[0]).is_ok());
+        assert!(gate.check("open sesame").is_ok());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("lock")).unwrap();
+    }
+
+    #[test]
+    fn share_invites() {
+        let gate = std::sync::Arc::new(gate(None, true, "shared-invites"));
+        let path = gate.invites_path.clone().unwrap();
+        let tokens = create_invites(&path, 20).unwrap();
+        let threads: Vec<_> = tokens
+            .chunks(5)
+            .map(|chunk| {
+                let (gate, path, chunk) = (gate.clone(), path.clone(), chunk.to_vec());
+                std::thread::spawn(move || {
+                    create_invites(&path, 10).unwrap();
+                    chunk.iter().all(|token| gate.check(token).is_ok())
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap());
+        }
+        // no invite added is lost, and none taken is left
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 40);
+        assert!(tokens.iter().all(|token| !text.contains(token.as_str())));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("lock")).unwrap();
+    }
+}
@@ -0,0 +1,644 @@
+use std::fmt::Display;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+use crate::config::KeysConfig;
+
+/// Things a key can do in the TUI, decoupled from the keys themselves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// Send msg in editor, or choose the selected item in a list
+    Send,
+    /// Leave the focused block back to editor
+    Cancel,
+    Quit,
+    FocusNext,
+    FocusPrev,
+    NextRoom,
+    PrevRoom,
+    /// Move cursor or selection up, scroll msgs by a line in msg window
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    /// Go to start of input, or the oldest msg in msg window
+    MoveHome,
+    MoveEnd,
+    /// Scroll msgs by a page
+    PageUp,
+    PageDown,
+    DeleteBackward,
+    DeleteForward,
+    ClearInput,
+    /// Complete command or nickname in editor, or focus next block
+    Complete,
+    OpenPalette,
+    ShowHelp,
+    /// Reply to the selected msg in msg window
+    Reply,
+    /// Select the msg quoted by the selected reply
+    JumpToParent,
+    /// Search msgs displayed in msg window
+    Search,
+    /// Go to the older match of the search
+    NextMatch,
+    /// Go to the newer match of the search
+    PrevMatch,
+}
+impl Action {
+    pub const ALL: [Action; 26] = [
+        Action::Send,
+        Action::Cancel,
+        Action::Quit,
+        Action::FocusNext,
+        Action::FocusPrev,
+        Action::NextRoom,
+        Action::PrevRoom,
+        Action::MoveUp,
+        Action::MoveDown,
+        Action::MoveLeft,
+        Action::MoveRight,
+        Action::MoveHome,
+        Action::MoveEnd,
+        Action::PageUp,
+        Action::PageDown,
+        Action::DeleteBackward,
+        Action::DeleteForward,
+        Action::ClearInput,
+        Action::Complete,
+        Action::OpenPalette,
+        Action::ShowHelp,
+        Action::Reply,
+        Action::JumpToParent,
+        Action::Search,
+        Action::NextMatch,
+        Action::PrevMatch,
+    ];
+
+    /// Name used in config file
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Send => "send",
+            Action::Cancel => "cancel",
+            Action::Quit => "quit",
+            Action::FocusNext => "focus-next",
+            Action::FocusPrev => "focus-prev",
+            Action::NextRoom => "next-room",
+            Action::PrevRoom => "prev-room",
+            Action::MoveUp => "move-up",
+            Action::MoveDown => "move-down",
+            Action::MoveLeft => "move-left",
+            Action::MoveRight => "move-right",
+            Action::MoveHome => "move-home",
+            Action::MoveEnd => "move-end",
+            Action::PageUp => "page-up",
+            Action::PageDown => "page-down",
+            Action::DeleteBackward => "delete-backward",
+            Action::DeleteForward => "delete-forward",
+            Action::ClearInput => "clear-input",
+            Action::Complete => "complete",
+            Action::OpenPalette => "open-palette",
+            Action::ShowHelp => "show-help",
+            Action::Reply => "reply",
+            Action::JumpToParent => "jump-to-parent",
+            Action::Search => "search",
+            Action::NextMatch => "next-match",
+            Action::PrevMatch => "prev-match",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Action::Send => "Send msg, or choose the selected item",
+            Action::Cancel => "Close popup, or go back to editor",
+            Action::Quit => "Quit",
+            Action::FocusNext => "Focus next block",
+            Action::FocusPrev => "Focus previous block",
+            Action::NextRoom => "Next room",
+            Action::PrevRoom => "Previous room",
+            Action::MoveUp => "Move up, or scroll msgs by a line",
+            Action::MoveDown => "Move down, or scroll msgs by a line",
+            Action::MoveLeft => "Move cursor left",
+            Action::MoveRight => "Move cursor right",
+            Action::MoveHome => "Go to start of input, or the oldest msg",
+            Action::MoveEnd => "Go to end of input, or the newest msg",
+            Action::PageUp => "Scroll msgs up by a page",
+            Action::PageDown => "Scroll msgs down by a page",
+            Action::DeleteBackward => "Delete char before cursor",
+            Action::DeleteForward => "Delete char after cursor",
+            Action::ClearInput => "Clear editor",
+            Action::Complete => "Complete command or nickname, or focus next block",
+            Action::OpenPalette => "Open command palette",
+            Action::ShowHelp => "Show keybindings",
+            Action::Reply => "Reply to the selected msg",
+            Action::JumpToParent => "Jump to the msg quoted by the selected reply",
+            Action::Search => "Search msgs in current room",
+            Action::NextMatch => "Go to the older match of the search",
+            Action::PrevMatch => "Go to the newer match of the search",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        Action::ALL.into_iter().find(|action| action.name() == name)
+    }
+}
+
+/// Where a keybinding works, bindings of the focused block are checked before global ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyScope {
+    Global,
+    Editor,
+    /// Msg window, room list and client list
+    Browse,
+}
+impl Display for KeyScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyScope::Global => write!(f, "Global"),
+            KeyScope::Editor => write!(f, "Editor"),
+            KeyScope::Browse => write!(f, "Browse"),
+        }
+    }
+}
+
+/// A key with modifiers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyBinding {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+impl From<KeyEvent> for KeyBinding {
+    /// `Shift` is dropped from chars and `BackTab`, it is already a part of the key.
+    fn from(key: KeyEvent) -> KeyBinding {
+        let modifiers = match key.code {
+            KeyCode::Char(_) | KeyCode::BackTab => key.modifiers - KeyModifiers::SHIFT,
+            _ => key.modifiers,
+        };
+        KeyBinding {
+            code: key.code,
+            modifiers,
+        }
+    }
+}
+impl Display for KeyBinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            write!(f, "Ctrl+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            write!(f, "Alt+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::SHIFT) {
+            write!(f, "Shift+")?;
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(ch) => write!(f, "{}", ch),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            KeyCode::BackTab => write!(f, "Shift+Tab"),
+            KeyCode::PageUp => write!(f, "PageUp"),
+            KeyCode::PageDown => write!(f, "PageDown"),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+impl KeyBinding {
+    pub fn new(code: KeyCode, modifiers: KeyModifiers) -> KeyBinding {
+        KeyBinding { code, modifiers }
+    }
+
+    /// Parse keys like `ctrl-p`, `Alt+Left`, `shift-tab`, `f1` or `G`.
+    pub fn parse(key_str: &str) -> Option<KeyBinding> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = key_str;
+        loop {
+            let lower = rest.to_lowercase();
+            let stripped = ["ctrl", "alt", "shift"].iter().find_map(|name| {
+                let after = lower.strip_prefix(name)?;
+                (after.starts_with('-') || after.starts_with('+')).then_some(*name)
+            });
+            match stripped {
+                Some(name) => {
+                    modifiers |= match name {
+                        "ctrl" => KeyModifiers::CONTROL,
+                        "alt" => KeyModifiers::ALT,
+                        _ => KeyModifiers::SHIFT,
+                    };
+                    rest = &rest[name.len() + 1..];
+                }
+                None => break,
+            }
+        }
+        let code = match rest.to_lowercase().as_str() {
+            "enter" => KeyCode::Enter,
+            "esc" | "escape" => KeyCode::Esc,
+            "tab" if modifiers.contains(KeyModifiers::SHIFT) => {
+                modifiers -= KeyModifiers::SHIFT;
+                KeyCode::BackTab
+            }
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "insert" => KeyCode::Insert,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "space" => KeyCode::Char(' '),
+            lower => {
+                let mut chars = rest.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(ch), None) => {
+                        // `Ctrl+P` is the same as `Ctrl+p`, while `G` is not `g`
+                        if modifiers.is_empty() {
+                            KeyCode::Char(ch)
+                        } else {
+                            KeyCode::Char(ch.to_ascii_lowercase())
+                        }
+                    }
+                    _ => {
+                        let n = lower.strip_prefix('f')?.parse::<u8>().ok()?;
+                        KeyCode::F(n)
+                    }
+                }
+            }
+        };
+        Some(KeyBinding { code, modifiers })
+    }
+}
+
+/// A key bound to an action in a scope
+pub struct Binding {
+    pub scope: KeyScope,
+    pub key: KeyBinding,
+    pub action: Action,
+}
+
+/// Keys moving focus in every block, they can not be bound to other actions,
+/// so focus can always leave editor whatever is typed.
+const FOCUS_KEYS: [(KeyCode, Action); 2] = [
+    (KeyCode::F(6), Action::FocusNext),
+    (KeyCode::BackTab, Action::FocusPrev),
+];
+
+/// Keybindings of the TUI.
+pub struct Keymap {
+    bindings: Vec<Binding>,
+}
+impl Default for Keymap {
+    fn default() -> Keymap {
+        let mut keymap = Keymap { bindings: vec![] };
+        use Action::*;
+        for (key, action) in [
+            (KeyCode::Enter, Send),
+            (KeyCode::Esc, Cancel),
+            (KeyCode::Tab, Complete),
+            (KeyCode::F(1), ShowHelp),
+            (KeyCode::Up, MoveUp),
+            (KeyCode::Down, MoveDown),
+            (KeyCode::Left, MoveLeft),
+            (KeyCode::Right, MoveRight),
+            (KeyCode::Home, MoveHome),
+            (KeyCode::End, MoveEnd),
+            (KeyCode::PageUp, PageUp),
+            (KeyCode::PageDown, PageDown),
+            (KeyCode::Backspace, DeleteBackward),
+            (KeyCode::Delete, DeleteForward),
+        ] {
+            keymap.bind(
+                KeyScope::Global,
+                KeyBinding::new(key, KeyModifiers::NONE),
+                action,
+            );
+        }
+        for (key, modifiers, action) in [
+            (KeyCode::Char('c'), KeyModifiers::CONTROL, Quit),
+            (KeyCode::Char('q'), KeyModifiers::CONTROL, Quit),
+            (KeyCode::Char('p'), KeyModifiers::CONTROL, OpenPalette),
+            (KeyCode::Left, KeyModifiers::ALT, PrevRoom),
+            (KeyCode::Right, KeyModifiers::ALT, NextRoom),
+        ] {
+            keymap.bind(KeyScope::Global, KeyBinding::new(key, modifiers), action);
+        }
+        for (key, action) in FOCUS_KEYS {
+            keymap.bind(
+                KeyScope::Global,
+                KeyBinding::new(key, KeyModifiers::NONE),
+                action,
+            );
+        }
+        for (ch, action) in [
+            ('r', Reply),
+            ('p', JumpToParent),
+            ('/', Search),
+            ('n', NextMatch),
+            ('N', PrevMatch),
+        ] {
+            keymap.bind(
+                KeyScope::Browse,
+                KeyBinding::new(KeyCode::Char(ch), KeyModifiers::NONE),
+                action,
+            );
+        }
+        keymap
+    }
+}
+impl Keymap {
+    /// Build keymap from config: start from a preset, then apply user's bindings.
+    pub fn from_config(config: &KeysConfig) -> Result<Keymap, String> {
+        let mut keymap = Keymap::default();
+        match config.preset.as_deref() {
+            None | Some("default") => {}
+            Some("emacs") => keymap.apply_emacs_preset(),
+            Some("vi") => keymap.apply_vi_preset(),
+            Some(preset) => return Err(format!("Unknown keymap preset \"{}\".", preset)),
+        }
+        for (scope, bindings) in [
+            (KeyScope::Global, &config.global),
+            (KeyScope::Editor, &config.editor),
+            (KeyScope::Browse, &config.browse),
+        ] {
+            for (key_str, action_name) in bindings {
+                let key = KeyBinding::parse(key_str)
+                    .ok_or_else(|| format!("Unknown key \"{}\".", key_str))?;
+                if key.modifiers.is_empty() && FOCUS_KEYS.iter().any(|(code, _)| *code == key.code)
+                {
+                    return Err(format!("{} is kept for moving focus.", key));
+                }
+                if action_name == "none" {
+                    keymap.unbind(scope, key);
+                    continue;
+                }
+                let action = Action::from_name(action_name)
+                    .ok_or_else(|| format!("Unknown action \"{}\".", action_name))?;
+                keymap.bind(scope, key, action);
+            }
+        }
+        Ok(keymap)
+    }
+
+    /// Bind a key to an action, the old action of this key in the scope is replaced.
+    pub fn bind(&mut self, scope: KeyScope, key: KeyBinding, action: Action) {
+        self.unbind(scope, key);
+        self.bindings.push(Binding { scope, key, action });
+    }
+
+    pub fn unbind(&mut self, scope: KeyScope, key: KeyBinding) {
+        self.bindings
+            .retain(|binding| binding.scope != scope || binding.key != key);
+    }
+
+    /// Get the action of a key pressed in `scope`.
+    pub fn action(&self, scope: KeyScope, key: KeyEvent) -> Option<Action> {
+        let key = KeyBinding::from(key);
+        let find = |scope: KeyScope| {
+            self.bindings
+                .iter()
+                .find(|binding| binding.scope == scope && binding.key == key)
+                .map(|binding| binding.action)
+        };
+        find(scope).or_else(|| find(KeyScope::Global))
+    }
+
+    /// Get all keys bound to `action`, like `Ctrl+c, Ctrl+q`.
+    pub fn keys_for(&self, action: Action) -> String {
+        self.bindings
+            .iter()
+            .filter(|binding| binding.action == action)
+            .map(|binding| binding.key.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    pub fn bindings(&self) -> &[Binding] {
+        &self.bindings
+    }
+
+    /// Readline style keys in editor.
+    fn apply_emacs_preset(&mut self) {
+        use Action::*;
+        for (ch, action) in [
+            ('a', MoveHome),
+            ('e', MoveEnd),
+            ('b', MoveLeft),
+            ('f', MoveRight),
+            ('p', MoveUp),
+            ('n', MoveDown),
+            ('h', DeleteBackward),
+            ('d', DeleteForward),
+            ('u', ClearInput),
+            ('g', Cancel),
+        ] {
+            self.bind(
+                KeyScope::Editor,
+                KeyBinding::new(KeyCode::Char(ch), KeyModifiers::CONTROL),
+                action,
+            );
+        }
+        for (ch, action) in [('x', OpenPalette), ('p', PrevRoom), ('n', NextRoom)] {
+            self.bind(
+                KeyScope::Global,
+                KeyBinding::new(KeyCode::Char(ch), KeyModifiers::ALT),
+                action,
+            );
+        }
+    }
+
+    /// Vi style keys: `Esc` leaves editor, then `hjkl` and friends work in other blocks.
+    fn apply_vi_preset(&mut self) {
+        use Action::*;
+        self.bind(
+            KeyScope::Editor,
+            KeyBinding::new(KeyCode::Esc, KeyModifiers::NONE),
+            FocusNext,
+        );
+        for (ch, action) in [
+            ('j', MoveDown),
+            ('k', MoveUp),
+            ('h', PrevRoom),
+            ('l', NextRoom),
+            ('g', MoveHome),
+            ('G', MoveEnd),
+            ('i', Cancel),
+            (':', OpenPalette),
+            ('?', ShowHelp),
+        ] {
+            self.bind(
+                KeyScope::Browse,
+                KeyBinding::new(KeyCode::Char(ch), KeyModifiers::NONE),
+                action,
+            );
+        }
+        for (ch, action) in [('d', PageDown), ('u', PageUp)] {
+            self.bind(
+                KeyScope::Browse,
+                KeyBinding::new(KeyCode::Char(ch), KeyModifiers::CONTROL),
+                action,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn parse_keys() {
+        assert_eq!(
+            KeyBinding::parse("ctrl-p"),
+            Some(KeyBinding::new(KeyCode::Char('p'), KeyModifiers::CONTROL))
+        );
+        assert_eq!(
+            KeyBinding::parse("Ctrl+Alt+Left"),
+            Some(KeyBinding::new(
+                KeyCode::Left,
+                KeyModifiers::CONTROL | KeyModifiers::ALT
+            ))
+        );
+        assert_eq!(
+            KeyBinding::parse("shift-tab"),
+            Some(KeyBinding::new(KeyCode::BackTab, KeyModifiers::NONE))
+        );
+        assert_eq!(
+            KeyBinding::parse("F12"),
+            Some(KeyBinding::new(KeyCode::F(12), KeyModifiers::NONE))
+        );
+        assert_eq!(
+            KeyBinding::parse("G"),
+            Some(KeyBinding::new(KeyCode::Char('G'), KeyModifiers::NONE))
+        );
+        assert_eq!(KeyBinding::parse("hyper-x"), None);
+        assert_eq!(KeyBinding::parse("ctrl-"), None);
+    }
+
+    #[test]
+    fn display_keys() {
+        for key_str in ["Ctrl+p", "Alt+Left", "Shift+Tab", "F1", "Enter", "G"] {
+            assert_eq!(KeyBinding::parse(key_str).unwrap().to_string(), key_str);
+        }
+    }
+
+    #[test]
+    fn default_keymap() {
+        let keymap = Keymap::default();
+        assert_eq!(
+            keymap.action(KeyScope::Editor, key(KeyCode::Esc, KeyModifiers::NONE)),
+            Some(Action::Cancel)
+        );
+        assert_eq!(
+            keymap.action(
+                KeyScope::Browse,
+                key(KeyCode::Char('c'), KeyModifiers::CONTROL)
+            ),
+            Some(Action::Quit)
+        );
+        assert_eq!(
+            keymap.action(
+                KeyScope::Editor,
+                key(KeyCode::Char('j'), KeyModifiers::NONE)
+            ),
+            None
+        );
+        // shift is a part of `BackTab`
+        assert_eq!(
+            keymap.action(KeyScope::Editor, key(KeyCode::BackTab, KeyModifiers::SHIFT)),
+            Some(Action::FocusPrev)
+        );
+        assert_eq!(keymap.keys_for(Action::Quit), "Ctrl+c, Ctrl+q");
+    }
+
+    #[test]
+    fn presets_and_overrides() {
+        let mut config = KeysConfig {
+            preset: Some("vi".to_string()),
+            ..Default::default()
+        };
+        config.browse.insert("q".to_string(), "quit".to_string());
+        config
+            .global
+            .insert("ctrl-c".to_string(), "none".to_string());
+        let keymap = Keymap::from_config(&config).unwrap();
+        assert_eq!(
+            keymap.action(
+                KeyScope::Browse,
+                key(KeyCode::Char('G'), KeyModifiers::SHIFT)
+            ),
+            Some(Action::MoveEnd)
+        );
+        assert_eq!(
+            keymap.action(
+                KeyScope::Editor,
+                key(KeyCode::Char('j'), KeyModifiers::NONE)
+            ),
+            None
+        );
+        assert_eq!(
+            keymap.action(
+                KeyScope::Browse,
+                key(KeyCode::Char('q'), KeyModifiers::NONE)
+            ),
+            Some(Action::Quit)
+        );
+        assert_eq!(
+            keymap.action(
+                KeyScope::Browse,
+                key(KeyCode::Char('c'), KeyModifiers::CONTROL)
+            ),
+            None
+        );
+
+        let emacs = Keymap::from_config(&KeysConfig {
+            preset: Some("emacs".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            emacs.action(
+                KeyScope::Editor,
+                key(KeyCode::Char('a'), KeyModifiers::CONTROL)
+            ),
+            Some(Action::MoveHome)
+        );
+        // palette is still there out of editor
+        assert_eq!(
+            emacs.action(
+                KeyScope::Browse,
+                key(KeyCode::Char('p'), KeyModifiers::CONTROL)
+            ),
+            Some(Action::OpenPalette)
+        );
+    }
+
+    #[test]
+    fn bad_config() {
+        let mut config = KeysConfig::default();
+        config
+            .editor
+            .insert("ctrl-k".to_string(), "fly".to_string());
+        assert!(Keymap::from_config(&config).is_err());
+
+        let config = KeysConfig {
+            preset: Some("nano".to_string()),
+            ..Default::default()
+        };
+        assert!(Keymap::from_config(&config).is_err());
+
+        for key_str in ["f6", "shift-tab"] {
+            let mut config = KeysConfig::default();
+            config
+                .editor
+                .insert(key_str.to_string(), "none".to_string());
+            assert!(Keymap::from_config(&config).is_err());
+        }
+    }
+}
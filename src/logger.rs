@@ -0,0 +1,224 @@
+use std::{
+    env,
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Local, SecondsFormat};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::config::LoggingConfig;
+
+/// Overrides `level` in `[logging]` of config, like `RUST_LOG`
+const LOG_ENV: &str = "CHAMBER_LOG";
+
+/// Levels of modules, like `info,server=debug,rustls=warn`.
+///
+/// Modules of chamber are named without the `chamber::` prefix, the longest match wins.
+#[derive(Debug, PartialEq)]
+pub struct Filter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+impl Filter {
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        let module = target.strip_prefix("chamber::").unwrap_or(target);
+        self.modules
+            .iter()
+            .filter(|(name, _)| {
+                module == name
+                    || module
+                        .strip_prefix(name.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    /// The most verbose level of any module.
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Filter, String> {
+        let mut filter = Filter {
+            default: LevelFilter::Info,
+            modules: vec![],
+        };
+        for directive in text.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let parse_level = |level: &str| {
+                level
+                    .parse::<LevelFilter>()
+                    .map_err(|_| format!("Unknown log level: {}", level))
+            };
+            match directive.split_once('=') {
+                Some((module, level)) => filter
+                    .modules
+                    .push((module.trim().to_string(), parse_level(level.trim())?)),
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+/// Format a record as a line of text, or a JSON object if `json` is `true`.
+fn format_line(json: bool, time: DateTime<Local>, level: Level, module: &str, msg: &str) -> String {
+    let module = module.strip_prefix("chamber::").unwrap_or(module);
+    match json {
+        true => serde_json::json!({
+            "time": time.to_rfc3339_opts(SecondsFormat::Millis, false),
+            "level": level.as_str(),
+            "module": module,
+            "msg": msg,
+        })
+        .to_string(),
+        false => format!(
+            "{} {:<5} {}: {}",
+            time.format("%Y-%m-%d %H:%M:%S%.3f"),
+            level,
+            module,
+            msg
+        ),
+    }
+}
+
+/// Writes records to stderr, a file or both.
+struct Logger {
+    filter: Filter,
+    json: bool,
+    stderr: bool,
+    file: Option<Mutex<File>>,
+}
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_line(
+            self.json,
+            Local::now(),
+            record.level(),
+            record.target(),
+            &record.args().to_string(),
+        );
+        if self.stderr {
+            eprintln!("{}", line);
+        }
+        if let Some(file) = &self.file {
+            let _ = writeln!(file.lock().unwrap(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Start logging by `[logging]` in config, to stderr if `stderr` is `true`, and to `file`.
+///
+/// The TUI logs only to a file, as anything printed breaks its screen.
+pub fn init(
+    config: &LoggingConfig,
+    stderr: bool,
+    file: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let filter = match env::var(LOG_ENV) {
+        Ok(level) => level.parse()?,
+        Err(_) => config.level.parse()?,
+    };
+    let file = match file {
+        Some(path) => {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+            Some(Mutex::new(file))
+        }
+        None => None,
+    };
+    let logger = Logger {
+        filter,
+        json: config.json,
+        stderr,
+        file,
+    };
+    log::set_max_level(logger.filter.max_level());
+    // only fails if a logger is set already, then records keep going there
+    let _ = log::set_logger(Box::leak(Box::new(logger)));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn filter_modules() {
+        let filter: Filter = "warn, server=debug, server::flood=trace, rustls=off"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.level_for("chamber::gate"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("chamber::server"), LevelFilter::Debug);
+        assert_eq!(
+            filter.level_for("chamber::server::flood"),
+            LevelFilter::Trace
+        );
+        assert_eq!(filter.level_for("chamber::serverless"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("rustls::conn"), LevelFilter::Off);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+        assert_eq!(
+            "".parse::<Filter>().unwrap().level_for("chamber::server"),
+            LevelFilter::Info
+        );
+        assert!("loud".parse::<Filter>().is_err());
+        assert!("server=loud".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn format_lines() {
+        let time = Local.with_ymd_and_hms(2024, 5, 1, 12, 30, 5).unwrap();
+        let text = format_line(
+            false,
+            time,
+            Level::Info,
+            "chamber::server",
+            "Client 1 is online.",
+        );
+        assert_eq!(
+            text,
+            "2024-05-01 12:30:05.000 INFO  server: Client 1 is online."
+        );
+        let json = format_line(true, time, Level::Warn, "chamber::gate", "say \"hi\"");
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["level"], "WARN");
+        assert_eq!(value["module"], "gate");
+        assert_eq!(value["msg"], "say \"hi\"");
+        assert!(value["time"]
+            .as_str()
+            .unwrap()
+            .starts_with("2024-05-01T12:30:05.000"));
+    }
+}
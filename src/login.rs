@@ -0,0 +1,129 @@
+use crate::{
+    accounts::MIN_PASSWORD_LEN,
+    message::{is_valid_nickname, Message},
+};
+
+/// Field of the login form being typed in.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum LoginField {
+    #[default]
+    Nick,
+    Password,
+}
+
+/// State of the login screen, shown before chatting when server asks for it, or by `/login`.
+#[derive(Default)]
+pub struct LoginForm {
+    pub nick: String,
+    pub password: String,
+    pub field: LoginField,
+    /// Register a new account instead of logging in
+    pub register: bool,
+    /// Server does not let you chat before logging in, so the form can not be skipped
+    pub required: bool,
+    /// Why the last try failed, from server or the form itself
+    pub error: Option<String>,
+}
+impl LoginForm {
+    pub fn new(nick: &str, register: bool, required: bool) -> LoginForm {
+        LoginForm {
+            nick: nick.to_string(),
+            // go to the password if the nickname is given
+            field: match nick.is_empty() {
+                true => LoginField::Nick,
+                false => LoginField::Password,
+            },
+            register,
+            required,
+            ..Default::default()
+        }
+    }
+
+    /// Move to the other field.
+    pub fn switch_field(&mut self) {
+        self.field = match self.field {
+            LoginField::Nick => LoginField::Password,
+            LoginField::Password => LoginField::Nick,
+        };
+    }
+
+    pub fn push(&mut self, ch: char) {
+        match self.field {
+            LoginField::Nick => self.nick.push(ch),
+            LoginField::Password => self.password.push(ch),
+        }
+    }
+
+    pub fn pop(&mut self) {
+        match self.field {
+            LoginField::Nick => self.nick.pop(),
+            LoginField::Password => self.password.pop(),
+        };
+    }
+
+    /// Password shown as `*`s.
+    pub fn masked_password(&self) -> String {
+        "*".repeat(self.password.chars().count())
+    }
+
+    /// Build the msg to send, or `Err` with what is wrong with the form.
+    ///
+    /// The password is cleared, so it is not kept after being sent.
+    pub fn submit(&mut self) -> Result<Message, String> {
+        if !is_valid_nickname(&self.nick) {
+            self.field = LoginField::Nick;
+            return Err("Nicknames contain only letters, digits, - and _.".to_string());
+        }
+        if self.password.is_empty() {
+            self.field = LoginField::Password;
+            return Err("Type your password.".to_string());
+        }
+        if self.register && self.password.chars().count() < MIN_PASSWORD_LEN {
+            self.field = LoginField::Password;
+            return Err(format!(
+                "Passwords have at least {} chars.",
+                MIN_PASSWORD_LEN
+            ));
+        }
+        let msg = Message::login(&self.nick, &self.password, self.register);
+        self.password.clear();
+        self.error = None;
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::MessageType;
+
+    #[test]
+    fn fill_and_submit() {
+        let mut form = LoginForm::new("", false, true);
+        assert_eq!(form.field, LoginField::Nick);
+        "alicee".chars().for_each(|ch| form.push(ch));
+        form.pop();
+        assert!(form.submit().is_err());
+        assert_eq!(form.field, LoginField::Password);
+        form.switch_field();
+        assert_eq!(form.field, LoginField::Nick);
+        form.switch_field();
+        "secret".chars().for_each(|ch| form.push(ch));
+        assert_eq!(form.masked_password(), "******");
+
+        form.register = true;
+        assert!(matches!(form.submit(), Err(reason) if reason.contains("at least")));
+        form.register = false;
+        let msg = form.submit().unwrap();
+        assert!(matches!(msg.msg_type, MessageType::Login));
+        assert_eq!(msg.msg_target, "alice");
+        assert_eq!(msg.msg_content, "secret");
+        assert!(form.password.is_empty());
+
+        let mut form = LoginForm::new("42", true, false);
+        assert_eq!(form.field, LoginField::Password);
+        form.push('x');
+        assert!(form.submit().is_err());
+        assert_eq!(form.field, LoginField::Nick);
+    }
+}
@@ -1,9 +1,28 @@
 mod client;
 mod client_ui;
+mod command;
+mod keymap;
 mod server;
 
+mod access;
+mod accounts;
+mod admin;
+mod chat_log;
+mod config;
 mod consts;
+mod e2e;
+mod flood;
+mod gate;
+mod logger;
+mod login;
+mod markup;
 mod message;
+mod metrics;
+mod moderation;
+mod palette;
+mod search;
+mod theme;
+mod transport;
 mod utils;
 
 mod paragraph_chamber;
@@ -13,13 +32,36 @@ use std::env;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.contains(&String::from(consts::ARG_CLIENT)) {
+    // args after `logs` are a query, which may look like other subcommands
+    if args.get(1).map(String::as_str) == Some(consts::ARG_LOGS) {
+        if let Err(err) = chat_log::print_logs(&args[2..]) {
+            eprintln!("{}", err);
+        }
+    } else if args.get(1).map(String::as_str) == Some(consts::ARG_CERT) {
+        if let Err(err) = transport::gen_cert(&args[2..]) {
+            eprintln!("{}", err);
+        }
+    } else if args.get(1).map(String::as_str) == Some(consts::ARG_INVITE) {
+        if let Err(err) = gate::gen_invites(&args[2..]) {
+            eprintln!("{}", err);
+        }
+    } else if args.get(1).map(String::as_str) == Some(consts::ARG_ADMIN) {
+        if let Err(err) = admin::run(&args[2..]) {
+            eprintln!("{}", err);
+        }
+    } else if args.contains(&String::from(consts::ARG_CLIENT)) {
         println!("Start client!");
-        let _res = client::start();
+        if let Err(err) = client::start() {
+            eprintln!("{}", err);
+        }
     } else if args.contains(&String::from(consts::ARG_SERVER)) {
         println!("Start server!");
-        let _res = server::start();
+        if let Err(err) = server::start() {
+            eprintln!("{}", err);
+        }
     } else if args.contains(&"ui".to_string()) {
-        let _res = client_ui::ui_init();
+        if let Err(err) = client_ui::ui_init() {
+            eprintln!("{}", err);
+        }
     }
 }
@@ -0,0 +1,394 @@
+use tui::{
+    style::{Modifier, Style},
+    text::Span,
+};
+
+use crate::theme::Theme;
+
+/// Chars that can be escaped by a backslash, like `\*` for a literal `*`.
+const ESCAPABLE: &str = "\\*_`~[]()";
+
+/// Formats of a piece of text in msg content.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Markup {
+    /// `**bold**`
+    pub bold: bool,
+    /// `*italic*` or `_italic_`
+    pub italic: bool,
+    /// `~~strikethrough~~`
+    pub strikethrough: bool,
+    /// `` `code` ``
+    pub code: bool,
+    /// `[text](url)` or a bare `https://...`
+    pub link: bool,
+    /// `@nick`
+    pub mention: bool,
+}
+
+/// A piece of text with the same formats.
+#[derive(Debug, PartialEq)]
+pub struct Fragment {
+    pub text: String,
+    pub markup: Markup,
+}
+
+/// Parse markdown-lite formats in msg content.
+///
+/// Markers without a matching closing marker are kept as they are,
+/// and a backslash turns a marker into a literal char.
+/// Nothing is formatted inside code and links.
+pub fn parse(content: &str) -> Vec<Fragment> {
+    let chars: Vec<char> = content.chars().collect();
+    let mut fragments: Vec<Fragment> = vec![];
+    let mut text = String::new();
+    let mut markup = Markup::default();
+    let mut index = 0;
+    while index < chars.len() {
+        let ch = chars[index];
+        if ch == '\\' && index + 1 < chars.len() && ESCAPABLE.contains(chars[index + 1]) {
+            text.push(chars[index + 1]);
+            index += 2;
+            continue;
+        }
+        if ch == '`' {
+            if let Some(end) = (index + 1..chars.len()).find(|i| chars[*i] == '`') {
+                if end > index + 1 {
+                    push_fragment(&mut fragments, &mut text, markup);
+                    text.extend(&chars[index + 1..end]);
+                    let code = Markup {
+                        code: true,
+                        ..markup
+                    };
+                    push_fragment(&mut fragments, &mut text, code);
+                    index = end + 1;
+                    continue;
+                }
+            }
+        }
+        if ch == '[' {
+            if let Some((link_text, url, end)) = parse_link(&chars, index) {
+                push_fragment(&mut fragments, &mut text, markup);
+                text.push_str(&link_text);
+                let link = Markup {
+                    link: true,
+                    ..markup
+                };
+                push_fragment(&mut fragments, &mut text, link);
+                if link_text != url {
+                    text.push_str(&format!(" ({})", url));
+                }
+                index = end;
+                continue;
+            }
+        }
+        if is_word_start(&chars, index) {
+            if let Some(end) = find_url_end(&chars, index) {
+                push_fragment(&mut fragments, &mut text, markup);
+                text.extend(&chars[index..end]);
+                let link = Markup {
+                    link: true,
+                    ..markup
+                };
+                push_fragment(&mut fragments, &mut text, link);
+                index = end;
+                continue;
+            }
+        }
+        if ch == '@' && (index == 0 || !is_nickname_char(chars[index - 1])) {
+            let end = (index + 1..chars.len())
+                .find(|i| !is_nickname_char(chars[*i]))
+                .unwrap_or(chars.len());
+            if end > index + 1 {
+                push_fragment(&mut fragments, &mut text, markup);
+                text.extend(&chars[index..end]);
+                let mention = Markup {
+                    mention: true,
+                    ..markup
+                };
+                push_fragment(&mut fragments, &mut text, mention);
+                index = end;
+                continue;
+            }
+        }
+        // the longer marker comes first, so `**` is not taken as two `*`
+        let toggled = [("**", markup.bold), ("~~", markup.strikethrough)]
+            .into_iter()
+            .chain([("*", markup.italic), ("_", markup.italic)])
+            .find(|(marker, active)| {
+                let marker: Vec<char> = marker.chars().collect();
+                if *active {
+                    is_closing_marker(&chars, index, &marker)
+                } else {
+                    is_opening_marker(&chars, index, &marker)
+                        && (index + marker.len()..chars.len())
+                            .any(|i| is_closing_marker(&chars, i, &marker))
+                }
+            });
+        if let Some((marker, _)) = toggled {
+            push_fragment(&mut fragments, &mut text, markup);
+            match marker {
+                "**" => markup.bold = !markup.bold,
+                "~~" => markup.strikethrough = !markup.strikethrough,
+                _ => markup.italic = !markup.italic,
+            }
+            index += marker.len();
+            continue;
+        }
+        text.push(ch);
+        index += 1;
+    }
+    push_fragment(&mut fragments, &mut text, markup);
+    fragments
+}
+
+/// Turn msg content into styled spans, formats are added to `style`.
+pub fn to_spans(content: &str, style: Style, theme: &Theme) -> Vec<Span<'static>> {
+    parse(content)
+        .into_iter()
+        .map(|Fragment { text, markup }| {
+            let mut style = style;
+            if markup.bold {
+                style = style.add_modifier(Modifier::BOLD);
+            }
+            if markup.italic {
+                style = style.add_modifier(Modifier::ITALIC);
+            }
+            if markup.strikethrough {
+                style = style.add_modifier(Modifier::CROSSED_OUT);
+            }
+            if markup.code {
+                style = style.fg(theme.code);
+            }
+            if markup.link {
+                style = style.fg(theme.link).add_modifier(Modifier::UNDERLINED);
+            }
+            if markup.mention {
+                style = style
+                    .fg(theme.nick_color(&text[1..]))
+                    .add_modifier(Modifier::BOLD);
+            }
+            Span::styled(text, style)
+        })
+        .collect()
+}
+
+/// Get msg content without markers, like `bold` for `**bold**`.
+pub fn to_plain(content: &str) -> String {
+    parse(content)
+        .into_iter()
+        .map(|fragment| fragment.text)
+        .collect()
+}
+
+/// Check if `nickname` is mentioned by `@nickname` in msg content, case is ignored.
+pub fn is_mentioned(content: &str, nickname: &str) -> bool {
+    !nickname.is_empty()
+        && parse(content).iter().any(|fragment| {
+            fragment.markup.mention && fragment.text[1..].eq_ignore_ascii_case(nickname)
+        })
+}
+
+/// Move `text` into a new fragment, fragments next to each other with the same formats are merged.
+fn push_fragment(fragments: &mut Vec<Fragment>, text: &mut String, markup: Markup) {
+    if text.is_empty() {
+        return;
+    }
+    match fragments.last_mut() {
+        Some(last) if last.markup == markup => last.text.push_str(text),
+        _ => fragments.push(Fragment {
+            text: text.clone(),
+            markup,
+        }),
+    }
+    text.clear();
+}
+
+fn starts_with(chars: &[char], index: usize, marker: &[char]) -> bool {
+    chars[index..].starts_with(marker)
+}
+
+/// Chars allowed in nicknames, see `message::is_valid_nickname`.
+fn is_nickname_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '-' || ch == '_'
+}
+
+fn is_word_start(chars: &[char], index: usize) -> bool {
+    index == 0 || chars[index - 1].is_whitespace()
+}
+
+/// An opening marker is followed by a non-whitespace char, `_` must also start a word,
+/// so `snake_case_names` are not formatted.
+fn is_opening_marker(chars: &[char], index: usize, marker: &[char]) -> bool {
+    let end = index + marker.len();
+    starts_with(chars, index, marker)
+        && !is_part_of_longer_marker(chars, index, marker)
+        && end < chars.len()
+        && !chars[end].is_whitespace()
+        && (marker != ['_'] || index == 0 || !chars[index - 1].is_alphanumeric())
+}
+
+/// A closing marker follows a non-whitespace char, `_` must also end a word.
+///
+/// A `*` in `***` can close italic, as `**` is checked first.
+fn is_closing_marker(chars: &[char], index: usize, marker: &[char]) -> bool {
+    let end = index + marker.len();
+    starts_with(chars, index, marker)
+        && index > 0
+        && !chars[index - 1].is_whitespace()
+        && chars[index - 1] != '\\'
+        && (marker != ['_'] || end == chars.len() || !chars[end].is_alphanumeric())
+}
+
+/// A single `*` next to another `*` belongs to `**`.
+fn is_part_of_longer_marker(chars: &[char], index: usize, marker: &[char]) -> bool {
+    marker.len() == 1
+        && (chars.get(index + 1) == Some(&marker[0]) || index > 0 && chars[index - 1] == marker[0])
+}
+
+/// Parse `[text](url)` starting at `index`, returns text, url and index after the link.
+fn parse_link(chars: &[char], index: usize) -> Option<(String, String, usize)> {
+    let text_end = (index + 1..chars.len()).find(|i| chars[*i] == ']')?;
+    if text_end == index + 1 || chars.get(text_end + 1) != Some(&'(') {
+        return None;
+    }
+    let url_start = text_end + 2;
+    let url_end = (url_start..chars.len()).find(|i| chars[*i] == ')')?;
+    let url: String = chars[url_start..url_end].iter().collect();
+    if url.is_empty() || url.contains(char::is_whitespace) {
+        return None;
+    }
+    let text = chars[index + 1..text_end].iter().collect();
+    Some((text, url, url_end + 1))
+}
+
+/// Find end of a bare url starting at `index`, punctuation at the end is not a part of it.
+fn find_url_end(chars: &[char], index: usize) -> Option<usize> {
+    if !["http://", "https://"].iter().any(|scheme| {
+        let scheme: Vec<char> = scheme.chars().collect();
+        starts_with(chars, index, &scheme)
+    }) {
+        return None;
+    }
+    let mut end = (index..chars.len())
+        .find(|i| chars[*i].is_whitespace())
+        .unwrap_or(chars.len());
+    while end > index && ".,;:!?)".contains(chars[end - 1]) {
+        end -= 1;
+    }
+    Some(end)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fragment(text: &str, markup: Markup) -> Fragment {
+        Fragment {
+            text: text.to_string(),
+            markup,
+        }
+    }
+
+    #[test]
+    fn parse_formats() {
+        let bold = Markup {
+            bold: true,
+            ..Default::default()
+        };
+        let italic = Markup {
+            italic: true,
+            ..Default::default()
+        };
+        let code = Markup {
+            code: true,
+            ..Default::default()
+        };
+        let strikethrough = Markup {
+            strikethrough: true,
+            ..Default::default()
+        };
+        let plain = Markup::default();
+        assert_eq!(
+            parse("**hi** _there_ `a*b` ~~no~~"),
+            vec![
+                fragment("hi", bold),
+                fragment(" ", plain),
+                fragment("there", italic),
+                fragment(" ", plain),
+                fragment("a*b", code),
+                fragment(" ", plain),
+                fragment("no", strikethrough),
+            ]
+        );
+        assert_eq!(
+            parse("**bold *both***"),
+            vec![
+                fragment("bold ", bold),
+                fragment(
+                    "both",
+                    Markup {
+                        bold: true,
+                        italic: true,
+                        ..Default::default()
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_literal_markers() {
+        let plain = |text: &str| vec![fragment(text, Markup::default())];
+        assert_eq!(parse("2 * 3 * 4"), plain("2 * 3 * 4"));
+        assert_eq!(parse("snake_case_name"), plain("snake_case_name"));
+        assert_eq!(parse("**not closed"), plain("**not closed"));
+        assert_eq!(parse(r"\*\*not bold\*\*"), plain("**not bold**"));
+        assert_eq!(parse(r"a \` b \\ c \d"), plain(r"a ` b \ c \d"));
+        assert_eq!(parse("``"), plain("``"));
+        assert_eq!(to_plain("**a** `*b*` [c](d)"), "a *b* c (d)");
+    }
+
+    #[test]
+    fn parse_links() {
+        let link = Markup {
+            link: true,
+            ..Default::default()
+        };
+        let plain = Markup::default();
+        assert_eq!(
+            parse("see [docs](https://a.b/c_d) or https://x.y/a_b_c."),
+            vec![
+                fragment("see ", plain),
+                fragment("docs", link),
+                fragment(" (https://a.b/c_d) or ", plain),
+                fragment("https://x.y/a_b_c", link),
+                fragment(".", plain),
+            ]
+        );
+        assert_eq!(
+            parse("[no link] (x)"),
+            vec![fragment("[no link] (x)", plain)]
+        );
+    }
+
+    #[test]
+    fn find_mentions() {
+        let mention = Markup {
+            mention: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse("hi @bob-1, mail a@b.c"),
+            vec![
+                fragment("hi ", Markup::default()),
+                fragment("@bob-1", mention),
+                fragment(", mail a@b.c", Markup::default()),
+            ]
+        );
+        assert!(is_mentioned("hi @Bob!", "bob"));
+        assert!(!is_mentioned("hi @bobby", "bob"));
+        assert!(!is_mentioned("hi bob@bob.com", "bob"));
+        assert!(!is_mentioned("`@bob`", "bob"));
+        assert!(!is_mentioned("hi @", ""));
+    }
+}
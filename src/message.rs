@@ -1,10 +1,60 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    io::{self, BufRead, Read, Write},
+};
 
+use crate::consts::DEFAULT_ROOM;
+
+#[derive(Clone)]
 pub enum MessageType {
     ClientLogIn,
     ClientExit,
     ClientListUpdate,
     TextMessage,
+    ActionMessage,
+    DirectMessage,
+    NickChange,
+    RoomJoin,
+    RoomPart,
+    RoomList,
+    /// A line for user to read, from server or the client itself
+    Notice,
+    /// Someone starts or stops typing, only relayed to others and never kept
+    Typing,
+    /// The author changes content of the msg with `msg_id`
+    MessageEdit,
+    /// The author deletes the msg with `msg_id`
+    MessageDelete,
+    /// React to the msg with `msg_id`, content sent to server is an emoji or a short token,
+    /// and content from server is all reactions of the msg like `👍:2,ok:1`
+    Reaction,
+    /// Search msgs kept by server, content is the query,
+    /// and server sends it back before the results
+    Search,
+    /// A msg found by a search, content is the whole msg found
+    SearchResult,
+    /// A client publishes its public key for encrypted direct msgs, content is the key in base64,
+    /// and server sends keys of others with their nicknames as senders
+    PublicKey,
+    /// Server asks a new client to log in, content is `required` or `optional`
+    LoginPrompt,
+    /// Log in to an account, target is the nickname and content is the password,
+    /// server sends it back with the nickname as sender when it succeeds
+    Login,
+    /// Register an account and log in to it, like `Login`
+    Register,
+    /// Server asks a new client for the server password or an invite before letting it in,
+    /// content is `password`, `invite` or `password or invite`
+    AuthRequest,
+    /// A client answers `AuthRequest`, content is the password or invite,
+    /// server sends it back when the client is let in
+    Auth,
+    /// An operator acts on a user, target is a nickname or an IP and content is the action
+    /// with its arguments like `mute 10m spamming`, server tells everyone what is done
+    /// with the operator as sender
+    Moderation,
+    /// Ask server for its stats, server answers with a line of stats per msg
+    Stats,
     Error,
 }
 impl Display for MessageType {
@@ -14,6 +64,27 @@ impl Display for MessageType {
             MessageType::ClientExit => write!(f, "ClientExit"),
             MessageType::ClientListUpdate => write!(f, "ClientListUpdate"),
             MessageType::TextMessage => write!(f, "TextMessage"),
+            MessageType::ActionMessage => write!(f, "ActionMessage"),
+            MessageType::DirectMessage => write!(f, "DirectMessage"),
+            MessageType::NickChange => write!(f, "NickChange"),
+            MessageType::RoomJoin => write!(f, "RoomJoin"),
+            MessageType::RoomPart => write!(f, "RoomPart"),
+            MessageType::RoomList => write!(f, "RoomList"),
+            MessageType::Notice => write!(f, "Notice"),
+            MessageType::Typing => write!(f, "Typing"),
+            MessageType::MessageEdit => write!(f, "MessageEdit"),
+            MessageType::MessageDelete => write!(f, "MessageDelete"),
+            MessageType::Reaction => write!(f, "Reaction"),
+            MessageType::Search => write!(f, "Search"),
+            MessageType::SearchResult => write!(f, "SearchResult"),
+            MessageType::PublicKey => write!(f, "PublicKey"),
+            MessageType::LoginPrompt => write!(f, "LoginPrompt"),
+            MessageType::Login => write!(f, "Login"),
+            MessageType::Register => write!(f, "Register"),
+            MessageType::AuthRequest => write!(f, "AuthRequest"),
+            MessageType::Auth => write!(f, "Auth"),
+            MessageType::Moderation => write!(f, "Moderation"),
+            MessageType::Stats => write!(f, "Stats"),
             MessageType::Error => write!(f, "Error"),
         }
     }
@@ -33,60 +104,511 @@ impl MessageType {
             "ClientExit" => MessageType::ClientExit,
             "ClientListUpdate" => MessageType::ClientListUpdate,
             "TextMessage" => MessageType::TextMessage,
+            "ActionMessage" => MessageType::ActionMessage,
+            "DirectMessage" => MessageType::DirectMessage,
+            "NickChange" => MessageType::NickChange,
+            "RoomJoin" => MessageType::RoomJoin,
+            "RoomPart" => MessageType::RoomPart,
+            "RoomList" => MessageType::RoomList,
+            "Notice" => MessageType::Notice,
+            "Typing" => MessageType::Typing,
+            "MessageEdit" => MessageType::MessageEdit,
+            "MessageDelete" => MessageType::MessageDelete,
+            "Reaction" => MessageType::Reaction,
+            "Search" => MessageType::Search,
+            "SearchResult" => MessageType::SearchResult,
+            "PublicKey" => MessageType::PublicKey,
+            "LoginPrompt" => MessageType::LoginPrompt,
+            "Login" => MessageType::Login,
+            "Register" => MessageType::Register,
+            "AuthRequest" => MessageType::AuthRequest,
+            "Auth" => MessageType::Auth,
+            "Moderation" => MessageType::Moderation,
+            "Stats" => MessageType::Stats,
             _ => MessageType::Error,
         }
     }
 }
 
+#[derive(Clone)]
 pub struct Message {
     pub msg_type: MessageType,
     pub msg_sender: String,
+    /// Name of the room this message belongs to
+    pub msg_room: String,
+    /// Name or id of the receiver of a direct msg, empty for other msgs
+    pub msg_target: String,
+    /// Unix timestamp in seconds, set by server when the msg is relayed
+    pub msg_time: i64,
+    /// Id given by server to text, action and direct msgs, `0` for other msgs
+    pub msg_id: u64,
+    /// Id of the msg this one replies to, `0` if it is not a reply
+    pub msg_reply_to: u64,
     pub msg_content: String,
 }
+impl Default for Message {
+    fn default() -> Message {
+        Message {
+            msg_type: MessageType::TextMessage,
+            msg_sender: String::default(),
+            msg_room: DEFAULT_ROOM.to_string(),
+            msg_target: String::default(),
+            msg_time: chrono::Utc::now().timestamp(),
+            msg_id: 0,
+            msg_reply_to: 0,
+            msg_content: String::default(),
+        }
+    }
+}
 impl Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let msg_str = [
             self.msg_type.to_string(),
             self.msg_sender.clone(),
-            self.msg_content.clone(),
+            self.msg_room.clone(),
+            self.msg_target.clone(),
+            self.msg_time.to_string(),
+            self.msg_id.to_string(),
+            self.msg_reply_to.to_string(),
+            escape_content(&self.msg_content),
         ]
         .join(",");
         write!(f, "{}", msg_str)
     }
 }
 impl Message {
+    /// Number of comma separated fields before `msg_content`.
+    const HEADER_FIELDS: usize = 7;
+
     /// Convert a formatted string to `Message`
     /// ```rust
-    /// "AMsgType,name_or_id,xxxxx"
+    /// "AMsgType,name_or_id,room,target,time,id,reply_to,xxxxx"
     /// ```
     /// --->
     /// ```rust
     /// Message {
     ///     msg_type: MessageType::AMsgType,
     ///     sender_name: name_or_id,
+    ///     msg_room: room,
+    ///     msg_target: target,
+    ///     msg_time: time,
+    ///     msg_id: id,
+    ///     msg_reply_to: reply_to,
     ///     msg_content: xxxxx,
     /// }
     /// ```
     pub fn convert_to_msg(msg_str: &str) -> Message {
         let msg_info: Vec<&str> = msg_str.split(',').collect();
-        if msg_info.len() < 3 {
+        if msg_info.len() <= Message::HEADER_FIELDS {
             Message {
                 msg_type: MessageType::Error,
-                msg_sender: msg_info[1].to_string(),
+                msg_sender: msg_info.get(1).unwrap_or(&"").to_string(),
                 msg_content: "Msg format error.".to_string(),
+                ..Default::default()
             }
         } else {
             Message {
                 msg_type: MessageType::convert_to_msg_type(msg_info[0]),
                 msg_sender: msg_info[1].to_string(),
-                msg_content: msg_info[2..].join(","),
+                msg_room: msg_info[2].to_string(),
+                msg_target: msg_info[3].to_string(),
+                msg_time: msg_info[4].parse().unwrap_or_default(),
+                msg_id: msg_info[5].parse().unwrap_or_default(),
+                msg_reply_to: msg_info[6].parse().unwrap_or_default(),
+                msg_content: unescape_content(&msg_info[Message::HEADER_FIELDS..].join(",")),
             }
         }
     }
 
+    /// Build a direct msg to `target`, a nickname or an id.
+    pub fn direct(target: &str, content: &str) -> Message {
+        Message {
+            msg_type: MessageType::DirectMessage,
+            msg_target: target.to_string(),
+            msg_content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Build a request to change content of your msg with `id`.
+    pub fn edit(id: u64, content: &str) -> Message {
+        Message {
+            msg_type: MessageType::MessageEdit,
+            msg_id: id,
+            msg_content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Build a request to delete your msg with `id`.
+    pub fn delete(id: u64) -> Message {
+        Message {
+            msg_type: MessageType::MessageDelete,
+            msg_id: id,
+            ..Default::default()
+        }
+    }
+
+    /// Build a reaction to the msg with `id`, reacting again with the same token takes it back.
+    pub fn reaction(id: u64, token: &str) -> Message {
+        Message {
+            msg_type: MessageType::Reaction,
+            msg_id: id,
+            msg_content: token.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Wrap a msg found by a search.
+    pub fn search_result(found: &Message) -> Message {
+        Message {
+            msg_type: MessageType::SearchResult,
+            msg_sender: "server".to_string(),
+            msg_room: found.msg_room.clone(),
+            msg_content: found.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Get the msg wrapped in a `SearchResult`.
+    pub fn found_msg(&self) -> Message {
+        Message::convert_to_msg(&self.msg_content)
+    }
+
+    /// Build a request to log in as `nick`, or to register `nick` first.
+    pub fn login(nick: &str, password: &str, register: bool) -> Message {
+        Message {
+            msg_type: if register {
+                MessageType::Register
+            } else {
+                MessageType::Login
+            },
+            msg_target: nick.to_string(),
+            msg_content: password.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Build an operator's action on `target`, like `kick` with the reason as `args`.
+    pub fn moderation(action: &str, target: &str, args: &str) -> Message {
+        Message {
+            msg_type: MessageType::Moderation,
+            msg_target: target.to_string(),
+            msg_content: match args.is_empty() {
+                true => action.to_string(),
+                false => format!("{} {}", action, args),
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Build a typing event in `room`, or in direct msgs with `target` if it is not empty.
+    ///
+    /// Content is `start` or `stop`.
+    pub fn typing(room: &str, target: &str, is_typing: bool) -> Message {
+        Message {
+            msg_type: MessageType::Typing,
+            msg_room: room.to_string(),
+            msg_target: target.to_string(),
+            msg_content: if is_typing { "start" } else { "stop" }.to_string(),
+            ..Default::default()
+        }
+    }
+
     /// Convert a `Message` to a `String` that just contains
     /// basic infomation of the message
     pub fn to_brief_string(&self) -> String {
-        format!("{}: {}", self.msg_sender, self.msg_content)
+        match self.msg_type {
+            MessageType::TextMessage => format!("{}: {}", self.msg_sender, self.msg_content),
+            MessageType::ActionMessage => format!("* {} {}", self.msg_sender, self.msg_content),
+            MessageType::DirectMessage => format!(
+                "[DM] {} -> {}: {}",
+                self.msg_sender, self.msg_target, self.msg_content
+            ),
+            MessageType::MessageEdit => format!(
+                "* {} edited msg {}: {}",
+                self.msg_sender, self.msg_id, self.msg_content
+            ),
+            MessageType::MessageDelete => {
+                format!("* {} deleted msg {}", self.msg_sender, self.msg_id)
+            }
+            MessageType::Reaction => format!(
+                "* {} reacted to msg {}, reactions: {}",
+                self.msg_sender,
+                self.msg_id,
+                self.msg_content.replace(',', " ")
+            ),
+            MessageType::Search => format!("* Search results for \"{}\":", self.msg_content),
+            MessageType::PublicKey => format!("* {} published a key", self.msg_sender),
+            MessageType::SearchResult => {
+                let found = self.found_msg();
+                format!("  ({}) {}", found.msg_id, found.to_brief_string())
+            }
+            _ => format!("* {}", self.msg_content),
+        }
+    }
+
+    /// Write the message to `writer` as a single line.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(format!("{}\n", self).as_bytes())?;
+        writer.flush()
+    }
+
+    /// Read a message line from `reader`, `Ok(None)` means the peer has closed the connection.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<Option<Message>> {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Message::convert_to_msg(
+            line.trim_end_matches(['\r', '\n']),
+        )))
+    }
+
+    /// Read a message like `read_from`, but lines longer than `max_size` bytes are skipped
+    /// without being kept in memory, and `Err` of kind `InvalidData` is returned for them.
+    pub fn read_limited<R: BufRead>(
+        reader: &mut R,
+        max_size: usize,
+    ) -> io::Result<Option<Message>> {
+        let mut line = vec![];
+        let limit = max_size as u64 + 1;
+        if reader.by_ref().take(limit).read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = match line.strip_suffix(b"\n") {
+            Some(line) => line,
+            None if line.len() > max_size => {
+                skip_line(reader)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Msgs are at most {} bytes.", max_size),
+                ));
+            }
+            None => &line,
+        };
+        let line = std::str::from_utf8(line)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(Some(Message::convert_to_msg(line.trim_end_matches('\r'))))
+    }
+}
+
+/// Throw away the rest of a line.
+fn skip_line<R: BufRead>(reader: &mut R) -> io::Result<()> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        match buf.iter().position(|byte| *byte == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+/// Check if `name` can be used as a room name.
+///
+/// Room names are short and only contain letters, digits, `-` and `_`,
+/// so they never break the message format.
+pub fn is_valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= 32
+        && name
+            .chars()
+            .all(|ch| ch.is_alphanumeric() || ch == '-' || ch == '_')
+}
+
+/// Check if `name` can be used as a nickname.
+///
+/// Nicknames follow the rule of room names, but can not be all digits
+/// for a client can also be found by its id.
+pub fn is_valid_nickname(name: &str) -> bool {
+    is_valid_room_name(name) && !name.chars().all(|ch| ch.is_ascii_digit())
+}
+
+/// Check if `token` can be used as a reaction, like an emoji or `+1`.
+pub fn is_valid_reaction(token: &str) -> bool {
+    !token.is_empty()
+        && token.chars().count() <= 16
+        && !token
+            .chars()
+            .any(|ch| ch.is_whitespace() || ch.is_control() || ch == ',' || ch == ':')
+}
+
+/// Join reactions and their counts like `👍:2,ok:1`.
+pub fn format_reactions(reactions: &[(String, usize)]) -> String {
+    reactions
+        .iter()
+        .map(|(token, count)| format!("{}:{}", token, count))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Reverse of `format_reactions`, broken items are skipped.
+pub fn parse_reactions(content: &str) -> Vec<(String, usize)> {
+    content
+        .split(',')
+        .filter_map(|item| item.rsplit_once(':'))
+        .filter_map(|(token, count)| Some((token.to_string(), count.parse().ok()?)))
+        .collect()
+}
+
+/// Escape `\` and line breaks so a message always fits in one line.
+fn escape_content(content: &str) -> String {
+    content
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Reverse of `escape_content`.
+fn unescape_content(content: &str) -> String {
+    let mut result = String::with_capacity(content.len());
+    let mut chars = content.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn message_round_trip() {
+        let msg = Message {
+            msg_type: MessageType::TextMessage,
+            msg_sender: "alice".to_string(),
+            msg_room: "rust".to_string(),
+            msg_target: String::default(),
+            msg_time: 1660000000,
+            msg_id: 42,
+            msg_reply_to: 41,
+            msg_content: "hello, world\nC:\\path".to_string(),
+        };
+        let parsed = Message::convert_to_msg(&msg.to_string());
+        assert!(matches!(parsed.msg_type, MessageType::TextMessage));
+        assert_eq!(parsed.msg_sender, "alice");
+        assert_eq!(parsed.msg_room, "rust");
+        assert_eq!(parsed.msg_time, 1660000000);
+        assert_eq!(parsed.msg_id, 42);
+        assert_eq!(parsed.msg_reply_to, 41);
+        assert_eq!(parsed.msg_content, msg.msg_content);
+    }
+
+    #[test]
+    fn message_format_error() {
+        let parsed = Message::convert_to_msg("TextMessage");
+        assert!(matches!(parsed.msg_type, MessageType::Error));
+    }
+
+    #[test]
+    fn message_framing() {
+        let mut buf: Vec<u8> = vec![];
+        for content in ["first", "second\nline"] {
+            Message {
+                msg_content: content.to_string(),
+                ..Default::default()
+            }
+            .write_to(&mut buf)
+            .unwrap();
+        }
+        let mut reader = io::BufReader::new(&buf[..]);
+        let first = Message::read_from(&mut reader).unwrap().unwrap();
+        let second = Message::read_from(&mut reader).unwrap().unwrap();
+        assert_eq!(first.msg_content, "first");
+        assert_eq!(second.msg_content, "second\nline");
+        assert!(Message::read_from(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn limit_msg_size() {
+        let mut buf: Vec<u8> = vec![];
+        for content in ["short", &"long".repeat(100), "after"] {
+            Message {
+                msg_content: content.to_string(),
+                ..Default::default()
+            }
+            .write_to(&mut buf)
+            .unwrap();
+        }
+        // a small buffer, so the long line is skipped in pieces
+        let mut reader = io::BufReader::with_capacity(16, &buf[..]);
+        let first = Message::read_limited(&mut reader, 100).unwrap().unwrap();
+        assert_eq!(first.msg_content, "short");
+        let err = Message::read_limited(&mut reader, 100).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let after = Message::read_limited(&mut reader, 100).unwrap().unwrap();
+        assert_eq!(after.msg_content, "after");
+        assert!(Message::read_limited(&mut reader, 100).unwrap().is_none());
+    }
+
+    #[test]
+    fn direct_msg_round_trip() {
+        let dm = Message::direct("bob", "hi, bob");
+        let parsed = Message::convert_to_msg(&dm.to_string());
+        assert!(matches!(parsed.msg_type, MessageType::DirectMessage));
+        assert_eq!(parsed.msg_target, "bob");
+        assert_eq!(parsed.msg_content, "hi, bob");
+    }
+
+    #[test]
+    fn search_result_round_trip() {
+        let found = Message {
+            msg_type: MessageType::ActionMessage,
+            msg_sender: "alice".to_string(),
+            msg_id: 7,
+            msg_content: "waves,\nthen leaves".to_string(),
+            ..Default::default()
+        };
+        let result = Message::convert_to_msg(&Message::search_result(&found).to_string());
+        assert!(matches!(result.msg_type, MessageType::SearchResult));
+        let parsed = result.found_msg();
+        assert!(matches!(parsed.msg_type, MessageType::ActionMessage));
+        assert_eq!(parsed.msg_sender, "alice");
+        assert_eq!(parsed.msg_id, 7);
+        assert_eq!(parsed.msg_content, found.msg_content);
+    }
+
+    #[test]
+    fn reactions_round_trip() {
+        let reactions = vec![("👍".to_string(), 2), ("ok".to_string(), 1)];
+        let content = format_reactions(&reactions);
+        assert_eq!(content, "👍:2,ok:1");
+        assert_eq!(parse_reactions(&content), reactions);
+        assert!(parse_reactions("").is_empty());
+
+        assert!(is_valid_reaction("🎉"));
+        assert!(is_valid_reaction("+1"));
+        assert!(!is_valid_reaction("a,b"));
+        assert!(!is_valid_reaction("a b"));
+        assert!(!is_valid_reaction(""));
+    }
+
+    #[test]
+    fn room_name_validation() {
+        assert!(is_valid_room_name("rust-lang_cn"));
+        assert!(!is_valid_room_name(""));
+        assert!(!is_valid_room_name("a,b"));
+        assert!(!is_valid_room_name("a b"));
+        assert!(is_valid_nickname("alice2"));
+        assert!(!is_valid_nickname("42"));
     }
 }
@@ -0,0 +1,496 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+use crate::moderation::format_duration;
+
+/// Seconds msgs are counted over for the rate of msgs
+const RATE_SECS: usize = 10;
+/// Seconds to wait for a request to the metrics endpoint
+const HTTP_TIMEOUT_SECS: u64 = 5;
+/// Max size of a request to the metrics endpoint in bytes, larger ones are refused
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+
+/// Counts events of the last `RATE_SECS` seconds, a bucket per second.
+#[derive(Default)]
+struct Meter {
+    buckets: [u64; RATE_SECS],
+    /// Second of the newest bucket, since server started
+    last: u64,
+}
+impl Meter {
+    /// Move to second `now`, emptying buckets of the seconds skipped.
+    fn advance(&mut self, now: u64) {
+        if now <= self.last {
+            return;
+        }
+        let skipped = (now - self.last).min(RATE_SECS as u64);
+        for sec in now + 1 - skipped..=now {
+            self.buckets[sec as usize % RATE_SECS] = 0;
+        }
+        self.last = now;
+    }
+
+    fn mark(&mut self, now: u64) {
+        self.advance(now);
+        self.buckets[now as usize % RATE_SECS] += 1;
+    }
+
+    /// Events per second in the last `RATE_SECS` seconds.
+    fn rate(&mut self, now: u64) -> f64 {
+        self.advance(now);
+        self.buckets.iter().sum::<u64>() as f64 / RATE_SECS as f64
+    }
+}
+
+/// Activity of a room
+#[derive(Default, Clone, Copy)]
+struct RoomStats {
+    members: usize,
+    msgs: u64,
+}
+
+/// Counters and gauges of server, shared by all its threads.
+pub struct Metrics {
+    started: Instant,
+    /// Clients connected, logging in or not
+    clients: AtomicU64,
+    msgs_received: AtomicU64,
+    msgs_sent: AtomicU64,
+    /// Msgs from clients thrown away, for being too fast, too long or from muted clients,
+    /// and msgs failed to be sent to clients
+    msgs_dropped: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    connections: AtomicU64,
+    connections_rejected: AtomicU64,
+    /// Msgs read from clients, waiting for the chamber
+    queued_msgs: AtomicI64,
+    received_meter: Mutex<Meter>,
+    /// Msgs relayed to clients, a msg to a room counts once for each member
+    sent_meter: Mutex<Meter>,
+    rooms: Mutex<BTreeMap<String, RoomStats>>,
+}
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            started: Instant::now(),
+            clients: AtomicU64::default(),
+            msgs_received: AtomicU64::default(),
+            msgs_sent: AtomicU64::default(),
+            msgs_dropped: AtomicU64::default(),
+            bytes_received: AtomicU64::default(),
+            bytes_sent: AtomicU64::default(),
+            connections: AtomicU64::default(),
+            connections_rejected: AtomicU64::default(),
+            queued_msgs: AtomicI64::default(),
+            received_meter: Mutex::default(),
+            sent_meter: Mutex::default(),
+            rooms: Mutex::default(),
+        }
+    }
+}
+impl Metrics {
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// A msg from a client is passed to the chamber.
+    pub fn msg_received(&self) {
+        self.msgs_received.fetch_add(1, Ordering::Relaxed);
+        self.msg_queued();
+        let now = self.uptime().as_secs();
+        self.received_meter.lock().unwrap().mark(now);
+    }
+
+    /// A msg is passed to the chamber, for clients going offline too.
+    pub fn msg_queued(&self) {
+        self.queued_msgs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The chamber takes a msg from the queue.
+    pub fn msg_handled(&self) {
+        self.queued_msgs.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn msg_sent(&self) {
+        self.msgs_sent.fetch_add(1, Ordering::Relaxed);
+        let now = self.uptime().as_secs();
+        self.sent_meter.lock().unwrap().mark(now);
+    }
+
+    pub fn msg_dropped(&self) {
+        self.msgs_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_accepted(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A chat msg is sent to members of `room`.
+    pub fn room_msg(&self, room: &str) {
+        if let Some(stats) = self.rooms.lock().unwrap().get_mut(room) {
+            stats.msgs += 1;
+        }
+    }
+
+    /// Update the number of clients and members of rooms, rooms gone are forgotten.
+    pub fn set_clients(&self, clients: usize, rooms: &HashMap<String, HashSet<u32>>) {
+        self.clients.store(clients as u64, Ordering::Relaxed);
+        let mut stats = self.rooms.lock().unwrap();
+        stats.retain(|room, _| rooms.contains_key(room));
+        for (room, members) in rooms {
+            stats.entry(room.clone()).or_default().members = members.len();
+        }
+    }
+
+    fn rate(&self, meter: &Mutex<Meter>) -> f64 {
+        let now = self.uptime().as_secs();
+        meter.lock().unwrap().rate(now)
+    }
+
+    /// Metrics in the text format of Prometheus.
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(text, "# HELP chamber_{} {}", name, help);
+            let _ = writeln!(text, "# TYPE chamber_{} {}", name, kind);
+            let _ = writeln!(text, "chamber_{} {}", name, value);
+        };
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed).to_string();
+        metric(
+            "uptime_seconds",
+            "gauge",
+            "Seconds since server started.",
+            self.uptime().as_secs().to_string(),
+        );
+        metric(
+            "clients",
+            "gauge",
+            "Clients connected.",
+            load(&self.clients),
+        );
+        metric(
+            "connections_total",
+            "counter",
+            "Connections accepted.",
+            load(&self.connections),
+        );
+        metric(
+            "connections_rejected_total",
+            "counter",
+            "Connections rejected by limits, access lists or bans.",
+            load(&self.connections_rejected),
+        );
+        metric(
+            "msgs_received_total",
+            "counter",
+            "Msgs received from clients.",
+            load(&self.msgs_received),
+        );
+        metric(
+            "msgs_sent_total",
+            "counter",
+            "Msgs sent to clients.",
+            load(&self.msgs_sent),
+        );
+        metric(
+            "msgs_dropped_total",
+            "counter",
+            "Msgs dropped for limits or failures.",
+            load(&self.msgs_dropped),
+        );
+        metric(
+            "msgs_received_per_second",
+            "gauge",
+            &format!(
+                "Msgs received per second in the last {} seconds.",
+                RATE_SECS
+            ),
+            self.rate(&self.received_meter).to_string(),
+        );
+        metric(
+            "msgs_relayed_per_second",
+            "gauge",
+            &format!(
+                "Msgs sent to clients per second in the last {} seconds.",
+                RATE_SECS
+            ),
+            self.rate(&self.sent_meter).to_string(),
+        );
+        metric(
+            "bytes_received_total",
+            "counter",
+            "Bytes received from clients.",
+            load(&self.bytes_received),
+        );
+        metric(
+            "bytes_sent_total",
+            "counter",
+            "Bytes sent to clients.",
+            load(&self.bytes_sent),
+        );
+        metric(
+            "queued_msgs",
+            "gauge",
+            "Msgs waiting to be handled.",
+            self.queued_msgs.load(Ordering::Relaxed).to_string(),
+        );
+        let rooms = self.rooms.lock().unwrap().clone();
+        let series = |name: &str, kind: &str, help: &str, value: fn(&RoomStats) -> u64| {
+            let mut text = format!(
+                "# HELP chamber_{} {}\n# TYPE chamber_{} {}\n",
+                name, help, name, kind
+            );
+            for (room, stats) in &rooms {
+                let _ = writeln!(
+                    text,
+                    "chamber_{}{{room=\"{}\"}} {}",
+                    name,
+                    escape_label(room),
+                    value(stats)
+                );
+            }
+            text
+        };
+        text += &series("room_members", "gauge", "Members of rooms.", |stats| {
+            stats.members as u64
+        });
+        text += &series(
+            "room_msgs_total",
+            "counter",
+            "Chat msgs sent to rooms.",
+            |stats| stats.msgs,
+        );
+        text
+    }
+
+    /// Metrics for people to read, a line per item.
+    pub fn summary(&self) -> Vec<String> {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let rooms: Vec<String> = self
+            .rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(room, stats)| {
+                format!("#{} {} members, {} msgs", room, stats.members, stats.msgs)
+            })
+            .collect();
+        vec![
+            format!("uptime: {}", format_duration(self.uptime())),
+            format!(
+                "clients: {}, connections: {} accepted, {} rejected",
+                load(&self.clients),
+                load(&self.connections),
+                load(&self.connections_rejected)
+            ),
+            format!(
+                "msgs: {} received, {} sent, {} dropped, {:.1}/s received, {:.1}/s relayed, {} queued",
+                load(&self.msgs_received),
+                load(&self.msgs_sent),
+                load(&self.msgs_dropped),
+                self.rate(&self.received_meter),
+                self.rate(&self.sent_meter),
+                self.queued_msgs.load(Ordering::Relaxed)
+            ),
+            format!(
+                "bytes: {} received, {} sent",
+                load(&self.bytes_received),
+                load(&self.bytes_sent)
+            ),
+            format!("rooms: {}", rooms.join("; ")),
+        ]
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Reads or writes through `inner`, counting the bytes into the metrics.
+pub struct Counted<T> {
+    inner: T,
+    metrics: Arc<Metrics>,
+}
+impl<T> Counted<T> {
+    pub fn new(inner: T, metrics: Arc<Metrics>) -> Counted<T> {
+        Counted { inner, metrics }
+    }
+}
+impl<T: Read> Read for Counted<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.metrics
+            .bytes_received
+            .fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+}
+impl<T: Write> Write for Counted<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.metrics
+            .bytes_sent
+            .fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Serve metrics at `/metrics` of `addr` over HTTP, for Prometheus to scrape.
+pub fn serve(addr: &str, metrics: Arc<Metrics>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(err) = answer(stream, &metrics) {
+                warn!("Metrics request failed: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn answer(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECS)))?;
+    let mut reader = BufReader::new(&stream).take(MAX_REQUEST_SIZE);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // read headers, they are not used
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Request is too large or cut off.",
+            ));
+        }
+        if header.trim().is_empty() {
+            break;
+        }
+    }
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.to_prometheus()),
+        _ => ("404 Not Found", "Metrics are at /metrics.\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    (&stream).write_all(response.as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate_of_msgs() {
+        let mut meter = Meter::default();
+        for _ in 0..20 {
+            meter.mark(3);
+        }
+        meter.mark(5);
+        assert_eq!(meter.rate(5), 2.1);
+        // the msgs of second 3 are out of the window
+        assert_eq!(meter.rate(13), 0.1);
+        assert_eq!(meter.rate(100), 0.0);
+        meter.mark(100);
+        assert_eq!(meter.rate(100), 0.1);
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let metrics = Arc::new(Metrics::default());
+        let rooms = HashMap::from([
+            ("lobby".to_string(), HashSet::from([0, 1])),
+            ("rust".to_string(), HashSet::from([1])),
+        ]);
+        metrics.set_clients(2, &rooms);
+        metrics.msg_received();
+        metrics.msg_received();
+        metrics.msg_handled();
+        metrics.room_msg("rust");
+        for _ in 0..5 {
+            metrics.msg_sent();
+        }
+        let mut writer = Counted::new(vec![], metrics.clone());
+        writer.write_all(b"hello\n").unwrap();
+
+        let text = metrics.to_prometheus();
+        for line in [
+            "# TYPE chamber_clients gauge",
+            "chamber_clients 2",
+            "chamber_msgs_received_total 2",
+            "chamber_queued_msgs 1",
+            "chamber_msgs_received_per_second 0.2",
+            "chamber_msgs_relayed_per_second 0.5",
+            "chamber_bytes_sent_total 6",
+            "chamber_room_members{room=\"lobby\"} 2",
+            "chamber_room_msgs_total{room=\"rust\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} is missing", line);
+        }
+
+        // rooms gone are forgotten
+        metrics.set_clients(
+            1,
+            &HashMap::from([("lobby".to_string(), HashSet::from([0]))]),
+        );
+        assert!(!metrics.to_prometheus().contains("rust"));
+        assert!(metrics.summary()[2].starts_with("msgs: 2 received, 5 sent"));
+    }
+
+    #[test]
+    fn answer_requests() {
+        let metrics = Metrics::default();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let request = |text: Vec<u8>| {
+            let mut client = TcpStream::connect(addr).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            client.write_all(&text).unwrap();
+            let result = answer(server, &metrics);
+            let mut response = String::new();
+            let _ = client.read_to_string(&mut response);
+            (result, response)
+        };
+
+        let (result, response) = request(b"GET /metrics HTTP/1.1\r\nHost: a\r\n\r\n".to_vec());
+        assert!(result.is_ok());
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("chamber_clients 0"));
+
+        let mut huge = b"GET /metrics HTTP/1.1\r\nX: ".to_vec();
+        huge.resize(MAX_REQUEST_SIZE as usize * 2, b'a');
+        let (result, response) = request(huge);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(response.is_empty());
+    }
+}
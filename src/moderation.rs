@@ -0,0 +1,176 @@
+use std::{collections::BTreeSet, fs, io, net::IpAddr, path::PathBuf, time::Duration};
+
+/// Longest duration of mutes, a year
+pub const MAX_DURATION_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// Nicknames and IPs banned from server, kept in a file like
+///
+/// ```text
+/// nick alice
+/// ip 10.0.0.7
+/// ```
+#[derive(Default)]
+pub struct BanList {
+    /// Where bans are saved, `None` if they are not saved
+    path: Option<PathBuf>,
+    nicks: BTreeSet<String>,
+    ips: BTreeSet<IpAddr>,
+}
+impl BanList {
+    /// Load bans from `path`, there are no bans if the file does not exist.
+    pub fn load(path: PathBuf) -> io::Result<BanList> {
+        let mut bans = BanList::default();
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                for line in text.lines() {
+                    match line.split_once(' ') {
+                        Some(("nick", nick)) => {
+                            bans.nicks.insert(nick.trim().to_string());
+                        }
+                        Some(("ip", ip)) => {
+                            if let Ok(ip) = ip.trim().parse() {
+                                bans.ips.insert(ip);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        bans.path = Some(path);
+        Ok(bans)
+    }
+
+    pub fn is_nick_banned(&self, nick: &str) -> bool {
+        self.nicks.contains(nick)
+    }
+
+    pub fn is_ip_banned(&self, ip: &IpAddr) -> bool {
+        self.ips.contains(ip)
+    }
+
+    /// Ban a nickname, or an IP if `target` is one, return `false` if it is banned already.
+    pub fn ban(&mut self, target: &str) -> io::Result<bool> {
+        let added = match target.parse::<IpAddr>() {
+            Ok(ip) => self.ips.insert(ip),
+            Err(_) => self.nicks.insert(target.to_string()),
+        };
+        if added {
+            self.save()?;
+        }
+        Ok(added)
+    }
+
+    /// Take back a ban, return `false` if `target` is not banned.
+    pub fn unban(&mut self, target: &str) -> io::Result<bool> {
+        let removed = match target.parse::<IpAddr>() {
+            Ok(ip) => self.ips.remove(&ip),
+            Err(_) => self.nicks.remove(target),
+        };
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let nicks = self.nicks.iter().map(|nick| format!("nick {}\n", nick));
+        let ips = self.ips.iter().map(|ip| format!("ip {}\n", ip));
+        fs::write(path, nicks.chain(ips).collect::<String>())
+    }
+}
+
+/// Parse durations like `30s`, `10m`, `2h` or `1d`, a number without unit is in minutes.
+///
+/// Durations longer than `MAX_DURATION_SECS` are refused.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let (number, unit) = match text.find(|ch: char| !ch.is_ascii_digit()) {
+        Some(index) => text.split_at(index),
+        None => (text, "m"),
+    };
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    let number: u64 = number.parse().ok().filter(|number| *number > 0)?;
+    number
+        .checked_mul(secs)
+        .filter(|secs| *secs <= MAX_DURATION_SECS)
+        .map(Duration::from_secs)
+}
+
+/// Describe a duration shortly, like `1h 30m`, seconds are rounded up to minutes.
+pub fn format_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs().div_ceil(60);
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    let parts: Vec<String> = [(days, "d"), (hours, "h"), (minutes, "m")]
+        .iter()
+        .filter(|(number, _)| *number > 0)
+        .map(|(number, unit)| format!("{}{}", number, unit))
+        .collect();
+    match parts.is_empty() {
+        true => "0m".to_string(),
+        false => parts.join(" "),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Some(Duration::from_secs(86400)));
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("5y"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(
+            parse_duration("365d"),
+            Some(Duration::from_secs(MAX_DURATION_SECS))
+        );
+        assert_eq!(parse_duration("366d"), None);
+        assert_eq!(parse_duration("200000000000000d"), None);
+        assert_eq!(format_duration(Duration::from_secs(30)), "1m");
+        assert_eq!(format_duration(Duration::from_secs(5400)), "1h 30m");
+        assert_eq!(format_duration(Duration::from_secs(90000)), "1d 1h");
+    }
+
+    #[test]
+    fn save_bans() {
+        let path = std::env::temp_dir().join(format!("chamber-bans-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut bans = BanList::load(path.clone()).unwrap();
+        assert!(bans.ban("mallory").unwrap());
+        assert!(!bans.ban("mallory").unwrap());
+        assert!(bans.ban("10.0.0.7").unwrap());
+        assert!(bans.ban("::1").unwrap());
+        assert!(bans.unban("::1").unwrap());
+        assert!(!bans.unban("alice").unwrap());
+
+        let loaded = BanList::load(path.clone()).unwrap();
+        assert!(loaded.is_nick_banned("mallory"));
+        assert!(!loaded.is_nick_banned("alice"));
+        assert!(loaded.is_ip_banned(&"10.0.0.7".parse().unwrap()));
+        assert!(!loaded.is_ip_banned(&"::1".parse().unwrap()));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "nick mallory\nip 10.0.0.7\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{
+    command::CommandRegistry,
+    keymap::{Action, Keymap},
+};
+
+/// Things that can be done from the command palette.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaletteAction {
+    Run(Action),
+    /// Run a slash command, commands with arguments are put into editor
+    Command(&'static str),
+}
+
+/// A line in the command palette.
+pub struct PaletteEntry {
+    pub label: String,
+    /// Keys or command doing the same thing
+    pub keys: String,
+    pub action: PaletteAction,
+}
+
+/// Actions worth listing in the palette, moving cursor and deleting chars are not.
+const PALETTE_ACTIONS: [Action; 9] = [
+    Action::FocusNext,
+    Action::FocusPrev,
+    Action::NextRoom,
+    Action::PrevRoom,
+    Action::PageUp,
+    Action::PageDown,
+    Action::ClearInput,
+    Action::ShowHelp,
+    Action::Quit,
+];
+
+/// Get all entries of the palette: actions with their keys in `keymap`, then all commands.
+pub fn palette_entries(commands: &CommandRegistry, keymap: &Keymap) -> Vec<PaletteEntry> {
+    let mut entries: Vec<PaletteEntry> = PALETTE_ACTIONS
+        .iter()
+        .map(|action| PaletteEntry {
+            label: action.description().to_string(),
+            keys: keymap.keys_for(*action),
+            action: PaletteAction::Run(*action),
+        })
+        .collect();
+    entries.extend(commands.commands().iter().map(|cmd| PaletteEntry {
+        label: cmd.description.to_string(),
+        keys: cmd.usage.to_string(),
+        action: PaletteAction::Command(cmd.name),
+    }));
+    entries
+}
+
+/// State of the command palette popup.
+#[derive(Default)]
+pub struct Palette {
+    pub filter: String,
+    /// Index of the selected entry in the filtered entries
+    pub selected: usize,
+}
+impl Palette {
+    /// Get entries matching `filter`, better matches come first.
+    pub fn filter<'a>(&self, entries: &'a [PaletteEntry]) -> Vec<&'a PaletteEntry> {
+        let mut matched: Vec<(i32, &PaletteEntry)> = entries
+            .iter()
+            .filter_map(|entry| {
+                let label_score = fuzzy_score(&self.filter, &entry.label);
+                let keys_score = fuzzy_score(&self.filter, &entry.keys);
+                label_score.max(keys_score).map(|score| (score, entry))
+            })
+            .collect();
+        // stable sort keeps the original order of entries with the same score
+        matched.sort_by_key(|(score, _)| -score);
+        matched.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    /// Move selection, `step` can be negative.
+    pub fn select(&mut self, step: isize, entry_num: usize) {
+        if entry_num == 0 {
+            self.selected = 0;
+        } else {
+            self.selected = (self.selected as isize + step).rem_euclid(entry_num as isize) as usize;
+        }
+    }
+}
+
+/// Score how well `pattern` matches `text`, `None` if chars of `pattern`
+/// do not appear in `text` in order.
+///
+/// Matching ignores case, and consecutive chars or chars at start of words score more.
+pub fn fuzzy_score(pattern: &str, text: &str) -> Option<i32> {
+    let text: Vec<char> = text.chars().flat_map(char::to_lowercase).collect();
+    let mut score = 0;
+    let mut text_index = 0;
+    let mut prev_matched: Option<usize> = None;
+    for pattern_ch in pattern.chars().flat_map(char::to_lowercase) {
+        if pattern_ch.is_whitespace() {
+            continue;
+        }
+        let found = text[text_index..].iter().position(|ch| *ch == pattern_ch)? + text_index;
+        score += 1;
+        if prev_matched.map(|prev| prev + 1 == found).unwrap_or(false) {
+            score += 5;
+        }
+        if found == 0 || !text[found - 1].is_alphanumeric() {
+            score += 3;
+        }
+        prev_matched = Some(found);
+        text_index = found + 1;
+    }
+    Some(score)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fuzzy_match() {
+        assert!(fuzzy_score("nr", "Next room").is_some());
+        assert!(fuzzy_score("NEXT", "Next room").is_some());
+        assert!(fuzzy_score("rn", "Next room").is_none());
+        assert_eq!(fuzzy_score("", "Next room"), Some(0));
+        assert!(fuzzy_score("next", "Next room") > fuzzy_score("next", "New text"));
+    }
+
+    #[test]
+    fn filter_entries() {
+        let entries = palette_entries(&CommandRegistry::default(), &Keymap::default());
+        let palette = Palette {
+            filter: "join".to_string(),
+            selected: 0,
+        };
+        let matched = palette.filter(&entries);
+        assert_eq!(matched[0].action, PaletteAction::Command("join"));
+
+        let palette = Palette {
+            filter: "ctrl+q".to_string(),
+            selected: 0,
+        };
+        let matched = palette.filter(&entries);
+        assert_eq!(matched[0].action, PaletteAction::Run(Action::Quit));
+
+        let palette = Palette::default();
+        assert_eq!(palette.filter(&entries).len(), entries.len());
+    }
+
+    #[test]
+    fn move_selection() {
+        let mut palette = Palette::default();
+        palette.select(-1, 3);
+        assert_eq!(palette.selected, 2);
+        palette.select(1, 3);
+        assert_eq!(palette.selected, 0);
+        palette.select(1, 0);
+        assert_eq!(palette.selected, 0);
+    }
+}
@@ -117,6 +117,7 @@ impl<'a> Paragraph<'a> {
         self
     }
 
+    #[allow(dead_code)]
     pub fn style(mut self, style: Style) -> Paragraph<'a> {
         self.style = style;
         self
@@ -132,6 +133,7 @@ impl<'a> Paragraph<'a> {
         self
     }
 
+    #[allow(dead_code)]
     pub fn alignment(mut self, alignment: Alignment) -> Paragraph<'a> {
         self.alignment = alignment;
         self
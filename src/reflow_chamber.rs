@@ -1,6 +1,7 @@
 /// Rewrite WordWrapper and Paragraph to implement text wrapping with optional word-breaking.
 /// Inspired by a branch of tui-rs at https://github.com/Phoenix-Chen/tui-rs/tree/optional_trim_end
-use tui::text::StyledGrapheme;
+use std::iter;
+use tui::text::{Spans, StyledGrapheme};
 use unicode_segmentation::UnicodeSegmentation;
 use unicode_width::UnicodeWidthStr;
 
@@ -235,6 +236,54 @@ fn trim_offset(src: &str, mut offset: usize) -> &str {
     &src[start..]
 }
 
+/// Wrap `lines` like `Paragraph` with `Wrap { trim, break_words }` does,
+/// each row displayed is returned with its graphemes.
+pub fn wrap_lines<'a>(
+    lines: &'a [Spans<'a>],
+    max_width: u16,
+    trim: bool,
+    break_words: bool,
+) -> Vec<Vec<StyledGrapheme<'a>>> {
+    let mut styled = lines.iter().flat_map(|spans| {
+        spans
+            .0
+            .iter()
+            .flat_map(|span| span.styled_graphemes(Default::default()))
+            .chain(iter::once(StyledGrapheme {
+                symbol: "\n",
+                style: Default::default(),
+            }))
+    });
+    let mut wrapper = WordWrapper::new(&mut styled, max_width, trim, break_words);
+    let mut rows = vec![];
+    while let Some((row, _)) = wrapper.next_line() {
+        rows.push(row.to_vec());
+    }
+    rows
+}
+
+/// Cut `text` into one line no wider than `max_width`, `…` is added if anything is cut off.
+pub fn truncate_line(text: &str, max_width: u16) -> String {
+    let is_cut = text.contains('\n') || text.width() > max_width as usize;
+    if !is_cut {
+        return text.to_string();
+    }
+    if max_width == 0 {
+        return String::new();
+    }
+    let style = Default::default();
+    let mut styled =
+        UnicodeSegmentation::graphemes(text, true).map(|g| StyledGrapheme { symbol: g, style });
+    // leave room for the ellipsis
+    let mut truncator = LineTruncator::new(&mut styled, max_width - 1);
+    let mut line: String = truncator
+        .next_line()
+        .map(|(line, _)| line.iter().map(|grapheme| grapheme.symbol).collect())
+        .unwrap_or_default();
+    line.push('…');
+    line
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -351,7 +400,7 @@ mod test {
         let (word_wrapper_single_space, _) = run_composer(
             Composer::WordWrapper {
                 trim: true,
-                break_words: true,
+                break_words: false,
             },
             text,
             width as u16,
@@ -359,7 +408,7 @@ mod test {
         let (word_wrapper_multi_space, _) = run_composer(
             Composer::WordWrapper {
                 trim: true,
-                break_words: true,
+                break_words: false,
             },
             text_multi_space,
             width as u16,
@@ -445,7 +494,7 @@ mod test {
         let (word_wrapper, _) = run_composer(
             Composer::WordWrapper {
                 trim: true,
-                break_words: true,
+                break_words: false,
             },
             text,
             width,
@@ -532,7 +581,7 @@ mod test {
         let (word_wrapper, _) = run_composer(
             Composer::WordWrapper {
                 trim: true,
-                break_words: true,
+                break_words: false,
             },
             text,
             width,
@@ -558,7 +607,7 @@ mod test {
         let (word_wrapper, word_wrapper_width) = run_composer(
             Composer::WordWrapper {
                 trim: true,
-                break_words: true,
+                break_words: false,
             },
             text,
             width,
@@ -586,7 +635,7 @@ mod test {
         let (word_wrapper, _) = run_composer(
             Composer::WordWrapper {
                 trim: true,
-                break_words: true,
+                break_words: false,
             },
             text,
             width,
@@ -598,7 +647,7 @@ mod test {
         let (word_wrapper_space, _) = run_composer(
             Composer::WordWrapper {
                 trim: true,
-                break_words: true,
+                break_words: false,
             },
             &text_space,
             width,
@@ -606,6 +655,22 @@ mod test {
         assert_eq!(word_wrapper_space, vec!["AAAAAAAAAAAAAAA AAAA", "AAA",]);
     }
 
+    /// Tests WordWrapper breaking words at the end of line when `break_words` is enabled.
+    #[test]
+    fn line_composer_word_wrapper_break_words() {
+        let width = 10;
+        let text = "abcd efghij klmnop";
+        let (word_wrapper, _) = run_composer(
+            Composer::WordWrapper {
+                trim: true,
+                break_words: true,
+            },
+            text,
+            width,
+        );
+        assert_eq!(word_wrapper, vec!["abcd efghi", "j klmnop"]);
+    }
+
     #[test]
     fn line_composer_word_wrapper_preserve_indentation() {
         let width = 20;
@@ -628,7 +693,7 @@ mod test {
         let (word_wrapper, _) = run_composer(
             Composer::WordWrapper {
                 trim: false,
-                break_words: true,
+                break_words: false,
             },
             text,
             width,
@@ -646,7 +711,7 @@ mod test {
         let (word_wrapper, _) = run_composer(
             Composer::WordWrapper {
                 trim: false,
-                break_words: true,
+                break_words: false,
             },
             text,
             width,
@@ -663,4 +728,71 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn wrap_lines_like_paragraph() {
+        let lines = vec![
+            Spans::from("abc defgh ijk"),
+            Spans::from(""),
+            Spans::from("十十十十十"),
+        ];
+        let rows: Vec<String> = wrap_lines(&lines, 8, false, false)
+            .iter()
+            .map(|row| row.iter().map(|grapheme| grapheme.symbol).collect())
+            .collect();
+        assert_eq!(rows, vec!["abc", "defgh", "ijk", "", "十十十十", "十"]);
+        assert!(wrap_lines(&lines, 0, false, false).is_empty());
+    }
+
+    #[test]
+    fn truncate_to_one_line() {
+        assert_eq!(truncate_line("short", 10), "short");
+        assert_eq!(truncate_line("abcdefghijkl", 10), "abcdefghi…");
+        assert_eq!(truncate_line("first\nsecond", 10), "first…");
+        // a wide char that does not fit is dropped
+        assert_eq!(truncate_line("abcdefgh十十", 10), "abcdefgh…");
+        assert_eq!(truncate_line("abc", 0), "");
+    }
+
+    /// Tests WordWrapper keeping style of every symbol when a styled word is wrapped.
+    #[test]
+    fn line_composer_word_wrapper_keeps_styles() {
+        use tui::style::{Modifier, Style};
+
+        let plain = Style::default();
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let mut styled = UnicodeSegmentation::graphemes("abc defgh", true)
+            .map(|g| StyledGrapheme {
+                symbol: g,
+                style: plain,
+            })
+            .chain(
+                UnicodeSegmentation::graphemes("ijk lmn", true).map(|g| StyledGrapheme {
+                    symbol: g,
+                    style: bold,
+                }),
+            );
+        let mut composer = WordWrapper::new(&mut styled, 8, true, false);
+        let mut lines = vec![];
+        while let Some((line, _)) = composer.next_line() {
+            lines.push(
+                line.iter()
+                    .map(|StyledGrapheme { symbol, style }| (*symbol, *style == bold))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        fn expected(text: &'static str, bold_from: usize) -> Vec<(&'static str, bool)> {
+            (0..text.len())
+                .map(|i| (&text[i..i + 1], i >= bold_from))
+                .collect()
+        }
+        assert_eq!(
+            lines,
+            vec![
+                expected("abc", 3),
+                expected("defghijk", 5),
+                expected("lmn", 0)
+            ]
+        );
+    }
 }
@@ -0,0 +1,309 @@
+use std::{fmt::Display, ops::Range};
+
+use chrono::{Local, NaiveDate, TimeZone};
+use tui::{
+    style::Style,
+    text::{Span, Spans},
+};
+
+use crate::message::Message;
+
+/// A search over msgs kept by server, like `from:alice after:2022-08-01 release`.
+///
+/// Text other than filters is matched as a substring of msg content, case is ignored.
+#[derive(Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    pub text: String,
+    /// Nickname of the author
+    pub from: Option<String>,
+    /// Msgs sent before this unix timestamp
+    pub before: Option<i64>,
+    /// Msgs sent at or after this unix timestamp
+    pub after: Option<i64>,
+}
+impl Display for SearchQuery {
+    /// Dates are written as timestamps, so server reads them in the timezone of the client.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if let Some(from) = &self.from {
+            parts.push(format!("from:{}", from));
+        }
+        if let Some(before) = self.before {
+            parts.push(format!("before:{}", before));
+        }
+        if let Some(after) = self.after {
+            parts.push(format!("after:{}", after));
+        }
+        if !self.text.is_empty() {
+            parts.push(self.text.clone());
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+impl SearchQuery {
+    /// Parse a query, dates are `YYYY-MM-DD` in local time or unix timestamps.
+    pub fn parse(query: &str) -> Result<SearchQuery, String> {
+        let mut search = SearchQuery::default();
+        let mut words = vec![];
+        for word in query.split_whitespace() {
+            match word.split_once(':') {
+                Some(("from", nick)) if !nick.is_empty() => search.from = Some(nick.to_string()),
+                Some(("before", date)) => search.before = Some(parse_date(date)?),
+                Some(("after", date)) => search.after = Some(parse_date(date)?),
+                _ => words.push(word),
+            }
+        }
+        search.text = words.join(" ");
+        if search == SearchQuery::default() {
+            return Err("Nothing to search for.".to_string());
+        }
+        Ok(search)
+    }
+
+    pub fn matches(&self, msg: &Message) -> bool {
+        self.from
+            .as_ref()
+            .map(|from| msg.msg_sender.eq_ignore_ascii_case(from))
+            .unwrap_or(true)
+            && self
+                .before
+                .map(|before| msg.msg_time < before)
+                .unwrap_or(true)
+            && self
+                .after
+                .map(|after| msg.msg_time >= after)
+                .unwrap_or(true)
+            && msg
+                .msg_content
+                .to_lowercase()
+                .contains(&self.text.to_lowercase())
+    }
+}
+
+/// Parse `YYYY-MM-DD` into the timestamp of its local midnight, or take a timestamp as it is.
+fn parse_date(date: &str) -> Result<i64, String> {
+    if let Ok(timestamp) = date.parse() {
+        return Ok(timestamp);
+    }
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .and_then(|time| Local.from_local_datetime(&time).earliest())
+        .map(|time| time.timestamp())
+        .ok_or_else(|| format!("Invalid date \"{}\", use YYYY-MM-DD.", date))
+}
+
+/// State of the search results popup.
+pub struct SearchResults {
+    pub query: String,
+    /// Msgs found, the oldest comes first
+    pub results: Vec<Message>,
+    /// Index of the selected result
+    pub selected: usize,
+}
+impl SearchResults {
+    pub fn new(query: &str) -> SearchResults {
+        SearchResults {
+            query: query.to_string(),
+            results: vec![],
+            selected: 0,
+        }
+    }
+
+    /// Add a result, the newest result is selected.
+    pub fn push(&mut self, msg: Message) {
+        self.results.push(msg);
+        self.selected = self.results.len() - 1;
+    }
+
+    /// Move selection, `step` can be negative.
+    pub fn select(&mut self, step: isize) {
+        if self.results.is_empty() {
+            return;
+        }
+        self.selected =
+            (self.selected as isize + step).clamp(0, self.results.len() as isize - 1) as usize;
+    }
+}
+
+/// State of searching msgs displayed in current room.
+#[derive(Default)]
+pub struct LocalSearch {
+    pub query: String,
+    /// The query is being typed
+    pub typing: bool,
+    /// Index of the current match, counted from the newest one
+    pub current: usize,
+}
+
+/// Find where `query` appears in `text`, case is ignored and matches do not overlap.
+pub fn find_matches(text: &str, query: &str) -> Vec<Range<usize>> {
+    let query: Vec<char> = query.chars().collect();
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut ranges = vec![];
+    if query.is_empty() {
+        return ranges;
+    }
+    let mut index = 0;
+    while index + query.len() <= chars.len() {
+        let matched = chars[index..index + query.len()]
+            .iter()
+            .zip(&query)
+            .all(|((_, ch), query_ch)| ch.to_lowercase().eq(query_ch.to_lowercase()));
+        if matched {
+            let end = chars
+                .get(index + query.len())
+                .map(|(pos, _)| *pos)
+                .unwrap_or(text.len());
+            ranges.push(chars[index].0..end);
+            index += query.len();
+        } else {
+            index += 1;
+        }
+    }
+    ranges
+}
+
+/// Highlight `query` in `lines` by splitting spans, and return the number of matches.
+///
+/// Matches can cross spans, the `current` one (counted in all lines) gets `current_style`.
+pub fn highlight_matches(
+    lines: &mut [Spans<'static>],
+    query: &str,
+    current: Option<usize>,
+    match_style: Style,
+    current_style: Style,
+) -> usize {
+    let mut count = 0;
+    for line in lines.iter_mut() {
+        let text: String = line.0.iter().map(|span| span.content.as_ref()).collect();
+        let ranges = find_matches(&text, query);
+        if ranges.is_empty() {
+            continue;
+        }
+        let mut spans = vec![];
+        let mut offset = 0;
+        for span in line.0.drain(..) {
+            let content = span.content.as_ref();
+            let end = offset + content.len();
+            let mut pos = offset;
+            while pos < end {
+                // the match containing `pos`, or the text before the next match
+                let (piece_end, matched) = match ranges.iter().position(|range| range.end > pos) {
+                    Some(k) if ranges[k].start <= pos => (ranges[k].end.min(end), Some(count + k)),
+                    Some(k) => (ranges[k].start.min(end), None),
+                    None => (end, None),
+                };
+                let style = match matched {
+                    Some(k) if Some(k) == current => span.style.patch(current_style),
+                    Some(_) => span.style.patch(match_style),
+                    None => span.style,
+                };
+                spans.push(Span::styled(
+                    content[pos - offset..piece_end - offset].to_string(),
+                    style,
+                ));
+                pos = piece_end;
+            }
+            offset = end;
+        }
+        line.0 = spans;
+        count += ranges.len();
+    }
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn msg(sender: &str, time: i64, content: &str) -> Message {
+        Message {
+            msg_sender: sender.to_string(),
+            msg_time: time,
+            msg_content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_queries() {
+        let query = SearchQuery::parse("from:alice  Release notes before:100").unwrap();
+        assert_eq!(query.from.as_deref(), Some("alice"));
+        assert_eq!(query.before, Some(100));
+        assert_eq!(query.after, None);
+        assert_eq!(query.text, "Release notes");
+        assert_eq!(query.to_string(), "from:alice before:100 Release notes");
+        assert_eq!(SearchQuery::parse(&query.to_string()), Ok(query));
+
+        let query = SearchQuery::parse("after:2022-08-01").unwrap();
+        let midnight = Local.with_ymd_and_hms(2022, 8, 1, 0, 0, 0).unwrap();
+        assert_eq!(query.after, Some(midnight.timestamp()));
+
+        assert!(SearchQuery::parse("after:yesterday").is_err());
+        assert!(SearchQuery::parse("  ").is_err());
+        // not a filter
+        assert_eq!(SearchQuery::parse("re:hi").unwrap().text, "re:hi");
+    }
+
+    #[test]
+    fn match_msgs() {
+        let query = SearchQuery::parse("from:Alice after:100 before:200 hello").unwrap();
+        assert!(query.matches(&msg("alice", 100, "Oh, HELLO there")));
+        assert!(!query.matches(&msg("bob", 150, "hello")));
+        assert!(!query.matches(&msg("alice", 200, "hello")));
+        assert!(!query.matches(&msg("alice", 99, "hello")));
+        assert!(!query.matches(&msg("alice", 150, "hi")));
+    }
+
+    #[test]
+    fn find_and_highlight() {
+        assert_eq!(find_matches("Abc abc ABC", "bc"), vec![1..3, 5..7, 9..11]);
+        assert_eq!(find_matches("aaaa", "aa"), vec![0..2, 2..4]);
+        assert_eq!(find_matches("星星 star", "星"), vec![0..3, 3..6]);
+        assert!(find_matches("abc", "").is_empty());
+
+        let plain = Style::default();
+        let matched = Style::default().bg(tui::style::Color::Blue);
+        let current = Style::default().bg(tui::style::Color::Red);
+        let mut lines = vec![
+            Spans::from(vec![Span::raw("alice"), Span::raw(": hi al")]),
+            Spans::from(Span::raw("no")),
+        ];
+        let count = highlight_matches(&mut lines, "e: h", Some(0), matched, current);
+        assert_eq!(count, 1);
+        let spans: Vec<(&str, Style)> = lines[0]
+            .0
+            .iter()
+            .map(|span| (span.content.as_ref(), span.style))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                ("alic", plain),
+                ("e", current),
+                (": h", current),
+                ("i al", plain)
+            ]
+        );
+        let count = highlight_matches(&mut lines, "al", Some(1), matched, current);
+        assert_eq!(count, 2);
+        assert_eq!(lines[0].0[0].style, matched);
+        assert_eq!(lines[0].0.last().unwrap().content, "al");
+        assert_eq!(lines[0].0.last().unwrap().style, current);
+    }
+
+    #[test]
+    fn select_results() {
+        let mut results = SearchResults::new("hi");
+        results.select(-1);
+        assert_eq!(results.selected, 0);
+        results.push(msg("alice", 1, "hi"));
+        results.push(msg("bob", 2, "hi"));
+        assert_eq!(results.selected, 1);
+        results.select(-5);
+        assert_eq!(results.selected, 0);
+        results.select(1);
+        assert_eq!(results.selected, 1);
+    }
+}
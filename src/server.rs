@@ -1,27 +1,1471 @@
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    str::from_utf8,
-    sync::mpsc::{self, Sender},
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    io::{BufRead, BufReader, ErrorKind},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    consts::MSG_BUF_SIZE,
-    message::{Message, MessageType},
+    access::{AccessList, Connections},
+    accounts::{hash_password, verify_password, Accounts},
+    admin::{self, AdminCommand, AdminRequest},
+    config::{Config, FloodAction, LimitsConfig},
+    consts::{
+        AUTH_SECS, DEFAULT_ROOM, HISTORY_SIZE, PASSWORD_JOBS_PER_CLIENT, SEARCH_RESULTS,
+        SERVER_ADDR,
+    },
+    e2e::parse_public_key,
+    flood::{IpBuckets, Limiter, Verdict},
+    gate::Gate,
+    logger,
+    message::{
+        format_reactions, is_valid_nickname, is_valid_reaction, is_valid_room_name, Message,
+        MessageType,
+    },
+    metrics::{self, Counted, Metrics},
+    moderation::{format_duration, parse_duration, BanList},
+    search::SearchQuery,
+    transport::{Acceptor, ChatStream},
 };
+use log::{debug, error, info, trace, warn};
 
-/// TcpStream with a temp id
+/// Stream with a temp id
+#[derive(Clone)]
 struct WrappedStream {
     stream_id: u32,
-    stream: TcpStream,
+    stream: ChatStream,
 }
-impl Clone for WrappedStream {
-    fn clone(&self) -> Self {
-        Self {
-            stream_id: self.stream_id.clone(),
-            stream: self.stream.try_clone().expect("Failed to clone stream."),
+
+/// A msg received from a client, tagged with the id of its stream
+struct ClientMessage {
+    stream_id: u32,
+    msg: Message,
+}
+
+/// Something wrong a client does, found by the thread of its connection
+enum Abuse {
+    /// A msg is dropped for being sent too fast
+    TooFast,
+    /// A msg line is refused, for being too long or not UTF-8
+    BadFrame(String),
+    /// Msgs keep being sent too fast
+    Flooding,
+}
+
+/// A password to check off the chamber loop, as Argon2 is slow on purpose
+struct PasswordJob {
+    id: u32,
+    nick: String,
+    password: String,
+    /// Hash of the account to log in, `None` to register the account
+    hash: Option<String>,
+}
+
+/// A password checked by `check_passwords`
+struct PasswordChecked {
+    id: u32,
+    nick: String,
+    /// Hash of the password for a new account, `None` for logging in
+    result: Result<Option<String>, String>,
+}
+
+/// An online client
+struct ConnectedClient {
+    name: String,
+    stream: ChatStream,
+    /// Key for encrypted direct msgs in base64, `None` if not published
+    public_key: Option<String>,
+    /// Nickname of the account logged in, `None` for guests
+    account: Option<String>,
+    addr: Option<SocketAddr>,
+    /// When the client connected
+    since: Instant,
+    /// Made an operator by another one, operators in config are not marked
+    is_op: bool,
+}
+
+/// A msg kept by server, so its author can edit or delete it later
+struct SentMsg {
+    /// Id of the author's stream
+    author: u32,
+    /// Id of the receiver's stream, for direct msgs
+    target: Option<u32>,
+    msg: Message,
+    /// Reactions and ids of clients reacting, in the order they are first added
+    reactions: Vec<(String, HashSet<u32>)>,
+}
+impl SentMsg {
+    /// Check if client `id` has received the msg, and is still able to see it.
+    fn is_receiver(&self, id: u32, chamber: &Chamber) -> bool {
+        match self.target {
+            Some(target) => id == target || id == self.author,
+            None => chamber.is_member(id, &self.msg.msg_room),
+        }
+    }
+
+    /// Add or take back a reaction of client `id`.
+    fn toggle_reaction(&mut self, id: u32, token: &str) {
+        match self.reactions.iter_mut().find(|(t, _)| t == token) {
+            Some((_, reactors)) => {
+                if !reactors.remove(&id) {
+                    reactors.insert(id);
+                }
+            }
+            None => self
+                .reactions
+                .push((token.to_string(), HashSet::from([id]))),
+        }
+        self.reactions.retain(|(_, reactors)| !reactors.is_empty());
+    }
+}
+
+/// State of the server: online clients and the rooms they have joined
+#[derive(Default)]
+struct Chamber {
+    clients: HashMap<u32, ConnectedClient>,
+    /// room name -> ids of members
+    rooms: HashMap<String, HashSet<u32>>,
+    /// Id of the last msg kept
+    last_msg_id: u64,
+    /// Recent msgs, the oldest comes first
+    history: VecDeque<SentMsg>,
+    /// Registered accounts, `None` if accounts are off
+    accounts: Option<Accounts>,
+    /// Clients can not chat before logging in
+    require_login: bool,
+    /// Accounts made operators in config
+    operators: Vec<String>,
+    /// Mutes by `mute_keys` of clients, so they are kept when clients reconnect
+    mutes: HashMap<String, Instant>,
+    /// Shared with threads of new connections, which check banned IPs
+    bans: Arc<Mutex<BanList>>,
+    /// Shared with the thread accepting connections
+    access: Arc<Mutex<AccessList>>,
+    limits: LimitsConfig,
+    /// Shared with all threads of server
+    metrics: Arc<Metrics>,
+    /// Passwords are sent to the thread of `check_passwords`
+    password_jobs: Option<Sender<PasswordJob>>,
+    /// Id of client -> number of its passwords being checked
+    pending_passwords: HashMap<u32, usize>,
+}
+impl Chamber {
+    /// Get name of a client, or an empty string if the client is offline.
+    fn client_name(&self, id: u32) -> String {
+        self.clients
+            .get(&id)
+            .map(|client| client.name.clone())
+            .unwrap_or_default()
+    }
+
+    /// Send a msg to one client.
+    ///
+    /// Failures are ignored here, the thread reading from this client
+    /// will find out that it is offline.
+    fn send_to(&self, id: u32, msg: &Message) {
+        if let Some(client) = self.clients.get(&id) {
+            match msg.write_to(&mut Counted::new(&client.stream, self.metrics.clone())) {
+                Ok(()) => self.metrics.msg_sent(),
+                Err(err) => {
+                    self.metrics.msg_dropped();
+                    warn!("Failed to send msg to client {}: {}", id, err);
+                }
+            }
+        }
+    }
+
+    /// Send a msg to all members of `room`.
+    fn send_to_room(&self, room: &str, msg: &Message) {
+        if let Some(members) = self.rooms.get(room) {
+            for id in members {
+                self.send_to(*id, msg);
+            }
+        }
+    }
+
+    /// Relay a typing event to the other members of its room, or to the receiver of direct msgs.
+    fn relay_typing(&self, id: u32, mut msg: Message) {
+        msg.msg_sender = self.client_name(id);
+        if !msg.msg_target.is_empty() {
+            if let Some(target) = self
+                .find_client(&msg.msg_target)
+                .filter(|target| *target != id)
+            {
+                msg.msg_target = self.client_name(target);
+                self.send_to(target, &msg);
+            }
+        } else if self.is_member(id, &msg.msg_room) {
+            for member in &self.rooms[&msg.msg_room] {
+                if *member != id {
+                    self.send_to(*member, &msg);
+                }
+            }
+        }
+    }
+
+    /// Tell a client what went wrong with its request.
+    fn send_error(&self, id: u32, room: &str, content: String) {
+        let msg = Message {
+            msg_type: MessageType::Error,
+            msg_sender: "server".to_string(),
+            msg_room: room.to_string(),
+            msg_content: content,
+            ..Default::default()
+        };
+        self.send_to(id, &msg);
+    }
+
+    /// Find a client by its name, or by its id if no client has such a name.
+    ///
+    /// Clients waiting to log in are not found.
+    fn find_client(&self, name_or_id: &str) -> Option<u32> {
+        self.clients
+            .iter()
+            .find(|(id, client)| client.name == name_or_id && !self.is_pending(**id))
+            .map(|(id, _)| *id)
+            .or_else(|| {
+                name_or_id
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|id| self.clients.contains_key(id) && !self.is_pending(*id))
+            })
+    }
+
+    /// Check if a client has to log in before chatting.
+    fn is_pending(&self, id: u32) -> bool {
+        self.require_login
+            && self
+                .clients
+                .get(&id)
+                .map(|client| client.account.is_none())
+                .unwrap_or(false)
+    }
+
+    /// Send updated client list to all clients, the list looks like `0:name,1:name`.
+    fn broadcast_client_list(&self) {
+        let mut ids: Vec<&u32> = self
+            .clients
+            .keys()
+            .filter(|id| !self.is_pending(**id))
+            .collect();
+        ids.sort();
+        let client_list: Vec<String> = ids
+            .iter()
+            .map(|id| format!("{}:{}", id, self.clients[id].name))
+            .collect();
+        let msg = Message {
+            msg_type: MessageType::ClientListUpdate,
+            msg_sender: "server".to_string(),
+            msg_content: client_list.join(","),
+            ..Default::default()
+        };
+        for id in ids {
+            self.send_to(*id, &msg);
+        }
+    }
+
+    /// Give a msg an id and keep it, only `HISTORY_SIZE` msgs are kept.
+    fn keep_msg(&mut self, author: u32, target: Option<u32>, msg: &mut Message) {
+        self.last_msg_id += 1;
+        msg.msg_id = self.last_msg_id;
+        self.history.push_back(SentMsg {
+            author,
+            target,
+            msg: msg.clone(),
+            reactions: vec![],
+        });
+        if self.history.len() > HISTORY_SIZE {
+            self.history.pop_front();
+        }
+    }
+
+    /// Send a msg to everyone who has received the kept msg `sent`.
+    fn send_to_receivers(&self, sent: &SentMsg, msg: &Message) {
+        match sent.target {
+            Some(target) => {
+                self.send_to(target, msg);
+                if target != sent.author {
+                    self.send_to(sent.author, msg);
+                }
+            }
+            None => self.send_to_room(&sent.msg.msg_room, msg),
+        }
+    }
+
+    /// Find a kept msg that client `id` can change, errors are sent to the client.
+    fn find_own_msg(&self, id: u32, msg_id: u64) -> Option<usize> {
+        let index = self
+            .history
+            .iter()
+            .position(|sent| sent.msg.msg_id == msg_id);
+        match index {
+            Some(index) if self.history[index].author == id => Some(index),
+            Some(_) => {
+                self.send_error(
+                    id,
+                    DEFAULT_ROOM,
+                    "You can only change your own msgs.".to_string(),
+                );
+                None
+            }
+            None => {
+                self.send_error(
+                    id,
+                    DEFAULT_ROOM,
+                    format!("Msg {} is not found, it may be too old.", msg_id),
+                );
+                None
+            }
+        }
+    }
+
+    /// Change content of a msg and tell everyone who has received it.
+    fn edit_msg(&mut self, id: u32, msg: Message) {
+        if msg.msg_content.trim().is_empty() {
+            self.send_error(
+                id,
+                DEFAULT_ROOM,
+                "Content can not be empty, delete the msg instead.".to_string(),
+            );
+            return;
+        }
+        if let Some(index) = self.find_own_msg(id, msg.msg_id) {
+            let name = self.client_name(id);
+            let sent = &mut self.history[index];
+            sent.msg.msg_content = msg.msg_content;
+            let update = Message {
+                msg_type: MessageType::MessageEdit,
+                msg_sender: name,
+                msg_room: sent.msg.msg_room.clone(),
+                msg_target: sent.msg.msg_target.clone(),
+                msg_id: sent.msg.msg_id,
+                msg_content: sent.msg.msg_content.clone(),
+                ..Default::default()
+            };
+            self.send_to_receivers(&self.history[index], &update);
+        }
+    }
+
+    /// Delete a msg and tell everyone who has received it.
+    fn delete_msg(&mut self, id: u32, msg: Message) {
+        if let Some(index) = self.find_own_msg(id, msg.msg_id) {
+            if let Some(sent) = self.history.remove(index) {
+                let update = Message {
+                    msg_type: MessageType::MessageDelete,
+                    msg_sender: self.client_name(id),
+                    msg_room: sent.msg.msg_room.clone(),
+                    msg_target: sent.msg.msg_target.clone(),
+                    msg_id: sent.msg.msg_id,
+                    ..Default::default()
+                };
+                self.send_to_receivers(&sent, &update);
+            }
+        }
+    }
+
+    /// Add or take back a reaction, and tell everyone who has received the msg.
+    fn react(&mut self, id: u32, msg: Message) {
+        if !is_valid_reaction(&msg.msg_content) {
+            self.send_error(
+                id,
+                DEFAULT_ROOM,
+                "Reactions are emoji or short words without spaces, commas or colons.".to_string(),
+            );
+            return;
+        }
+        let index = self
+            .history
+            .iter()
+            .position(|sent| sent.msg.msg_id == msg.msg_id && sent.is_receiver(id, self));
+        let index = match index {
+            Some(index) => index,
+            None => {
+                self.send_error(
+                    id,
+                    DEFAULT_ROOM,
+                    format!("Msg {} is not found, it may be too old.", msg.msg_id),
+                );
+                return;
+            }
+        };
+        let name = self.client_name(id);
+        let sent = &mut self.history[index];
+        sent.toggle_reaction(id, &msg.msg_content);
+        let counts: Vec<(String, usize)> = sent
+            .reactions
+            .iter()
+            .map(|(token, reactors)| (token.clone(), reactors.len()))
+            .collect();
+        let update = Message {
+            msg_type: MessageType::Reaction,
+            msg_sender: name,
+            msg_room: sent.msg.msg_room.clone(),
+            msg_target: sent.msg.msg_target.clone(),
+            msg_id: sent.msg.msg_id,
+            msg_content: format_reactions(&counts),
+            ..Default::default()
+        };
+        self.send_to_receivers(&self.history[index], &update);
+    }
+
+    /// Check if the msg a reply quotes is kept, and in the same room or direct msgs as the reply.
+    ///
+    /// `target` is the receiver of a direct msg, errors are sent to client `id`.
+    fn check_reply(&self, id: u32, target: Option<u32>, msg: &Message) -> bool {
+        if msg.msg_reply_to == 0 {
+            return true;
+        }
+        let found = self.history.iter().any(|sent| {
+            sent.msg.msg_id == msg.msg_reply_to
+                && match (sent.target, target) {
+                    (None, None) => sent.msg.msg_room == msg.msg_room,
+                    (Some(sent_target), Some(target)) => {
+                        (sent.author, sent_target) == (id, target)
+                            || (sent.author, sent_target) == (target, id)
+                    }
+                    _ => false,
+                }
+        });
+        if !found {
+            self.send_error(
+                id,
+                DEFAULT_ROOM,
+                format!(
+                    "Msg {} is not found here, it may be too old.",
+                    msg.msg_reply_to
+                ),
+            );
+        }
+        found
+    }
+
+    /// Send msgs kept that client `id` can see and match the query, after the query itself.
+    fn search(&self, id: u32, msg: Message) {
+        let query = match SearchQuery::parse(&msg.msg_content) {
+            Ok(query) => query,
+            Err(reason) => {
+                self.send_error(id, &msg.msg_room, reason);
+                return;
+            }
+        };
+        let found: Vec<&SentMsg> = self
+            .history
+            .iter()
+            .rev()
+            .filter(|sent| sent.is_receiver(id, self) && query.matches(&sent.msg))
+            .take(SEARCH_RESULTS)
+            .collect();
+        let header = Message {
+            msg_type: MessageType::Search,
+            msg_sender: "server".to_string(),
+            msg_room: msg.msg_room,
+            msg_content: msg.msg_content,
+            ..Default::default()
+        };
+        self.send_to(id, &header);
+        for sent in found.into_iter().rev() {
+            self.send_to(id, &Message::search_result(&sent.msg));
+        }
+    }
+
+    /// Send a direct msg to its receiver, and echo it to the sender.
+    fn send_direct_msg(&mut self, id: u32, mut msg: Message) {
+        let target = match self.find_client(&msg.msg_target) {
+            Some(target) => target,
+            None => {
+                self.send_error(
+                    id,
+                    &msg.msg_room,
+                    format!("No such user: {}.", msg.msg_target),
+                );
+                return;
+            }
+        };
+        if !self.check_reply(id, Some(target), &msg) {
+            return;
+        }
+        msg.msg_sender = self.client_name(id);
+        msg.msg_target = self.client_name(target);
+        self.keep_msg(id, Some(target), &mut msg);
+        self.send_to(target, &msg);
+        if target != id {
+            self.send_to(id, &msg);
+        }
+    }
+
+    /// Rename a client and tell everyone.
+    fn change_nickname(&mut self, id: u32, new_name: &str) {
+        if !is_valid_nickname(new_name) {
+            self.send_error(
+                id,
+                DEFAULT_ROOM,
+                format!("Invalid nickname \"{}\".", new_name),
+            );
+            return;
+        }
+        if self.find_client(new_name).is_some() {
+            self.send_error(
+                id,
+                DEFAULT_ROOM,
+                format!("Nickname {} is already in use.", new_name),
+            );
+            return;
+        }
+        let is_registered = self
+            .accounts
+            .as_ref()
+            .map(|accounts| accounts.is_registered(new_name))
+            .unwrap_or(false);
+        let is_own = self
+            .clients
+            .get(&id)
+            .map(|client| client.account.as_deref() == Some(new_name))
+            .unwrap_or(false);
+        if self.bans.lock().unwrap().is_nick_banned(new_name) {
+            self.send_error(
+                id,
+                DEFAULT_ROOM,
+                format!("Nickname {} is banned.", new_name),
+            );
+            return;
+        }
+        if is_registered && !is_own {
+            self.send_error(
+                id,
+                DEFAULT_ROOM,
+                format!("Nickname {} is registered, /login to use it.", new_name),
+            );
+            return;
+        }
+        self.rename(id, new_name);
+    }
+
+    /// Rename a client without checking the new name, and tell everyone.
+    fn rename(&mut self, id: u32, new_name: &str) {
+        let old_name = self.client_name(id);
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.name = new_name.to_string();
+        }
+        let msg = Message {
+            msg_type: MessageType::NickChange,
+            msg_sender: old_name.clone(),
+            msg_target: new_name.to_string(),
+            msg_content: format!("{} is now known as {}.", old_name, new_name),
+            ..Default::default()
+        };
+        for id in self.clients.keys() {
+            self.send_to(*id, &msg);
+        }
+        self.broadcast_client_list();
+    }
+
+    fn is_member(&self, id: u32, room: &str) -> bool {
+        self.rooms
+            .get(room)
+            .map(|members| members.contains(&id))
+            .unwrap_or(false)
+    }
+
+    /// Save the stream of a new client, then let it join the default room.
+    ///
+    /// If login is required, the client only gets a prompt, and joins after logging in.
+    fn add_client(&mut self, client: WrappedStream) {
+        let id = client.stream_id;
+        self.clients.insert(
+            id,
+            ConnectedClient {
+                name: format!("client{}", id),
+                addr: client.stream.peer_addr().ok(),
+                since: Instant::now(),
+                stream: client.stream,
+                public_key: None,
+                account: None,
+                is_op: false,
+            },
+        );
+        if self.accounts.is_some() {
+            let prompt = Message {
+                msg_type: MessageType::LoginPrompt,
+                msg_sender: "server".to_string(),
+                msg_content: if self.require_login {
+                    "required"
+                } else {
+                    "optional"
+                }
+                .to_string(),
+                ..Default::default()
+            };
+            self.send_to(id, &prompt);
+        }
+        if !self.require_login {
+            self.welcome(id);
+        }
+        self.sync_metrics();
+    }
+
+    /// Tell a client its name, let it join the default room and give it keys of others.
+    fn welcome(&mut self, id: u32) {
+        let name = self.client_name(id);
+        let welcome = Message {
+            msg_type: MessageType::ClientLogIn,
+            msg_sender: name.clone(),
+            msg_content: format!("Welcome to Chamber, you are {}.", name),
+            ..Default::default()
+        };
+        self.send_to(id, &welcome);
+        self.join_room(id, DEFAULT_ROOM);
+        self.broadcast_client_list();
+        for other in self.clients.values() {
+            if let Some(key) = &other.public_key {
+                let msg = Message {
+                    msg_type: MessageType::PublicKey,
+                    msg_sender: other.name.clone(),
+                    msg_content: key.clone(),
+                    ..Default::default()
+                };
+                self.send_to(id, &msg);
+            }
+        }
+    }
+
+    /// Check the password off the chamber loop, or tell the reason it is refused at once.
+    fn login(&mut self, id: u32, msg: Message, register: bool) {
+        let nick = msg.msg_target;
+        let pending = self.pending_passwords.get(&id).copied().unwrap_or(0);
+        if pending >= PASSWORD_JOBS_PER_CLIENT {
+            let reason = "Your password is being checked, wait for it.".to_string();
+            self.send_error(id, DEFAULT_ROOM, reason);
+            return;
+        }
+        let job = self
+            .check_login(id, &nick, &msg.msg_content, register)
+            .and_then(|hash| {
+                let jobs = self
+                    .password_jobs
+                    .as_ref()
+                    .ok_or("Accounts are off on this server.")?;
+                jobs.send(PasswordJob {
+                    id,
+                    nick: nick.clone(),
+                    password: msg.msg_content,
+                    hash,
+                })
+                .map_err(|_| "Failed to check the password.".to_string())
+            });
+        match job {
+            Ok(()) => *self.pending_passwords.entry(id).or_default() += 1,
+            Err(reason) => self.send_error(id, DEFAULT_ROOM, reason),
+        }
+    }
+
+    /// Log a client in after its password is checked, saving the account if it is new.
+    ///
+    /// The client is renamed to the nickname of the account.
+    fn finish_login(&mut self, checked: PasswordChecked) {
+        let PasswordChecked { id, nick, result } = checked;
+        if let Some(pending) = self.pending_passwords.get_mut(&id) {
+            *pending -= 1;
+            if *pending == 0 {
+                self.pending_passwords.remove(&id);
+            }
+        }
+        if !self.clients.contains_key(&id) {
+            return;
+        }
+        // the nickname may be taken while the password is checked
+        let result = result.and_then(|hash| {
+            self.check_nick(id, &nick)?;
+            match (hash, self.accounts.as_mut()) {
+                (Some(hash), Some(accounts)) => accounts.add(&nick, hash),
+                _ => Ok(()),
+            }
+        });
+        if let Err(reason) = result {
+            self.send_error(id, DEFAULT_ROOM, reason);
+            return;
+        }
+        let was_pending = self.is_pending(id);
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.account = Some(nick.clone());
+        }
+        let reply = Message {
+            msg_type: MessageType::Login,
+            msg_sender: nick.clone(),
+            msg_content: format!("Logged in as {}.", nick),
+            ..Default::default()
+        };
+        self.send_to(id, &reply);
+        if was_pending {
+            if let Some(client) = self.clients.get_mut(&id) {
+                client.name = nick;
+            }
+            self.welcome(id);
+        } else if self.client_name(id) != nick {
+            self.rename(id, &nick);
+        }
+    }
+
+    /// Check a client can log in as `nick`, `Err` contains the reason.
+    fn check_nick(&self, id: u32, nick: &str) -> Result<(), String> {
+        if self
+            .clients
+            .get(&id)
+            .and_then(|client| client.account.as_ref())
+            .is_some()
+        {
+            return Err("You have logged in already.".to_string());
+        }
+        if self
+            .find_client(nick)
+            .filter(|other| *other != id)
+            .is_some()
+            || self
+                .clients
+                .values()
+                .any(|client| client.account.as_deref() == Some(nick))
+        {
+            return Err(format!("Nickname {} is already in use.", nick));
+        }
+        if self.bans.lock().unwrap().is_nick_banned(nick) {
+            return Err(format!("Nickname {} is banned.", nick));
+        }
+        Ok(())
+    }
+
+    /// Check what is quick to check before the password, `Ok` contains the hash of
+    /// the account to log in, or `None` to register it.
+    fn check_login(
+        &self,
+        id: u32,
+        nick: &str,
+        password: &str,
+        register: bool,
+    ) -> Result<Option<String>, String> {
+        self.check_nick(id, nick)?;
+        let accounts = self
+            .accounts
+            .as_ref()
+            .ok_or("Accounts are off on this server.")?;
+        if register {
+            accounts.check_new(nick, password)?;
+            Ok(None)
+        } else {
+            accounts
+                .hash_of(nick)
+                .map(|hash| Some(hash.to_string()))
+                .ok_or_else(|| "Wrong nickname or password.".to_string())
+        }
+    }
+
+    /// Keep the key of a client for encrypted direct msgs, and give it to everyone.
+    fn publish_key(&mut self, id: u32, msg: Message) {
+        if parse_public_key(&msg.msg_content).is_none() {
+            self.send_error(id, DEFAULT_ROOM, "Invalid public key.".to_string());
+            return;
+        }
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.public_key = Some(msg.msg_content.clone());
+        }
+        let msg = Message {
+            msg_type: MessageType::PublicKey,
+            msg_sender: self.client_name(id),
+            msg_content: msg.msg_content,
+            ..Default::default()
+        };
+        for id in self.clients.keys() {
+            self.send_to(*id, &msg);
+        }
+    }
+
+    /// Delete stream of an offline client and tell rooms it was in.
+    fn remove_client(&mut self, id: u32) {
+        let name = self.client_name(id);
+        if let Some(client) = self.clients.remove(&id) {
+            let _ = client.stream.shutdown();
+        }
+        let mut left_rooms = vec![];
+        for (room, members) in self.rooms.iter_mut() {
+            if members.remove(&id) {
+                left_rooms.push(room.clone());
+            }
+        }
+        for room in left_rooms {
+            let msg = Message {
+                msg_type: MessageType::RoomPart,
+                msg_sender: name.clone(),
+                msg_room: room.clone(),
+                msg_content: format!("{} is offline now.", name),
+                ..Default::default()
+            };
+            self.send_to_room(&room, &msg);
+        }
+        self.rooms
+            .retain(|room, members| room == DEFAULT_ROOM || !members.is_empty());
+        self.broadcast_client_list();
+        self.sync_metrics();
+    }
+
+    fn join_room(&mut self, id: u32, room: &str) {
+        if !is_valid_room_name(room) {
+            self.send_error(
+                id,
+                DEFAULT_ROOM,
+                format!(
+                    "Invalid room name \"{}\", use letters, digits, - and _.",
+                    room
+                ),
+            );
+            return;
+        }
+        if self.is_member(id, room) {
+            self.send_error(id, room, format!("You are already in #{}.", room));
+            return;
+        }
+        self.rooms.entry(room.to_string()).or_default().insert(id);
+        let name = self.client_name(id);
+        let msg = Message {
+            msg_type: MessageType::RoomJoin,
+            msg_sender: name.clone(),
+            msg_room: room.to_string(),
+            msg_content: format!("{} joined #{}.", name, room),
+            ..Default::default()
+        };
+        self.send_to_room(room, &msg);
+        self.sync_metrics();
+    }
+
+    fn part_room(&mut self, id: u32, room: &str) {
+        if room == DEFAULT_ROOM {
+            self.send_error(id, room, format!("You can not leave #{}.", room));
+            return;
+        }
+        if !self.is_member(id, room) {
+            self.send_error(id, DEFAULT_ROOM, format!("You are not in #{}.", room));
+            return;
+        }
+        let name = self.client_name(id);
+        let msg = Message {
+            msg_type: MessageType::RoomPart,
+            msg_sender: name.clone(),
+            msg_room: room.to_string(),
+            msg_content: format!("{} left #{}.", name, room),
+            ..Default::default()
+        };
+        // the leaving client receives the msg too, so it can close the room
+        self.send_to_room(room, &msg);
+        if let Some(members) = self.rooms.get_mut(room) {
+            members.remove(&id);
+            if members.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+        self.sync_metrics();
+    }
+
+    fn list_rooms(&self, id: u32, room: &str) {
+        let mut rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .map(|(name, members)| format!("#{} ({})", name, members.len()))
+            .collect();
+        rooms.sort();
+        let msg = Message {
+            msg_type: MessageType::RoomList,
+            msg_sender: "server".to_string(),
+            msg_room: room.to_string(),
+            msg_content: format!("Rooms: {}", rooms.join(", ")),
+            ..Default::default()
+        };
+        self.send_to(id, &msg);
+    }
+
+    /// Check if a client is an operator, given in config or made by another operator.
+    fn is_op(&self, id: u32) -> bool {
+        self.clients
+            .get(&id)
+            .map(|client| client.is_op || self.is_configured_op(client))
+            .unwrap_or(false)
+    }
+
+    /// Operators in config are accounts, as anyone can take a nickname.
+    fn is_configured_op(&self, client: &ConnectedClient) -> bool {
+        client
+            .account
+            .as_ref()
+            .is_some_and(|account| self.operators.contains(account))
+    }
+
+    /// Keys a mute of a client is kept by, its account and its IP.
+    fn mute_keys(&self, id: u32) -> Vec<String> {
+        let Some(client) = self.clients.get(&id) else {
+            return vec![];
+        };
+        let account = client
+            .account
+            .as_ref()
+            .map(|account| format!("account {}", account));
+        let ip = client.addr.map(|addr| format!("ip {}", addr.ip()));
+        account.into_iter().chain(ip).collect()
+    }
+
+    /// Time left before a client can send msgs again, `None` if it is not muted.
+    fn mute_left(&self, id: u32) -> Option<Duration> {
+        self.mute_keys(id)
+            .iter()
+            .filter_map(|key| self.mutes.get(key))
+            .max()?
+            .checked_duration_since(Instant::now())
+    }
+
+    /// Stop a client sending msgs for `duration`, mutes ended are forgotten.
+    fn set_mute(&mut self, id: u32, duration: Duration) {
+        let now = Instant::now();
+        self.mutes.retain(|_, until| *until > now);
+        for key in self.mute_keys(id) {
+            self.mutes.insert(key, now + duration);
+        }
+    }
+
+    /// Tell everyone what an operator has done.
+    fn broadcast_moderation(&self, sender: &str, target: &str, content: String) {
+        let msg = Message {
+            msg_type: MessageType::Moderation,
+            msg_sender: sender.to_string(),
+            msg_target: target.to_string(),
+            msg_content: content,
+            ..Default::default()
+        };
+        for other in self.clients.keys() {
+            if !self.is_pending(*other) {
+                self.send_to(*other, &msg);
+            }
+        }
+    }
+
+    /// Tell only the operator about what is done, for things others should not see, like IPs.
+    fn notify(&self, id: u32, content: String) {
+        let msg = Message {
+            msg_type: MessageType::Notice,
+            msg_sender: "server".to_string(),
+            msg_content: content,
+            ..Default::default()
+        };
+        self.send_to(id, &msg);
+    }
+
+    /// Find an online user an operator acts on, operators can not be acted on.
+    fn find_target(&self, nick: &str) -> Result<u32, String> {
+        let target = self
+            .find_client(nick)
+            .ok_or_else(|| format!("No such user: {}.", nick))?;
+        if self.is_op(target) {
+            return Err(format!(
+                "{} is an operator, /deop them first.",
+                self.client_name(target)
+            ));
+        }
+        Ok(target)
+    }
+
+    /// Carry out an operator's action, errors are sent to the operator.
+    fn moderate(&mut self, id: u32, msg: Message) {
+        if !self.is_op(id) {
+            self.send_error(id, DEFAULT_ROOM, "Only operators can do that.".to_string());
+            return;
+        }
+        let (action, args) = msg
+            .msg_content
+            .split_once(' ')
+            .unwrap_or((&msg.msg_content, ""));
+        let target = msg.msg_target.as_str();
+        let result = match action {
+            "kick" => self.kick(id, target, args),
+            "ban" => self.ban(id, target, args),
+            "banip" => self.ban_ip_of(id, target, args),
+            "unban" => self.unban(id, target),
+            "mute" => self.mute(id, target, args),
+            "unmute" => self.unmute(id, target),
+            "op" => self.set_op(id, target, true),
+            "deop" => self.set_op(id, target, false),
+            _ => Err(format!("Unknown action: {}.", action)),
+        };
+        if let Err(reason) = result {
+            self.send_error(id, DEFAULT_ROOM, reason);
+        }
+    }
+
+    fn kick(&mut self, id: u32, nick: &str, reason: &str) -> Result<(), String> {
+        let target = self.find_target(nick)?;
+        let name = self.client_name(target);
+        let event = format!("{} was kicked by {}", name, self.client_name(id));
+        self.broadcast_moderation(&self.client_name(id), &name, with_reason(event, reason));
+        self.remove_client(target);
+        Ok(())
+    }
+
+    /// Ban a nickname or an IP, users using it are disconnected.
+    fn ban(&mut self, id: u32, target: &str, reason: &str) -> Result<(), String> {
+        let ip = target.parse::<IpAddr>().ok();
+        let victims: Vec<u32> = match ip {
+            Some(ip) => self.clients_of_ip(id, ip)?,
+            None => match self.find_client(target) {
+                Some(_) => vec![self.find_target(target)?],
+                None => vec![],
+            },
+        };
+        let added = self
+            .bans
+            .lock()
+            .unwrap()
+            .ban(target)
+            .map_err(|err| format!("Failed to save bans: {}", err))?;
+        if !added {
+            return Err(format!("{} is banned already.", target));
+        }
+        match ip {
+            Some(ip) => self.notify(id, format!("IP {} is banned.", ip)),
+            None if victims.is_empty() => {
+                let event = format!("{} was banned by {}", target, self.client_name(id));
+                self.broadcast_moderation(
+                    &self.client_name(id),
+                    target,
+                    with_reason(event, reason),
+                );
+            }
+            None => {}
+        }
+        self.disconnect_banned(id, victims, reason);
+        Ok(())
+    }
+
+    /// Ban the IP of an online user.
+    fn ban_ip_of(&mut self, id: u32, nick: &str, reason: &str) -> Result<(), String> {
+        let target = self.find_target(nick)?;
+        let ip = self.clients[&target]
+            .addr
+            .map(|addr| addr.ip())
+            .ok_or_else(|| format!("The IP of {} is unknown.", nick))?;
+        self.ban(id, &ip.to_string(), reason)
+    }
+
+    /// Find users of an IP to ban, the operator and other operators must not be among them.
+    fn clients_of_ip(&self, id: u32, ip: IpAddr) -> Result<Vec<u32>, String> {
+        let ids: Vec<u32> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.addr.map(|addr| addr.ip()) == Some(ip))
+            .map(|(id, _)| *id)
+            .collect();
+        if ids.contains(&id) {
+            return Err("You can not ban your own IP.".to_string());
+        }
+        if let Some(op) = ids.iter().find(|id| self.is_op(**id)) {
+            return Err(format!(
+                "{} is an operator using this IP, /deop them first.",
+                self.client_name(*op)
+            ));
+        }
+        Ok(ids)
+    }
+
+    fn disconnect_banned(&mut self, id: u32, victims: Vec<u32>, reason: &str) {
+        for victim in victims {
+            let name = self.client_name(victim);
+            let event = format!("{} was banned by {}", name, self.client_name(id));
+            self.broadcast_moderation(&self.client_name(id), &name, with_reason(event, reason));
+            self.remove_client(victim);
+        }
+    }
+
+    fn unban(&mut self, id: u32, target: &str) -> Result<(), String> {
+        let removed = self
+            .bans
+            .lock()
+            .unwrap()
+            .unban(target)
+            .map_err(|err| format!("Failed to save bans: {}", err))?;
+        if !removed {
+            return Err(format!("{} is not banned.", target));
+        }
+        match target.parse::<IpAddr>() {
+            Ok(ip) => self.notify(id, format!("IP {} is not banned any more.", ip)),
+            Err(_) => {
+                let event = format!("{} was unbanned by {}.", target, self.client_name(id));
+                self.broadcast_moderation(&self.client_name(id), target, event);
+            }
+        }
+        Ok(())
+    }
+
+    fn mute(&mut self, id: u32, nick: &str, args: &str) -> Result<(), String> {
+        let target = self.find_target(nick)?;
+        let (duration, reason) = args.split_once(' ').unwrap_or((args, ""));
+        let duration = parse_duration(duration)
+            .ok_or("Durations look like 30s, 10m, 2h or 1d, and are a year at most.")?;
+        self.set_mute(target, duration);
+        let name = self.client_name(target);
+        let event = format!(
+            "{} was muted for {} by {}",
+            name,
+            format_duration(duration),
+            self.client_name(id)
+        );
+        self.broadcast_moderation(&self.client_name(id), &name, with_reason(event, reason));
+        Ok(())
+    }
+
+    fn unmute(&mut self, id: u32, nick: &str) -> Result<(), String> {
+        let target = self.find_target(nick)?;
+        if self.mute_left(target).is_none() {
+            return Err(format!("{} is not muted.", nick));
+        }
+        for key in self.mute_keys(target) {
+            self.mutes.remove(&key);
+        }
+        let name = self.client_name(target);
+        let event = format!("{} was unmuted by {}.", name, self.client_name(id));
+        self.broadcast_moderation(&self.client_name(id), &name, event);
+        Ok(())
+    }
+
+    /// Make a user an operator, or take the status back.
+    fn set_op(&mut self, id: u32, nick: &str, is_op: bool) -> Result<(), String> {
+        let target = self
+            .find_client(nick)
+            .ok_or_else(|| format!("No such user: {}.", nick))?;
+        let name = self.client_name(target);
+        let client = &self.clients[&target];
+        if !is_op && self.is_configured_op(client) {
+            return Err(format!("{} is an operator in config.", name));
+        }
+        if self.is_op(target) == is_op {
+            return Err(match is_op {
+                true => format!("{} is an operator already.", name),
+                false => format!("{} is not an operator.", name),
+            });
+        }
+        if let Some(client) = self.clients.get_mut(&target) {
+            client.is_op = is_op;
+        }
+        let event = match is_op {
+            true => format!("{} made {} an operator.", self.client_name(id), name),
+            false => format!(
+                "{} took operator status from {}.",
+                self.client_name(id),
+                name
+            ),
+        };
+        self.broadcast_moderation(&self.client_name(id), &name, event);
+        Ok(())
+    }
+
+    /// Carry out a command from `chamber admin`, and send the answer back.
+    fn handle_admin(&mut self, request: AdminRequest) {
+        let answer = match request.command {
+            AdminCommand::Clients => Ok(self.list_clients()),
+            AdminCommand::Kick { nick, reason } => self.admin_kick(&nick, &reason),
+            AdminCommand::Notice(text) => {
+                self.broadcast_notice(&text);
+                Ok("Notice sent.".to_string())
+            }
+            AdminCommand::Reload => self.reload(),
+            AdminCommand::Stats => Ok(self.stats().join("\n")),
+        };
+        let _ = request.reply.send(answer);
+    }
+
+    /// A line per client: id, nickname, address, uptime and what it is.
+    fn list_clients(&self) -> String {
+        let mut ids: Vec<&u32> = self.clients.keys().collect();
+        ids.sort();
+        let lines: Vec<String> = ids
+            .into_iter()
+            .map(|id| {
+                let client = &self.clients[id];
+                let mut line = format!(
+                    "{:<6} {:<20} {:<24} {}",
+                    id,
+                    client.name,
+                    client
+                        .addr
+                        .map(|addr| addr.to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                    format_duration(client.since.elapsed())
+                );
+                if let Some(account) = &client.account {
+                    line += &format!(" account:{}", account);
+                }
+                if self.is_pending(*id) {
+                    line += " logging-in";
+                }
+                if self.is_op(*id) {
+                    line += " op";
+                }
+                if self.mute_left(*id).is_some() {
+                    line += " muted";
+                }
+                line
+            })
+            .collect();
+        match lines.is_empty() {
+            true => "No clients online.".to_string(),
+            false => lines.join("\n"),
+        }
+    }
+
+    /// Kick a client, operators too.
+    fn admin_kick(&mut self, nick: &str, reason: &str) -> Result<String, String> {
+        let target = self
+            .find_client(nick)
+            .ok_or_else(|| format!("No such user: {}.", nick))?;
+        let name = self.client_name(target);
+        let event = with_reason(format!("{} was kicked by admin", name), reason);
+        self.broadcast_moderation("admin", &name, event);
+        self.remove_client(target);
+        Ok(format!("Kicked {}.", name))
+    }
+
+    fn broadcast_notice(&self, text: &str) {
+        let msg = Message {
+            msg_type: MessageType::Notice,
+            msg_sender: "server".to_string(),
+            msg_content: text.to_string(),
+            msg_time: chrono::Utc::now().timestamp(),
+            ..Default::default()
+        };
+        for id in self.clients.keys() {
+            if !self.is_pending(*id) {
+                self.send_to(*id, &msg);
+            }
+        }
+    }
+
+    /// Load the config file again, and the bans file.
+    ///
+    /// Operators, bans, access lists and what to do with flooding clients take effect at once,
+    /// other settings when server restarts.
+    fn reload(&mut self) -> Result<String, String> {
+        let config = Config::load().map_err(|err| err.to_string())?;
+        if !config.server.operators.is_empty() && self.accounts.is_none() {
+            return Err("operators need accounts = true in [server].".to_string());
+        }
+        let access = AccessList::new(&config.server)?;
+        let bans_path = config.server.bans_path().ok_or("Bans file is unknown.")?;
+        let bans = BanList::load(bans_path.clone())
+            .map_err(|err| format!("Failed to load {}: {}", bans_path.display(), err))?;
+        *self.access.lock().unwrap() = access;
+        *self.bans.lock().unwrap() = bans;
+        self.operators = config.server.operators;
+        self.limits.on_flood = config.server.limits.on_flood;
+        self.limits.mute_secs = config.server.limits.mute_secs;
+        Ok("Reloaded operators, bans, access lists and flood actions, \
+            restart server for other settings."
+            .to_string())
+    }
+
+    /// Stats of server for people to read, a line per item.
+    fn stats(&self) -> Vec<String> {
+        let pending = self
+            .clients
+            .keys()
+            .filter(|id| self.is_pending(**id))
+            .count();
+        let mut lines = self.metrics.summary();
+        lines.push(format!(
+            "logging in: {}, history: {} msgs kept",
+            pending,
+            self.history.len()
+        ));
+        lines
+    }
+
+    /// Answer `/stats`, with a msg per line.
+    fn send_stats(&self, id: u32, room: &str) {
+        for line in self.stats() {
+            let msg = Message {
+                msg_type: MessageType::Stats,
+                msg_sender: "server".to_string(),
+                msg_room: room.to_string(),
+                msg_content: line,
+                ..Default::default()
+            };
+            self.send_to(id, &msg);
+        }
+    }
+
+    /// Update metrics of clients and rooms.
+    fn sync_metrics(&self) {
+        self.metrics.set_clients(self.clients.len(), &self.rooms);
+    }
+
+    /// Tell a client what it has done wrong, and stop it if it is flooding.
+    fn handle_abuse(&mut self, id: u32, abuse: Abuse) {
+        if !self.clients.contains_key(&id) {
+            return;
+        }
+        match abuse {
+            Abuse::TooFast => self.send_error(
+                id,
+                DEFAULT_ROOM,
+                "You are sending msgs too fast, slow down.".to_string(),
+            ),
+            Abuse::BadFrame(reason) => self.send_error(id, DEFAULT_ROOM, reason),
+            Abuse::Flooding => {
+                let name = self.client_name(id);
+                match self.limits.on_flood {
+                    FloodAction::Mute => {
+                        let duration = Duration::from_secs(self.limits.mute_secs.max(1));
+                        self.set_mute(id, duration);
+                        let event = format!(
+                            "{} was muted for {} for flooding.",
+                            name,
+                            format_duration(duration)
+                        );
+                        self.broadcast_moderation("server", &name, event);
+                    }
+                    FloodAction::Disconnect => {
+                        let event = format!("{} was disconnected for flooding.", name);
+                        self.broadcast_moderation("server", &name, event);
+                        self.remove_client(id);
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_msg(&mut self, client_msg: ClientMessage) {
+        let ClientMessage { stream_id, mut msg } = client_msg;
+        // time of clients can not be trusted
+        msg.msg_time = chrono::Utc::now().timestamp();
+        if self.is_pending(stream_id)
+            && !matches!(
+                msg.msg_type,
+                MessageType::Login | MessageType::Register | MessageType::ClientExit
+            )
+        {
+            self.send_error(stream_id, DEFAULT_ROOM, "Log in first.".to_string());
+            return;
+        }
+        if matches!(
+            msg.msg_type,
+            MessageType::TextMessage
+                | MessageType::ActionMessage
+                | MessageType::DirectMessage
+                | MessageType::MessageEdit
+                | MessageType::Reaction
+        ) {
+            if let Some(left) = self.mute_left(stream_id) {
+                self.metrics.msg_dropped();
+                self.send_error(
+                    stream_id,
+                    &msg.msg_room,
+                    format!("You are muted for {}.", format_duration(left)),
+                );
+                return;
+            }
+        }
+        match msg.msg_type {
+            MessageType::ClientLogIn => {
+                // send updated client list to all clients
+            }
+            MessageType::ClientExit => {
+                self.remove_client(stream_id);
+                info!("{}", msg.msg_content);
+            }
+            MessageType::TextMessage | MessageType::ActionMessage => {
+                if !self.is_member(stream_id, &msg.msg_room) {
+                    self.send_error(
+                        stream_id,
+                        DEFAULT_ROOM,
+                        format!("Join #{} before sending msgs to it.", msg.msg_room),
+                    );
+                    return;
+                }
+                if !self.check_reply(stream_id, None, &msg) {
+                    return;
+                }
+                // send msg to all members of the room
+                msg.msg_sender = self.client_name(stream_id);
+                self.keep_msg(stream_id, None, &mut msg);
+                self.send_to_room(&msg.msg_room, &msg);
+                self.metrics.room_msg(&msg.msg_room);
+            }
+            MessageType::DirectMessage => self.send_direct_msg(stream_id, msg),
+            MessageType::NickChange => self.change_nickname(stream_id, &msg.msg_content),
+            MessageType::RoomJoin => self.join_room(stream_id, &msg.msg_room),
+            MessageType::RoomPart => self.part_room(stream_id, &msg.msg_room),
+            MessageType::RoomList => self.list_rooms(stream_id, &msg.msg_room),
+            MessageType::Stats => self.send_stats(stream_id, &msg.msg_room),
+            MessageType::Typing => self.relay_typing(stream_id, msg),
+            MessageType::MessageEdit => self.edit_msg(stream_id, msg),
+            MessageType::MessageDelete => self.delete_msg(stream_id, msg),
+            MessageType::Reaction => self.react(stream_id, msg),
+            MessageType::Search => self.search(stream_id, msg),
+            MessageType::PublicKey => self.publish_key(stream_id, msg),
+            MessageType::Login => self.login(stream_id, msg, false),
+            MessageType::Register => self.login(stream_id, msg, true),
+            MessageType::Moderation => self.moderate(stream_id, msg),
+            MessageType::Error => {}
+            _ => {}
+        }
+    }
+}
+
+/// End a moderation event with its reason, if there is one.
+fn with_reason(event: String, reason: &str) -> String {
+    match reason.trim() {
+        "" => format!("{}.", event),
+        reason => format!("{} ({}).", event, reason),
+    }
+}
+
+/// Tell a peer why it can not connect, then disconnect it.
+fn reject(stream: &ChatStream, reason: &str) {
+    let msg = Message {
+        msg_type: MessageType::Error,
+        msg_sender: "server".to_string(),
+        msg_content: reason.to_string(),
+        ..Default::default()
+    };
+    let _ = msg.write_to(&mut &*stream);
+    let _ = stream.shutdown();
+}
+
+/// Tell a peer rejected before it got a slot why, the TLS handshake is done first if it is on.
+fn turn_away(acceptor: &Acceptor, socket: TcpStream, reason: &str) {
+    let addr = socket
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    match acceptor.accept(socket) {
+        Ok(stream) => reject(&stream, reason),
+        Err(err) => debug!("TLS handshake with rejected {} failed: {}", addr, err),
+    }
+}
+
+/// Check or hash passwords one by one, so logins do not hold up the chamber.
+fn check_passwords(jobs: Receiver<PasswordJob>, results: Sender<PasswordChecked>) {
+    for job in jobs {
+        let result = match &job.hash {
+            Some(hash) => match verify_password(hash, &job.password) {
+                true => Ok(None),
+                false => Err("Wrong nickname or password.".to_string()),
+            },
+            None => hash_password(&job.password).map(Some),
+        };
+        let checked = PasswordChecked {
+            id: job.id,
+            nick: job.nick,
+            result,
+        };
+        if results.send(checked).is_err() {
+            break;
         }
     }
 }
@@ -29,24 +1473,79 @@ impl Clone for WrappedStream {
 /// receiver 只能被一个线程所拥有，stream 接收到消息之后，广播交给 server 来进行……
 ///
 /// 但是在 server 线程中更新客户端列表时会不会略繁琐……
-fn handle_client(mut client: WrappedStream, sender: Sender<Message>) -> std::io::Result<()> {
-    // let mut buffer: Vec<u8> = vec![]; 为什么不可以……因为没有指定缓冲大小吗……
-    let mut buffer: Vec<u8> = vec![0; MSG_BUF_SIZE];
+fn handle_client(
+    client: WrappedStream,
+    mut reader: impl BufRead,
+    sender: Sender<ClientMessage>,
+    abuse_sender: Sender<(u32, Abuse)>,
+    mut limiter: Limiter,
+    max_msg_size: usize,
+    metrics: Arc<Metrics>,
+) -> std::io::Result<()> {
     let client_addr = client
         .stream
         .peer_addr()
         .expect("Failed to get client addr.");
-    println!("Client {} has been online.", client_addr);
+    info!(
+        "Client {} with id {} is online.",
+        client_addr, client.stream_id
+    );
+    let report = |abuse| {
+        abuse_sender
+            .send((client.stream_id, abuse))
+            .expect("Failed to send abuse.")
+    };
     loop {
-        if let Ok(msg_size) = client.stream.read(&mut buffer) {
-            if msg_size > 0 {
-                // read msg string and convert it to type Message
-                let msg_str = from_utf8(&buffer[..msg_size]).unwrap();
-                let msg = Message::convert_to_msg(msg_str);
-                println!("Client {}: {}", client_addr, msg);
-                sender.send(msg).expect("Failed to send msg.");
-                println!("Sent to receiver")
+        let read = Message::read_limited(&mut reader, max_msg_size);
+        let bad_frame = matches!(&read, Err(err) if err.kind() == ErrorKind::InvalidData);
+        // drop msgs sent too fast before they reach the chamber
+        let verdict = match read {
+            Ok(Some(_)) => limiter.check(Instant::now()),
+            Err(_) if bad_frame => limiter.check(Instant::now()),
+            _ => Verdict::Pass,
+        };
+        match verdict {
+            Verdict::Pass => {}
+            Verdict::Drop { warn } => {
+                metrics.msg_dropped();
+                if warn {
+                    warn!("Client {}: sending too fast", client_addr);
+                    report(Abuse::TooFast);
+                }
+                continue;
+            }
+            Verdict::Flooding => {
+                warn!("Client {}: flooding", client_addr);
+                metrics.msg_dropped();
+                report(Abuse::Flooding);
+                continue;
             }
+        }
+        if let (Err(err), true) = (&read, bad_frame) {
+            warn!("Client {}: refused msg, {}", client_addr, err);
+            metrics.msg_dropped();
+            report(Abuse::BadFrame(err.to_string()));
+            continue;
+        }
+        if let Ok(Some(msg)) = read {
+            match msg.msg_type {
+                // never log passwords
+                MessageType::Login | MessageType::Register => {
+                    debug!(
+                        "Client {}: {} {}",
+                        client_addr, msg.msg_type, msg.msg_target
+                    )
+                }
+                _ => debug!("Client {}: {}", client_addr, msg),
+            }
+            metrics.msg_received();
+            sender
+                .send(ClientMessage {
+                    stream_id: client.stream_id,
+                    msg,
+                })
+                .expect("Failed to send msg.");
+            trace!("Msg of client {} sent to the chamber.", client.stream_id);
         } else {
             // client has been offline, delete its stream
             let exit_message = Message {
@@ -56,85 +1555,215 @@ fn handle_client(mut client: WrappedStream, sender: Sender<Message>) -> std::io:
                     "Client {} with id {} is offline now.",
                     client_addr, client.stream_id
                 ),
+                ..Default::default()
             };
-            sender.send(exit_message).expect("Failed to send exit msg.");
+            metrics.msg_queued();
+            sender
+                .send(ClientMessage {
+                    stream_id: client.stream_id,
+                    msg: exit_message,
+                })
+                .expect("Failed to send exit msg.");
             break;
         }
     }
 
-    return Ok(());
+    Ok(())
 }
 
-pub fn start() -> std::io::Result<()> {
+pub fn start() -> Result<(), Box<dyn Error>> {
+    let config = Config::load()?;
+    logger::init(&config.logging, true, config.logging.file_path().as_deref())?;
+    let acceptor = Arc::new(Acceptor::new(&config.tls)?);
+    let gate = Arc::new(Gate::new(&config.server)?);
+    if config.server.require_login && !config.server.accounts {
+        return Err("require_login needs accounts = true in [server].".into());
+    }
+    if !config.server.operators.is_empty() && !config.server.accounts {
+        return Err("operators need accounts = true in [server].".into());
+    }
+    let accounts = match config.server.accounts {
+        true => {
+            let path = config
+                .server
+                .accounts_path()
+                .ok_or("Accounts file is unknown.")?;
+            let accounts = Accounts::load(path.clone())
+                .map_err(|err| format!("Failed to load {}: {}", path.display(), err))?;
+            Some(accounts)
+        }
+        false => None,
+    };
+    let listener = TcpListener::bind(SERVER_ADDR)?;
+
     let slant_font = figlet_rs::FIGfont::standard().unwrap();
     let figure = slant_font.convert("Chamber");
     assert!(figure.is_some());
     println!("{}", figure.unwrap());
 
-    let mut clients: HashMap<u32, TcpStream> = HashMap::default();
+    let bans_path = config.server.bans_path().ok_or("Bans file is unknown.")?;
+    let bans = BanList::load(bans_path.clone())
+        .map_err(|err| format!("Failed to load {}: {}", bans_path.display(), err))?;
+    let bans = Arc::new(Mutex::new(bans));
+    let access = Arc::new(Mutex::new(AccessList::new(&config.server)?));
+    let metrics = Arc::new(Metrics::default());
+    let (password_sender, password_receiver) = mpsc::channel::<PasswordJob>();
+    let (checked_sender, checked_receiver) = mpsc::channel::<PasswordChecked>();
+    thread::spawn(move || check_passwords(password_receiver, checked_sender));
+    if let Some(addr) = &config.server.metrics_addr {
+        match metrics::serve(addr, metrics.clone()) {
+            Ok(()) => info!("Metrics at http://{}/metrics", addr),
+            Err(err) => warn!("Metrics at {} are off: {}", addr, err),
+        }
+    }
+    let mut chamber = Chamber {
+        accounts,
+        require_login: config.server.require_login,
+        operators: config.server.operators.clone(),
+        bans: bans.clone(),
+        limits: config.server.limits.clone(),
+        access: access.clone(),
+        metrics: metrics.clone(),
+        password_jobs: Some(password_sender),
+        ..Default::default()
+    };
 
-    let (msg_sender, msg_receiver) = mpsc::channel::<Message>();
+    let (msg_sender, msg_receiver) = mpsc::channel::<ClientMessage>();
     let (client_sender, client_receiver) = mpsc::channel::<WrappedStream>();
+    let (abuse_sender, abuse_receiver) = mpsc::channel::<(u32, Abuse)>();
+    let (admin_sender, admin_receiver) = mpsc::channel::<AdminRequest>();
+    match config.server.admin_socket_path() {
+        Some(path) => match admin::listen(&path, admin_sender) {
+            Ok(()) => info!("Admin socket: {}", path.display()),
+            Err(err) => warn!("Admin socket {} is off: {}", path.display(), err),
+        },
+        None => warn!("Admin socket is off: its path is unknown."),
+    }
+    let limits = config.server.limits.clone();
+    let ip_buckets = Arc::new(IpBuckets::new(&limits));
+    let connections = Arc::new(Connections::new(
+        limits.max_connections,
+        limits.max_connections_per_ip,
+    ));
 
     // a thread to get connections
     thread::spawn(move || {
-        let listener = TcpListener::bind("127.0.0.1:9999").expect("Failed to bind.");
-        let mut id: u32 = 0;
-        for new_stream in listener.incoming() {
+        // may overflow, ha ha
+        for (id, new_stream) in (0..).zip(listener.incoming()) {
             let new_stream = new_stream.expect("Failed to get stream.");
-            let client = WrappedStream {
-                stream_id: id,
-                stream: new_stream,
+            let peer = new_stream.peer_addr().ok();
+            metrics.connection_accepted();
+            let addr = peer.map(|addr| addr.to_string()).unwrap_or_default();
+            // rejected before a slot, and on plaintext listeners before a thread, is spent on them
+            let admission = match peer.map(|peer| peer.ip()) {
+                Some(ip) if !access.lock().unwrap().is_allowed(&ip) => {
+                    Err("IP is not allowed to connect.")
+                }
+                ip => connections.open(ip),
+            };
+            // the slot is held as long as the connection is open
+            let slot = match admission {
+                Ok(slot) => slot,
+                Err(reason) => {
+                    info!("Rejected {}: {}", addr, reason);
+                    metrics.connection_rejected();
+                    match acceptor.is_tls() {
+                        true => {
+                            let acceptor = acceptor.clone();
+                            thread::spawn(move || turn_away(&acceptor, new_stream, reason));
+                        }
+                        false => turn_away(&acceptor, new_stream, reason),
+                    }
+                    continue;
+                }
             };
+            let acceptor = acceptor.clone();
+            let gate = gate.clone();
+            let bans = bans.clone();
+            let limits = limits.clone();
+            let ip_buckets = ip_buckets.clone();
+            let metrics = metrics.clone();
+            let abuse_sender = abuse_sender.clone();
             let msg_sender_clone = msg_sender.clone();
-            client_sender
-                .send(client.clone())
-                .expect("Failed to send client.");
+            let client_sender_clone = client_sender.clone();
             // create a new thread to handle a connection
             thread::spawn(move || {
-                handle_client(client, msg_sender_clone).unwrap_or_else(|err| eprintln!("{:?}", err))
+                let _slot = slot;
+                let socket = new_stream.try_clone();
+                let stream = match acceptor.accept(new_stream) {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("TLS handshake with {} failed: {}", addr, err);
+                        return;
+                    }
+                };
+                if let Some(peer) =
+                    peer.filter(|peer| bans.lock().unwrap().is_ip_banned(&peer.ip()))
+                {
+                    info!("Rejected {}: banned IP", peer);
+                    metrics.connection_rejected();
+                    reject(&stream, "You are banned from this chamber.");
+                    return;
+                }
+                // clients not let in never become online
+                let set_timeout = |timeout| {
+                    if let Ok(socket) = &socket {
+                        let _ = socket.set_read_timeout(timeout);
+                    }
+                };
+                set_timeout(Some(Duration::from_secs(AUTH_SECS)));
+                let mut reader = BufReader::new(Counted::new(stream.clone(), metrics.clone()));
+                if let Err(reason) = gate.admit(&mut reader, &stream) {
+                    info!("Rejected {}: {}", addr, reason);
+                    metrics.connection_rejected();
+                    let _ = stream.shutdown();
+                    return;
+                }
+                set_timeout(None);
+                let client = WrappedStream {
+                    stream_id: id,
+                    stream,
+                };
+                client_sender_clone
+                    .send(client.clone())
+                    .expect("Failed to send client.");
+                let limiter = Limiter::new(&limits, peer.map(|peer| (peer.ip(), ip_buckets)));
+                handle_client(
+                    client,
+                    reader,
+                    msg_sender_clone,
+                    abuse_sender,
+                    limiter,
+                    limits.max_msg_size,
+                    metrics,
+                )
+                .unwrap_or_else(|err| error!("Connection {} failed: {}", id, err))
             });
-            // may overflow, ha ha
-            id += 1;
         }
     });
 
     loop {
         if let Ok(client) = client_receiver.try_recv() {
-            println!("Stream pushed.");
-            clients.insert(client.stream_id, client.stream);
+            debug!("Client {} added to the chamber.", client.stream_id);
+            chamber.add_client(client);
+        }
+
+        if let Ok(request) = admin_receiver.try_recv() {
+            chamber.handle_admin(request);
+        }
+
+        if let Ok((id, abuse)) = abuse_receiver.try_recv() {
+            chamber.handle_abuse(id, abuse);
+        }
+
+        if let Ok(checked) = checked_receiver.try_recv() {
+            chamber.finish_login(checked);
         }
 
         if let Ok(msg) = msg_receiver.try_recv() {
-            println!("Msg received, handle it...");
-            // handle msg
-            match msg.msg_type {
-                crate::message::MessageType::ClientLogIn => {
-                    // send updated client list to all clients
-                }
-                crate::message::MessageType::ClientExit => {
-                    let index = msg
-                        .msg_sender
-                        .parse::<u32>()
-                        .expect("Failed to get index of exited client.");
-                    clients
-                        .get(&index)
-                        .expect("Failed to get exited client.")
-                        .shutdown(std::net::Shutdown::Both)?;
-                    clients.remove(&index);
-                    println!("{}", msg);
-                }
-                crate::message::MessageType::TextMessage => {
-                    // send msg to all clients
-                    for (_, mut client) in &clients {
-                        client
-                            .write(msg.to_string().as_bytes())
-                            .expect("Failed to send msg to client");
-                    }
-                }
-                crate::message::MessageType::Error => {}
-                _ => {}
-            }
+            trace!("Handling a msg of client {}.", msg.stream_id);
+            chamber.metrics.msg_handled();
+            chamber.handle_msg(msg);
         }
     }
 }
@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use tui::style::{Color, Modifier, Style};
+
+/// Colors of the TUI.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Theme {
+    pub background: Color,
+    pub text: Color,
+    pub border: Color,
+    pub focused_border: Color,
+    pub title: Color,
+    /// Lines like "alice joined #lobby."
+    pub system: Color,
+    pub error: Color,
+    pub timestamp: Color,
+    /// Direct msgs
+    pub direct: Color,
+    /// `code` in msgs
+    pub code: Color,
+    /// Links in msgs
+    pub link: Color,
+    /// Background of msgs mentioning you
+    pub mention: Color,
+    /// Background of text matching a search in msg window
+    pub search_match: Color,
+    /// Background of the current match
+    pub search_current: Color,
+    /// Colors picked for nicknames
+    pub nick_colors: Vec<Color>,
+}
+
+/// A theme defined in config file, missing fields are taken from `base`.
+///
+/// ```toml
+/// theme = "mine"
+///
+/// [themes.mine]
+/// base = "light"
+/// focused_border = "#268bd2"
+/// nick_colors = ["red", "green", "blue"]
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    pub base: Option<String>,
+    pub background: Option<String>,
+    pub text: Option<String>,
+    pub border: Option<String>,
+    pub focused_border: Option<String>,
+    pub title: Option<String>,
+    pub system: Option<String>,
+    pub error: Option<String>,
+    pub timestamp: Option<String>,
+    pub direct: Option<String>,
+    pub code: Option<String>,
+    pub link: Option<String>,
+    pub mention: Option<String>,
+    pub search_match: Option<String>,
+    pub search_current: Option<String>,
+    pub nick_colors: Option<Vec<String>>,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme::dark()
+    }
+}
+impl Theme {
+    pub fn dark() -> Theme {
+        Theme {
+            background: Color::Reset,
+            text: Color::Reset,
+            border: Color::Reset,
+            focused_border: Color::Green,
+            title: Color::Reset,
+            system: Color::Gray,
+            error: Color::LightRed,
+            timestamp: Color::DarkGray,
+            direct: Color::Magenta,
+            code: Color::Yellow,
+            link: Color::LightBlue,
+            mention: Color::Indexed(58),
+            search_match: Color::Indexed(24),
+            search_current: Color::Indexed(130),
+            nick_colors: vec![
+                Color::LightRed,
+                Color::LightGreen,
+                Color::LightYellow,
+                Color::LightBlue,
+                Color::LightMagenta,
+                Color::LightCyan,
+                Color::Yellow,
+                Color::Cyan,
+            ],
+        }
+    }
+
+    pub fn light() -> Theme {
+        Theme {
+            background: Color::Reset,
+            text: Color::Black,
+            border: Color::DarkGray,
+            focused_border: Color::Blue,
+            title: Color::Black,
+            system: Color::DarkGray,
+            error: Color::Red,
+            timestamp: Color::Gray,
+            direct: Color::Magenta,
+            code: Color::Rgb(175, 0, 95),
+            link: Color::Blue,
+            mention: Color::Indexed(229),
+            search_match: Color::Indexed(153),
+            search_current: Color::Indexed(214),
+            nick_colors: vec![
+                Color::Red,
+                Color::Green,
+                Color::Blue,
+                Color::Magenta,
+                Color::Cyan,
+                Color::Rgb(175, 95, 0),
+                Color::Rgb(95, 0, 175),
+                Color::Rgb(0, 95, 95),
+            ],
+        }
+    }
+
+    pub fn high_contrast() -> Theme {
+        Theme {
+            background: Color::Black,
+            text: Color::White,
+            border: Color::White,
+            focused_border: Color::LightYellow,
+            title: Color::White,
+            system: Color::LightCyan,
+            error: Color::LightRed,
+            timestamp: Color::White,
+            direct: Color::LightMagenta,
+            code: Color::LightYellow,
+            link: Color::LightCyan,
+            mention: Color::Blue,
+            search_match: Color::Magenta,
+            search_current: Color::Red,
+            nick_colors: vec![
+                Color::LightYellow,
+                Color::LightCyan,
+                Color::LightGreen,
+                Color::LightMagenta,
+                Color::LightRed,
+                Color::White,
+            ],
+        }
+    }
+
+    pub fn builtin(name: &str) -> Option<Theme> {
+        match name {
+            "dark" => Some(Theme::dark()),
+            "light" => Some(Theme::light()),
+            "high-contrast" => Some(Theme::high_contrast()),
+            _ => None,
+        }
+    }
+
+    /// Find a theme by name, themes in config file come before built-in ones.
+    pub fn load(name: &str, themes: &HashMap<String, ThemeConfig>) -> Result<Theme, String> {
+        Theme::load_inner(name, themes, 0)
+    }
+
+    fn load_inner(
+        name: &str,
+        themes: &HashMap<String, ThemeConfig>,
+        depth: usize,
+    ) -> Result<Theme, String> {
+        let config = match themes.get(name) {
+            Some(config) => config,
+            None => {
+                return Theme::builtin(name).ok_or_else(|| format!("Unknown theme \"{}\".", name))
+            }
+        };
+        // a theme may be based on itself or others in a loop
+        if depth > themes.len() {
+            return Err(format!("Theme \"{}\" is based on itself.", name));
+        }
+        let mut theme = match config.base.as_deref() {
+            Some(base) if base != name => Theme::load_inner(base, themes, depth + 1)?,
+            _ => Theme::default(),
+        };
+        for (color, value) in [
+            (&mut theme.background, &config.background),
+            (&mut theme.text, &config.text),
+            (&mut theme.border, &config.border),
+            (&mut theme.focused_border, &config.focused_border),
+            (&mut theme.title, &config.title),
+            (&mut theme.system, &config.system),
+            (&mut theme.error, &config.error),
+            (&mut theme.timestamp, &config.timestamp),
+            (&mut theme.direct, &config.direct),
+            (&mut theme.code, &config.code),
+            (&mut theme.link, &config.link),
+            (&mut theme.mention, &config.mention),
+            (&mut theme.search_match, &config.search_match),
+            (&mut theme.search_current, &config.search_current),
+        ] {
+            if let Some(value) = value {
+                *color = parse_color(value)?;
+            }
+        }
+        if let Some(nick_colors) = &config.nick_colors {
+            theme.nick_colors = nick_colors
+                .iter()
+                .map(|value| parse_color(value))
+                .collect::<Result<Vec<Color>, String>>()?;
+            if theme.nick_colors.is_empty() {
+                return Err(format!("Theme \"{}\" has no nick colors.", name));
+            }
+        }
+        Ok(theme)
+    }
+
+    /// Style of a block, focused block is highlighted.
+    pub fn block_style(&self, focused: bool) -> Style {
+        Style::default()
+            .fg(if focused {
+                self.focused_border
+            } else {
+                self.border
+            })
+            .bg(self.background)
+    }
+
+    pub fn title_style(&self) -> Style {
+        Style::default().fg(self.title).add_modifier(Modifier::BOLD)
+    }
+
+    pub fn text_style(&self) -> Style {
+        Style::default().fg(self.text)
+    }
+
+    /// Pick a color for a nickname, the same nickname always gets the same color.
+    pub fn nick_color(&self, nickname: &str) -> Color {
+        // FNV-1a, stable between runs unlike the std hasher
+        let hash = nickname.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        self.nick_colors[(hash % self.nick_colors.len() as u64) as usize]
+    }
+
+    pub fn nick_style(&self, nickname: &str) -> Style {
+        Style::default()
+            .fg(self.nick_color(nickname))
+            .add_modifier(Modifier::BOLD)
+    }
+}
+
+/// Parse colors like `red`, `light-blue`, `#ff8800` or `208` (index of 256 colors).
+pub fn parse_color(value: &str) -> Result<Color, String> {
+    let name = value.to_lowercase().replace(['-', '_', ' '], "");
+    let color = match name.as_str() {
+        "reset" | "default" => Color::Reset,
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" | "grey" => Color::Gray,
+        "darkgray" | "darkgrey" => Color::DarkGray,
+        "lightred" => Color::LightRed,
+        "lightgreen" => Color::LightGreen,
+        "lightyellow" => Color::LightYellow,
+        "lightblue" => Color::LightBlue,
+        "lightmagenta" => Color::LightMagenta,
+        "lightcyan" => Color::LightCyan,
+        "white" => Color::White,
+        // hex is sliced by bytes, which are chars only if it is ASCII
+        hex if hex.starts_with('#') && hex.len() == 7 && hex.is_ascii() => {
+            let channel = |range| u8::from_str_radix(&hex[range], 16);
+            match (channel(1..3), channel(3..5), channel(5..7)) {
+                (Ok(r), Ok(g), Ok(b)) => Color::Rgb(r, g, b),
+                _ => return Err(format!("Invalid color \"{}\".", value)),
+            }
+        }
+        index => Color::Indexed(
+            index
+                .parse()
+                .map_err(|_| format!("Invalid color \"{}\".", value))?,
+        ),
+    };
+    Ok(color)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_colors() {
+        assert_eq!(parse_color("Light-Blue"), Ok(Color::LightBlue));
+        assert_eq!(parse_color("#ff8800"), Ok(Color::Rgb(255, 136, 0)));
+        assert_eq!(parse_color("208"), Ok(Color::Indexed(208)));
+        assert!(parse_color("#ff88").is_err());
+        assert!(parse_color("#aé123").is_err());
+        assert!(parse_color("blurple").is_err());
+    }
+
+    #[test]
+    fn nick_colors_are_stable() {
+        let theme = Theme::dark();
+        assert_eq!(theme.nick_color("alice"), theme.nick_color("alice"));
+        let colors: Vec<Color> = ["alice", "bob", "carol", "dave", "eve"]
+            .iter()
+            .map(|nick| theme.nick_color(nick))
+            .collect();
+        // not all nicknames share a color
+        assert!(colors.iter().any(|color| *color != colors[0]));
+    }
+
+    #[test]
+    fn load_themes() {
+        assert_eq!(Theme::load("light", &HashMap::new()), Ok(Theme::light()));
+        assert!(Theme::load("neon", &HashMap::new()).is_err());
+
+        let mut themes = HashMap::new();
+        themes.insert(
+            "mine".to_string(),
+            ThemeConfig {
+                base: Some("high-contrast".to_string()),
+                focused_border: Some("#268bd2".to_string()),
+                nick_colors: Some(vec!["red".to_string()]),
+                ..Default::default()
+            },
+        );
+        themes.insert(
+            "loop".to_string(),
+            ThemeConfig {
+                base: Some("loop2".to_string()),
+                ..Default::default()
+            },
+        );
+        themes.insert(
+            "loop2".to_string(),
+            ThemeConfig {
+                base: Some("loop".to_string()),
+                ..Default::default()
+            },
+        );
+        let theme = Theme::load("mine", &themes).unwrap();
+        assert_eq!(theme.focused_border, Color::Rgb(38, 139, 210));
+        assert_eq!(theme.background, Color::Black);
+        assert_eq!(theme.nick_color("anyone"), Color::Red);
+        assert!(Theme::load("loop", &themes).is_err());
+    }
+}
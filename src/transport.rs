@@ -0,0 +1,532 @@
+use std::{
+    error::Error,
+    fs::OpenOptions,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    ClientConfig, ClientConnection, Connection, DigitallySignedStruct, RootCertStore, ServerConfig,
+    ServerConnection, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{config::TlsConfig, consts::TLS_HANDSHAKE_SECS};
+
+/// A connection between client and server, plain TCP or TLS.
+///
+/// Methods take `&self` like `&TcpStream` does, so one thread can read
+/// while others write to the same connection.
+pub trait Transport: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+    fn write(&self, buf: &[u8]) -> io::Result<usize>;
+    fn flush(&self) -> io::Result<()>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        Write::write(&mut &*self, buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// TLS over a `TcpStream`, the handshake is done before it is created.
+struct TlsTransport {
+    conn: Mutex<Connection>,
+    socket: TcpStream,
+}
+impl TlsTransport {
+    fn new(conn: impl Into<Connection>, mut socket: TcpStream) -> io::Result<TlsTransport> {
+        let mut conn = conn.into();
+        // a peer saying nothing should not keep the thread forever
+        socket.set_read_timeout(Some(Duration::from_secs(TLS_HANDSHAKE_SECS)))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut socket)?;
+        }
+        socket.set_read_timeout(None)?;
+        Ok(TlsTransport {
+            conn: Mutex::new(conn),
+            socket,
+        })
+    }
+
+    /// Send records waiting in the connection.
+    fn write_records(&self, conn: &mut Connection) -> io::Result<()> {
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.socket)?;
+        }
+        Ok(())
+    }
+}
+impl Transport for TlsTransport {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut records = [0; 4096];
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+            // the lock is not held while waiting, so msgs can be sent meanwhile
+            let len = Read::read(&mut &self.socket, &mut records)?;
+            if len == 0 {
+                return Ok(0);
+            }
+            let mut conn = self.conn.lock().unwrap();
+            let mut data = &records[..len];
+            while !data.is_empty() {
+                conn.read_tls(&mut data)?;
+                conn.process_new_packets()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            }
+            self.write_records(&mut conn)?;
+        }
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let len = conn.writer().write(buf)?;
+        self.write_records(&mut conn)?;
+        Ok(len)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.writer().flush()?;
+        self.write_records(&mut conn)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.send_close_notify();
+        // the peer may have gone already
+        let _ = self.write_records(&mut conn);
+        self.socket.shutdown(Shutdown::Both)
+    }
+}
+
+/// A shared handle of a connection, cloning it does not open a new one.
+#[derive(Clone)]
+pub struct ChatStream(Arc<dyn Transport>);
+impl ChatStream {
+    pub fn new(transport: impl Transport + 'static) -> ChatStream {
+        ChatStream(Arc::new(transport))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.0.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.0.shutdown()
+    }
+}
+impl Read for &ChatStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+impl Write for &ChatStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+impl Read for ChatStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+impl Write for ChatStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Opens connections to server, with TLS if it is enabled in config.
+pub struct Connector {
+    tls: Option<Arc<ClientConfig>>,
+}
+impl Connector {
+    /// Load CA certs or the pinned fingerprint from config.
+    pub fn new(config: &TlsConfig) -> Result<Connector, Box<dyn Error>> {
+        if !config.enabled {
+            return Ok(Connector { tls: None });
+        }
+        let roots = match config.ca_path() {
+            Some(path) => CertificateDer::pem_file_iter(&path)
+                .and_then(|certs| certs.collect())
+                .map_err(|err| format!("Failed to load {}: {}", path.display(), err))?,
+            None => vec![],
+        };
+        let fingerprint = config
+            .fingerprint
+            .as_deref()
+            .map(parse_fingerprint)
+            .transpose()?;
+        if roots.is_empty() && fingerprint.is_none() {
+            return Err("Set tls.ca or tls.fingerprint to trust the server.".into());
+        }
+        Ok(Connector {
+            tls: Some(client_config(roots, fingerprint)?),
+        })
+    }
+
+    /// Connect to `addr` like `127.0.0.1:9999`, the host is checked against the certificate.
+    pub fn connect(&self, addr: &str) -> io::Result<ChatStream> {
+        let socket = TcpStream::connect(addr)?;
+        let tls = match &self.tls {
+            Some(tls) => tls.clone(),
+            None => return Ok(ChatStream::new(socket)),
+        };
+        let host = addr.rsplit_once(':').map(|(host, _)| host).unwrap_or(addr);
+        let name = ServerName::try_from(host.trim_matches(['[', ']']).to_string())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let conn = ClientConnection::new(tls, name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        Ok(ChatStream::new(TlsTransport::new(conn, socket)?))
+    }
+}
+
+/// Wraps connections accepted by server, with TLS if it is enabled in config.
+pub struct Acceptor {
+    tls: Option<Arc<ServerConfig>>,
+}
+impl Acceptor {
+    /// Load the certificate chain and private key from config.
+    pub fn new(config: &TlsConfig) -> Result<Acceptor, Box<dyn Error>> {
+        if !config.enabled {
+            return Ok(Acceptor { tls: None });
+        }
+        let (cert_path, key_path) = match (config.cert_path(), config.key_path()) {
+            (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+            _ => return Err("Set tls.cert and tls.key to serve TLS.".into()),
+        };
+        let certs = CertificateDer::pem_file_iter(&cert_path)
+            .and_then(|certs| certs.collect())
+            .map_err(|err| format!("Failed to load {}: {}", cert_path.display(), err))?;
+        let key = PrivateKeyDer::from_pem_file(&key_path)
+            .map_err(|err| format!("Failed to load {}: {}", key_path.display(), err))?;
+        Ok(Acceptor {
+            tls: Some(server_config(certs, key)?),
+        })
+    }
+
+    /// Whether connections are wrapped in TLS, so `accept` does a handshake.
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// Do the TLS handshake with a new connection, it is slow so don't do it in accept loop.
+    pub fn accept(&self, socket: TcpStream) -> io::Result<ChatStream> {
+        match &self.tls {
+            Some(tls) => {
+                let conn = ServerConnection::new(tls.clone())
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+                Ok(ChatStream::new(TlsTransport::new(conn, socket)?))
+            }
+            None => Ok(ChatStream::new(socket)),
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<Arc<ServerConfig>, rustls::Error> {
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+/// Trust certs issued by `roots`, or the one cert with `fingerprint` if it is given.
+fn client_config(
+    roots: Vec<CertificateDer<'static>>,
+    fingerprint: Option<Vec<u8>>,
+) -> Result<Arc<ClientConfig>, rustls::Error> {
+    let builder =
+        ClientConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let config = match fingerprint {
+        Some(fingerprint) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCert {
+                fingerprint,
+                provider: provider(),
+            }))
+            .with_no_client_auth(),
+        None => {
+            let mut store = RootCertStore::empty();
+            for cert in roots {
+                store.add(cert)?;
+            }
+            builder.with_root_certificates(store).with_no_client_auth()
+        }
+    };
+    Ok(Arc::new(config))
+}
+
+/// Accepts the server cert whose SHA-256 fingerprint is pinned, names and dates are not checked.
+#[derive(Debug)]
+struct PinnedCert {
+    fingerprint: Vec<u8>,
+    provider: Arc<CryptoProvider>,
+}
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match Sha256::digest(end_entity).as_slice() == self.fingerprint {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::General(format!(
+                "Server certificate {} is not the pinned one.",
+                fingerprint(end_entity)
+            ))),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// SHA-256 fingerprint of a DER cert, like `AB:CD:...` printed by `openssl x509 -fingerprint`.
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+/// Parse a SHA-256 fingerprint in hex, colons and case do not matter.
+fn parse_fingerprint(text: &str) -> Result<Vec<u8>, String> {
+    let hex: Vec<char> = text.chars().filter(|ch| *ch != ':').collect();
+    let bytes = hex
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(&pair.iter().collect::<String>(), 16).ok())
+        .collect::<Option<Vec<u8>>>();
+    match bytes {
+        Some(bytes) if bytes.len() == 32 && hex.len() == 64 => Ok(bytes),
+        _ => Err(format!("Invalid SHA-256 fingerprint \"{}\".", text)),
+    }
+}
+
+/// Generate a self-signed cert for `names` (hosts or IPs), return PEM of the cert and its key.
+pub fn generate_cert(names: Vec<String>) -> Result<(String, String), Box<dyn Error>> {
+    let certified = rcgen::generate_simple_self_signed(names)?;
+    Ok((certified.cert.pem(), certified.key_pair.serialize_pem()))
+}
+
+/// `chamber cert [names]`, write a self-signed cert for local testing to `cert.pem` and `key.pem`.
+pub fn gen_cert(names: &[String]) -> Result<(), Box<dyn Error>> {
+    let names = match names.is_empty() {
+        true => vec!["localhost".to_string(), "127.0.0.1".to_string()],
+        false => names.to_vec(),
+    };
+    let (cert_pem, key_pem) = generate_cert(names)?;
+    for (path, pem) in [("cert.pem", &cert_pem), ("key.pem", &key_pem)] {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|err| format!("Failed to create {}: {}", path, err))?;
+        file.write_all(pem.as_bytes())?;
+    }
+    let cert = CertificateDer::from_pem_slice(cert_pem.as_bytes())?;
+    println!("Wrote cert.pem and key.pem.");
+    println!("SHA-256 fingerprint: {}", fingerprint(&cert));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::BufReader, net::TcpListener, thread};
+
+    use super::*;
+    use crate::message::Message;
+
+    fn names() -> Vec<String> {
+        vec!["localhost".to_string(), "127.0.0.1".to_string()]
+    }
+
+    /// Serve one connection echoing msgs, return its address.
+    fn echo_server(acceptor: Acceptor) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let stream = match acceptor.accept(socket) {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            let mut reader = BufReader::new(stream.clone());
+            while let Ok(Some(msg)) = Message::read_from(&mut reader) {
+                msg.write_to(&mut &stream).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn tls_acceptor(cert_pem: &str, key_pem: &str) -> Acceptor {
+        let certs = CertificateDer::pem_slice_iter(cert_pem.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_slice(key_pem.as_bytes()).unwrap();
+        Acceptor {
+            tls: Some(server_config(certs, key).unwrap()),
+        }
+    }
+
+    fn echo(stream: &ChatStream, content: &str) -> io::Result<String> {
+        let msg = Message {
+            msg_content: content.to_string(),
+            ..Default::default()
+        };
+        msg.write_to(&mut &*stream)?;
+        let mut reader = BufReader::new(stream.clone());
+        match Message::read_from(&mut reader)? {
+            Some(msg) => Ok(msg.msg_content),
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    #[test]
+    fn plain_and_trusted_tls() {
+        let addr = echo_server(Acceptor { tls: None });
+        let stream = Connector { tls: None }.connect(&addr).unwrap();
+        assert_eq!(echo(&stream, "plain").unwrap(), "plain");
+
+        let (cert_pem, key_pem) = generate_cert(names()).unwrap();
+        let addr = echo_server(tls_acceptor(&cert_pem, &key_pem));
+        let root = CertificateDer::from_pem_slice(cert_pem.as_bytes()).unwrap();
+        let connector = Connector {
+            tls: Some(client_config(vec![root], None).unwrap()),
+        };
+        let stream = connector.connect(&addr).unwrap();
+        assert_eq!(echo(&stream, "secret\nlines").unwrap(), "secret\nlines");
+        // a long msg takes several records
+        let long = "x".repeat(40000);
+        assert_eq!(echo(&stream, &long).unwrap(), long);
+        stream.shutdown().unwrap();
+
+        // a cert not issued by the trusted one
+        let (other_pem, _) = generate_cert(names()).unwrap();
+        let addr = echo_server(tls_acceptor(&cert_pem, &key_pem));
+        let other = CertificateDer::from_pem_slice(other_pem.as_bytes()).unwrap();
+        let connector = Connector {
+            tls: Some(client_config(vec![other], None).unwrap()),
+        };
+        assert!(connector.connect(&addr).is_err());
+    }
+
+    #[test]
+    fn pinned_fingerprint() {
+        let (cert_pem, key_pem) = generate_cert(names()).unwrap();
+        let cert = CertificateDer::from_pem_slice(cert_pem.as_bytes()).unwrap();
+        let pinned = parse_fingerprint(&fingerprint(&cert).to_lowercase()).unwrap();
+
+        let addr = echo_server(tls_acceptor(&cert_pem, &key_pem));
+        let connector = Connector {
+            tls: Some(client_config(vec![], Some(pinned)).unwrap()),
+        };
+        // names are not checked when the cert is pinned
+        let addr = addr.replace("127.0.0.1", "localhost");
+        let stream = connector.connect(&addr).unwrap();
+        assert_eq!(echo(&stream, "pinned").unwrap(), "pinned");
+
+        let addr = echo_server(tls_acceptor(&cert_pem, &key_pem));
+        let connector = Connector {
+            tls: Some(client_config(vec![], Some(vec![0; 32])).unwrap()),
+        };
+        assert!(connector.connect(&addr).is_err());
+
+        assert!(parse_fingerprint("AB:CD").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+    }
+}